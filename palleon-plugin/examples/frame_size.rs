@@ -0,0 +1,46 @@
+// a data plugin returning the size of every frame and the average over the last sizes, e.g.
+// [data.frame_size]
+// command = ["target/debug/examples/frame_size"]
+// working_directory = "."
+
+use palleon_plugin::{doc, Bson, DataPlugin, Document, Image, PluginResult, Runner};
+
+// how many of its own last values the plugin asks for
+const HISTORY: u32 = 10;
+
+struct FrameSize;
+
+impl DataPlugin for FrameSize {
+    fn dependencies(&self) -> Vec<(String, u32)> {
+        vec![("frame_size".to_string(), HISTORY)]
+    }
+
+    fn process(&mut self, image: &Image, dependency_data: &Document) -> PluginResult<Document> {
+        let size = image.data.as_ref().map_or(0, Vec::len) as i64;
+
+        // [[<timestamp>, {"size": <size>, ..}], ..]
+        let sizes: Vec<i64> = dependency_data.get_array("frame_size").map(|values| values.iter()
+            .filter_map(|value| match value {
+                Bson::Array(value) => value.get(1)?.as_document()?.get_i64("size").ok(),
+                _ => None,
+            })
+            .collect()).unwrap_or_default();
+        let average = (sizes.iter().sum::<i64>() + size) as f64 / (sizes.len() + 1) as f64;
+
+        // the dimensions if the input plugin told them
+        let mut result = doc! { "size": size, "average": average };
+        for key in ["width", "height"] {
+            if let Some(value) = image.metadata.get(key) {
+                result.insert(key, value.clone());
+            }
+        }
+        Ok(result)
+    }
+}
+
+fn main() {
+    if let Err(e) = Runner::new("frame_size", env!("CARGO_PKG_VERSION")).run_data(&mut FrameSize) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
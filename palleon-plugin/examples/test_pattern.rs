@@ -0,0 +1,34 @@
+// an input plugin sending a gray frame of 640x480 that gets brighter with every frame, e.g.
+// [input.pattern]
+// command = ["target/debug/examples/test_pattern"]
+// working_directory = "."
+
+use std::thread;
+use std::time::Duration;
+
+use palleon_plugin::{doc, Document, InputPlugin, PluginResult, Runner};
+
+struct TestPattern {
+    brightness: u8,
+}
+
+impl InputPlugin for TestPattern {
+    fn next_frame(&mut self) -> PluginResult<Option<Vec<u8>>> {
+        // 10 frames per second
+        thread::sleep(Duration::from_millis(100));
+
+        self.brightness = self.brightness.wrapping_add(1);
+        Ok(Some(vec![self.brightness; 640 * 480]))
+    }
+
+    fn metadata(&mut self) -> Document {
+        doc! { "width": 640, "height": 480, "pixel_format": "gray8", "encoding": "raw" }
+    }
+}
+
+fn main() {
+    if let Err(e) = Runner::new("test_pattern", env!("CARGO_PKG_VERSION")).run_input(&mut TestPattern { brightness: 0 }) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,113 @@
+use std::env;
+use std::io::{self, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+
+use bson::{doc, Document};
+
+use crate::{Endpoint, Error};
+
+// the framing of the core (see wrapped_stream.rs of the core): every message is a bson
+// document with its length as a 32 bit little endian integer in front
+
+// the core never sends anything near this, a bigger length means the stream is out of sync
+const MAX_FRAME_BYTES: u32 = 1024 * 1024 * 1024;
+
+enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.read(buf),
+            Stream::Unix(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.write(buf),
+            Stream::Unix(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.flush(),
+            Stream::Unix(s) => s.flush(),
+        }
+    }
+}
+
+pub(crate) struct Connection {
+    stream: BufReader<Stream>,
+}
+
+impl Connection {
+    // connects to the core at endpoint, or PALLEON_SOCKET or PALLEON_HOST:PALLEON_PORT without
+    // one, and authenticates with token or PALLEON_TOKEN, if either is set
+    pub(crate) fn open(endpoint: Option<&Endpoint>, token: Option<&str>) -> Result<Connection, Error> {
+        let endpoint = match endpoint {
+            Some(endpoint) => endpoint.clone(),
+            None => endpoint_from_env()?,
+        };
+        let stream = match endpoint {
+            Endpoint::Unix(path) => Stream::Unix(UnixStream::connect(path)?),
+            Endpoint::Tcp(addr) => Stream::Tcp(TcpStream::connect(addr)?),
+        };
+
+        let mut connection = Connection { stream: BufReader::new(stream) };
+        if let Some(token) = token.map(str::to_string).or_else(|| env::var("PALLEON_TOKEN").ok()) {
+            connection.send(&doc! { "token": token })?;
+            if connection.recv()?.get_bool("authenticated") != Ok(true) {
+                return Err(Error::Rejected);
+            }
+        }
+
+        Ok(connection)
+    }
+
+    pub(crate) fn send(&mut self, doc: &Document) -> Result<(), Error> {
+        let mut buffer = Vec::new();
+        doc.to_writer(&mut buffer).map_err(|e| Error::InvalidBson(e.to_string()))?;
+
+        let stream = self.stream.get_mut();
+        stream.write_all(&(buffer.len() as u32).to_le_bytes())?;
+        stream.write_all(&buffer)?;
+        Ok(())
+    }
+
+    pub(crate) fn recv(&mut self) -> Result<Document, Error> {
+        let mut length = [0u8; 4];
+        self.stream.read_exact(&mut length)?;
+        let length = u32::from_le_bytes(length);
+        if length > MAX_FRAME_BYTES {
+            return Err(Error::Protocol(format!("a frame of {} bytes", length)));
+        }
+
+        let mut buffer = vec![0u8; length as usize];
+        self.stream.read_exact(&mut buffer)?;
+        Document::from_reader(buffer.as_slice()).map_err(|e| Error::InvalidBson(e.to_string()))
+    }
+
+    // the next message and its type
+    pub(crate) fn recv_message(&mut self) -> Result<(String, Document), Error> {
+        let message = self.recv()?;
+        let name = message.get_str("type").map_err(|_| Error::Protocol("a message without a 'type'".to_string()))?.to_string();
+        Ok((name, message))
+    }
+}
+
+fn endpoint_from_env() -> Result<Endpoint, Error> {
+    if let Some(path) = env::var_os("PALLEON_SOCKET") {
+        return Ok(Endpoint::Unix(path.into()));
+    }
+    match (env::var("PALLEON_HOST"), env::var("PALLEON_PORT")) {
+        (Ok(host), Ok(port)) => Ok(Endpoint::Tcp(format!("{}:{}", host, port))),
+        _ => Err(Error::Environment("neither PALLEON_SOCKET nor PALLEON_HOST and PALLEON_PORT are set".to_string())),
+    }
+}
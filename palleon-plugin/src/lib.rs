@@ -0,0 +1,154 @@
+//! The plugin side of the palleon protocol (version 2), so a plugin only has to implement
+//! [`InputPlugin`] or [`DataPlugin`] and hand it to a [`Runner`]:
+//!
+//! ```no_run
+//! use palleon_plugin::{InputPlugin, PluginResult, Runner};
+//!
+//! struct Pattern(u8);
+//!
+//! impl InputPlugin for Pattern {
+//!     fn next_frame(&mut self) -> PluginResult<Option<Vec<u8>>> {
+//!         self.0 = self.0.wrapping_add(1);
+//!         Ok(Some(vec![self.0; 640 * 480]))
+//!     }
+//! }
+//!
+//! fn main() -> Result<(), palleon_plugin::Error> {
+//!     Runner::new("pattern", "0.1.0").run_input(&mut Pattern(0))
+//! }
+//! ```
+//!
+//! The runner connects to the core like every plugin started by it (`PALLEON_SOCKET` or
+//! `PALLEON_HOST`/`PALLEON_PORT`, authenticated with `PALLEON_TOKEN`), says hello and then
+//! answers the messages of the core until it is asked to shut down.
+//! A plugin that is not started by the core (e.g. in the listen mode) can be given the address
+//! and the token with [`Runner::connect_to`] and [`Runner::token`] instead.
+//! Pings are answered by the runner, an error of the plugin is sent to the core as an `error` message.
+
+use std::fmt;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+pub use bson::{self, doc, Bson, Document};
+
+mod connection;
+mod runner;
+
+pub use runner::Runner;
+
+/// what the methods of a plugin return, any error ends the connection
+pub type PluginResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// a plugin that produces frames, e.g. from a camera
+pub trait InputPlugin {
+    /// the next frame, None if there is none at the moment (the core asks again after the idle
+    /// backoff of the plugin's config, a second by default)
+    fn next_frame(&mut self) -> PluginResult<Option<Vec<u8>>>;
+
+    /// what the plugin tells about the frame just returned by next_frame, passed on to the data
+    /// plugins and the gui as [`Image::metadata`]: `width`, `height`, `pixel_format`, `encoding`,
+    /// `capture_time` (a date time), `sequence` and `tags` (a document), all optional
+    fn metadata(&mut self) -> Document {
+        Document::new()
+    }
+
+    /// when the plugin expects its next frame, asked right after next_frame returned None, the
+    /// core asks again after that long instead of after its idle backoff (unless its config says
+    /// otherwise), None leaves it to the idle backoff
+    fn retry_after(&mut self) -> Option<Duration> {
+        None
+    }
+
+    /// called when the core asks the plugin to shut down
+    fn shutdown(&mut self) {}
+}
+
+/// a plugin that gets every frame of the input plugins and returns what it made of it
+pub trait DataPlugin {
+    /// whether the images contain their data, a plugin that only needs the values of its
+    /// dependencies saves the core from sending the frames
+    fn wants_image(&self) -> bool {
+        true
+    }
+
+    /// the data plugins whose last values this plugin needs, with the number of values
+    /// (at most `i32::MAX`, run_data fails otherwise)
+    fn dependencies(&self) -> Vec<(String, u32)> {
+        vec![]
+    }
+
+    /// describes the documents returned by process, for the gui
+    fn output_schema(&self) -> Option<Document> {
+        None
+    }
+
+    /// the result for one image, dependency_data holds the last values of the dependencies as
+    /// {"<plugin name>": [[<timestamp>, <value>], ..], ..}
+    fn process(&mut self, image: &Image, dependency_data: &Document) -> PluginResult<Document>;
+
+    /// called when the core asks the plugin to shut down
+    fn shutdown(&mut self) {}
+}
+
+/// a frame of an input plugin
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image {
+    /// None if the plugin does not want the image (see [`DataPlugin::wants_image`])
+    pub data: Option<Vec<u8>>,
+    /// the name of the input plugin
+    pub input_source: String,
+    /// the time the result is stored under: the capture time if the input plugin sent one (and
+    /// the config of the core does not say otherwise), the arrival time otherwise
+    pub timestamp: SystemTime,
+    /// when the frame arrived at the core
+    pub arrival_time: SystemTime,
+    /// what the input plugin told about the frame (see [`InputPlugin::metadata`]), empty if nothing
+    pub metadata: Document,
+}
+
+/// where the runner connects to, see [`Runner::connect_to`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Endpoint {
+    /// the path of a unix socket
+    Unix(PathBuf),
+    /// host:port
+    Tcp(String),
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// the environment does not say where the core is
+    Environment(String),
+    Io(std::io::Error),
+    InvalidBson(String),
+    /// the core did not accept the token
+    Rejected,
+    /// the core sent something the protocol does not expect
+    Protocol(String),
+    /// the core sent an error message
+    Core(String),
+    /// the plugin returned an error (which was sent to the core)
+    Plugin(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Environment(message) => write!(f, "{}", message),
+            Error::Io(e) => write!(f, "{}", e),
+            Error::InvalidBson(e) => write!(f, "invalid bson: {}", e),
+            Error::Rejected => write!(f, "the core did not accept the token"),
+            Error::Protocol(message) => write!(f, "unexpected message: {}", message),
+            Error::Core(message) => write!(f, "the core reported an error: {}", message),
+            Error::Plugin(message) => write!(f, "the plugin failed: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
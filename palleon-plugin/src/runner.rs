@@ -0,0 +1,367 @@
+use bson::{doc, Bson, Document};
+
+use crate::connection::Connection;
+use crate::{DataPlugin, Endpoint, Error, Image, InputPlugin, PluginResult};
+
+// the protocol version the runner speaks, the first one with typed messages
+const PROTOCOL_VERSION: i32 = 2;
+
+/// connects a plugin to the core and runs it until the core asks it to shut down
+pub struct Runner {
+    name: String,
+    version: String,
+    endpoint: Option<Endpoint>,
+    token: Option<String>,
+}
+
+impl Runner {
+    /// name and version are only used for the log of the core
+    pub fn new(name: &str, version: &str) -> Self {
+        Runner { name: name.to_string(), version: version.to_string(), endpoint: None, token: None }
+    }
+
+    /// connects to endpoint instead of the address in the environment
+    pub fn connect_to(mut self, endpoint: Endpoint) -> Self {
+        self.endpoint = Some(endpoint);
+        self
+    }
+
+    /// authenticates with token instead of `PALLEON_TOKEN`, e.g. with the one the core wrote to
+    /// the token file of a plugin in the listen mode
+    pub fn token(mut self, token: &str) -> Self {
+        self.token = Some(token.to_string());
+        self
+    }
+
+    fn connect(&self) -> Result<Connection, Error> {
+        Connection::open(self.endpoint.as_ref(), self.token.as_deref())
+    }
+
+    pub fn run_input(&self, plugin: &mut impl InputPlugin) -> Result<(), Error> {
+        let mut connection = self.connect()?;
+        self.hello(&mut connection, Document::new(), Document::new())?;
+
+        loop {
+            match connection.recv_message()? {
+                (name, _) if name == "request_frame" => {
+                    let frame = fail_with(&mut connection, plugin.next_frame())?;
+                    match frame {
+                        Some(data) => {
+                            let mut frame = doc! { "type": "frame", "data": binary(data) };
+                            let metadata = plugin.metadata();
+                            if !metadata.is_empty() {
+                                frame.insert("metadata", metadata);
+                            }
+                            connection.send(&frame)?
+                        }
+                        None => {
+                            let mut no_frame = doc! { "type": "no_frame" };
+                            if let Some(retry_after) = plugin.retry_after() {
+                                no_frame.insert("retry_after_ms", i64::try_from(retry_after.as_millis()).unwrap_or(i64::MAX));
+                            }
+                            connection.send(&no_frame)?
+                        }
+                    }
+                }
+                (name, message) => {
+                    if handle_common(&mut connection, &name, &message)? {
+                        plugin.shutdown();
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn run_data(&self, plugin: &mut impl DataPlugin) -> Result<(), Error> {
+        // the hello holds the numbers of values as 32 bit integers
+        let dependencies = plugin.dependencies().into_iter()
+            .map(|(name, values)| match i32::try_from(values) {
+                Ok(values) => Ok((name, Bson::Int32(values))),
+                Err(_) => Err(Error::Plugin(format!("the dependency {:?} asks for {} values, at most {} are possible", name, values, i32::MAX))),
+            })
+            .collect::<Result<Document, Error>>()?;
+
+        let mut connection = self.connect()?;
+
+        let mut capabilities = Document::new();
+        if let Some(schema) = plugin.output_schema() {
+            capabilities.insert("output_schema", schema);
+        }
+        self.hello(&mut connection, capabilities, doc! { "image": plugin.wants_image(), "dependencies": dependencies })?;
+
+        // the dependency data always comes right before its image
+        let mut dependency_data = Document::new();
+
+        loop {
+            match connection.recv_message()? {
+                (name, mut message) if name == "dependency_data" => {
+                    dependency_data = message.remove("data").and_then(|data| data.as_document().cloned())
+                        .ok_or_else(|| Error::Protocol("'dependency_data' without 'data'".to_string()))?;
+                }
+                (name, message) if name == "image" => {
+                    let image = message.get_document("image").ok().and_then(image_from_document)
+                        .ok_or_else(|| Error::Protocol("'image' without a valid 'image'".to_string()))?;
+                    let result = fail_with(&mut connection, plugin.process(&image, &dependency_data))?;
+                    connection.send(&doc! { "type": "result", "result": result })?;
+                }
+                (name, message) => {
+                    if handle_common(&mut connection, &name, &message)? {
+                        plugin.shutdown();
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    // sends the hello with the fields of the kind of plugin and checks the answer
+    // the runner declares no optional capabilities, so there is nothing accepted to look at
+    fn hello(&self, connection: &mut Connection, mut capabilities: Document, fields: Document) -> Result<(), Error> {
+        capabilities.insert("push", false);
+        capabilities.insert("batch_size", 1);
+
+        let mut hello = doc! {
+            "protocol_version": PROTOCOL_VERSION,
+            "name": &self.name,
+            "version": &self.version,
+            "capabilities": capabilities,
+        };
+        hello.extend(fields);
+        connection.send(&hello)?;
+
+        let answer = connection.recv()?;
+        match answer.get_i32("protocol_version") {
+            Ok(version) if version >= PROTOCOL_VERSION => Ok(()),
+            Ok(version) => Err(Error::Protocol(format!("the core speaks protocol version {}, {} is needed", version, PROTOCOL_VERSION))),
+            Err(_) => Err(Error::Protocol("the answer to the hello has no protocol_version".to_string())),
+        }
+    }
+}
+
+// the messages every kind of plugin handles, true on shutdown
+fn handle_common(connection: &mut Connection, name: &str, message: &Document) -> Result<bool, Error> {
+    match name {
+        "ping" => connection.send(&doc! { "type": "pong" }).map(|_| false),
+        "shutdown" => Ok(true),
+        "error" => Err(Error::Core(message.get_str("message").unwrap_or_default().to_string())),
+        _ => Err(Error::Protocol(format!("'{}' is not expected", name))),
+    }
+}
+
+// an error of the plugin is reported to the core before the connection is closed
+fn fail_with<T>(connection: &mut Connection, result: PluginResult<T>) -> Result<T, Error> {
+    result.map_err(|e| {
+        let _ = connection.send(&doc! { "type": "error", "message": e.to_string() });
+        Error::Plugin(e.to_string())
+    })
+}
+
+fn binary(data: Vec<u8>) -> Bson {
+    Bson::Binary(bson::Binary { subtype: bson::spec::BinarySubtype::Generic, bytes: data })
+}
+
+fn image_from_document(image: &Document) -> Option<Image> {
+    let timestamp = image.get_datetime("timestamp").ok()?.to_system_time();
+    Some(Image {
+        data: image.get_binary_generic("data").ok().cloned(),
+        input_source: image.get_str("input_source").ok()?.to_string(),
+        timestamp,
+        arrival_time: image.get_datetime("arrival_time").map(|time| time.to_system_time()).unwrap_or(timestamp),
+        metadata: image.get_document("metadata").ok().cloned().unwrap_or_default(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+
+    static NEXT_SOCKET: AtomicUsize = AtomicUsize::new(0);
+
+    // the core's side of a connection, the runner runs in its own thread
+    struct FakeCore {
+        stream: UnixStream,
+        path: PathBuf,
+    }
+
+    impl FakeCore {
+        // accepts the runner, checks its token and answers the hello, returns the hello
+        fn start(run: impl FnOnce(Runner) -> Result<(), Error> + Send + 'static) -> (FakeCore, Document, thread::JoinHandle<Result<(), Error>>) {
+            let path = std::env::temp_dir().join(format!("palleon-plugin-test-{}-{}.sock", std::process::id(), NEXT_SOCKET.fetch_add(1, Ordering::SeqCst)));
+            let listener = UnixListener::bind(&path).unwrap();
+            let runner = Runner::new("test", "1.0").connect_to(Endpoint::Unix(path.clone())).token("secret");
+            let plugin = thread::spawn(move || run(runner));
+
+            let (stream, _) = listener.accept().unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+            let mut core = FakeCore { stream, path };
+            assert_eq!(core.recv(), doc! { "token": "secret" });
+            core.send(doc! { "authenticated": true });
+            let hello = core.recv();
+            core.send(doc! { "protocol_version": 2, "accepted": {} });
+
+            (core, hello, plugin)
+        }
+
+        fn send(&mut self, doc: Document) {
+            let mut buffer = Vec::new();
+            doc.to_writer(&mut buffer).unwrap();
+            self.stream.write_all(&(buffer.len() as u32).to_le_bytes()).unwrap();
+            self.stream.write_all(&buffer).unwrap();
+        }
+
+        fn recv(&mut self) -> Document {
+            let mut length = [0u8; 4];
+            self.stream.read_exact(&mut length).unwrap();
+            let mut buffer = vec![0u8; u32::from_le_bytes(length) as usize];
+            self.stream.read_exact(&mut buffer).unwrap();
+            Document::from_reader(buffer.as_slice()).unwrap()
+        }
+    }
+
+    impl Drop for FakeCore {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    // a frame with metadata, then no frame for 250 ms, then an error
+    struct Camera {
+        frames: u32,
+        shut_down: bool,
+    }
+
+    impl InputPlugin for Camera {
+        fn next_frame(&mut self) -> PluginResult<Option<Vec<u8>>> {
+            self.frames += 1;
+            match self.frames {
+                1 => Ok(Some(vec![1, 2, 3])),
+                2 => Ok(None),
+                _ => Err("the camera is gone".into()),
+            }
+        }
+
+        fn metadata(&mut self) -> Document {
+            doc! { "width": 3 }
+        }
+
+        fn retry_after(&mut self) -> Option<Duration> {
+            Some(Duration::from_millis(250))
+        }
+
+        fn shutdown(&mut self) {
+            self.shut_down = true;
+        }
+    }
+
+    // sums its input with the last value of "other"
+    struct Sum;
+
+    impl DataPlugin for Sum {
+        fn dependencies(&self) -> Vec<(String, u32)> {
+            vec![("other".to_string(), 1)]
+        }
+
+        fn process(&mut self, image: &Image, dependency_data: &Document) -> PluginResult<Document> {
+            let other = dependency_data.get_array("other")?[0].as_array().ok_or("no value")?[1].as_i32().ok_or("no number")?;
+            let sum = image.data.as_ref().ok_or("no data")?.iter().map(|b| *b as i32).sum::<i32>() + other;
+            Ok(doc! { "sum": sum, "source": &image.input_source })
+        }
+    }
+
+    #[test]
+    fn an_input_plugin_answers_the_requests_of_the_core() {
+        let (mut core, hello, plugin) = FakeCore::start(|runner| {
+            let mut camera = Camera { frames: 0, shut_down: false };
+            runner.run_input(&mut camera)
+        });
+        assert_eq!(hello.get_i32("protocol_version"), Ok(2));
+        assert_eq!(hello.get_str("name"), Ok("test"));
+
+        core.send(doc! { "type": "request_frame" });
+        let frame = core.recv();
+        assert_eq!(frame.get_str("type"), Ok("frame"));
+        assert_eq!(frame.get_binary_generic("data").unwrap(), &vec![1, 2, 3]);
+        assert_eq!(frame.get_document("metadata").unwrap(), &doc! { "width": 3 });
+
+        core.send(doc! { "type": "ping" });
+        assert_eq!(core.recv(), doc! { "type": "pong" });
+
+        core.send(doc! { "type": "request_frame" });
+        assert_eq!(core.recv(), doc! { "type": "no_frame", "retry_after_ms": 250_i64 });
+
+        core.send(doc! { "type": "request_frame" });
+        assert_eq!(core.recv(), doc! { "type": "error", "message": "the camera is gone" });
+        assert!(matches!(plugin.join().unwrap(), Err(Error::Plugin(_))));
+    }
+
+    #[test]
+    fn an_input_plugin_shuts_down_when_asked() {
+        let (mut core, _, plugin) = FakeCore::start(|runner| {
+            let mut camera = Camera { frames: 0, shut_down: false };
+            runner.run_input(&mut camera)?;
+            assert!(camera.shut_down);
+            Ok(())
+        });
+
+        core.send(doc! { "type": "shutdown" });
+        assert!(plugin.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn a_data_plugin_gets_its_dependencies_and_images() {
+        let (mut core, hello, plugin) = FakeCore::start(|runner| runner.run_data(&mut Sum));
+        assert_eq!(hello.get_bool("image"), Ok(true));
+        assert_eq!(hello.get_document("dependencies").unwrap(), &doc! { "other": 1 });
+
+        let now = bson::DateTime::from_system_time(SystemTime::now());
+        core.send(doc! { "type": "dependency_data", "data": { "other": [[now, 10]] } });
+        core.send(doc! { "type": "image", "image": { "data": binary(vec![1, 2]), "input_source": "camera", "timestamp": now } });
+        assert_eq!(core.recv(), doc! { "type": "result", "result": { "sum": 13, "source": "camera" } });
+
+        core.send(doc! { "type": "shutdown" });
+        assert!(plugin.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn a_rejected_token_ends_the_runner() {
+        let path = std::env::temp_dir().join(format!("palleon-plugin-test-{}-{}.sock", std::process::id(), NEXT_SOCKET.fetch_add(1, Ordering::SeqCst)));
+        let listener = UnixListener::bind(&path).unwrap();
+        let plugin = thread::spawn({
+            let path = path.clone();
+            move || Runner::new("test", "1.0").connect_to(Endpoint::Unix(path)).token("wrong").run_data(&mut Sum)
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let mut core = FakeCore { stream, path };
+        core.recv();
+        core.send(doc! { "authenticated": false });
+        assert!(matches!(plugin.join().unwrap(), Err(Error::Rejected)));
+    }
+
+    struct Greedy;
+
+    impl DataPlugin for Greedy {
+        fn dependencies(&self) -> Vec<(String, u32)> {
+            vec![("other".to_string(), u32::MAX)]
+        }
+
+        fn process(&mut self, _: &Image, _: &Document) -> PluginResult<Document> {
+            Ok(Document::new())
+        }
+    }
+
+    #[test]
+    fn more_values_than_the_hello_can_hold_are_refused_before_connecting() {
+        // nobody listens there
+        let runner = Runner::new("test", "1.0").connect_to(Endpoint::Unix(PathBuf::from("/palleon/no/such/socket")));
+        assert!(matches!(runner.run_data(&mut Greedy), Err(Error::Plugin(message)) if message.contains("4294967295")));
+    }
+}
@@ -0,0 +1,208 @@
+use std::{fmt, fs, io};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::Duration;
+
+use bson::{doc, Document};
+
+use crate::transport::{self, Transport};
+use crate::wrapped_stream::{self, MessageType, ProtocolError, WrappedStream};
+
+// every connection to a plugin or gui socket has to prove that it belongs to the core first
+// the handshake is the first message on the connection, using the framing of the data plugins:
+// - the connecting side sends the bson document {"token": "<token>"}
+// - the listening side answers {"authenticated": true} or {"authenticated": false} and closes the
+//   connection in the latter case
+// spawned plugins get their token in PALLEON_TOKEN, see the README for the other cases
+
+// an unauthenticated connection has this long to send its token (and the other side this long to
+// answer it), the token is the first thing sent, so this is plenty even for a plugin on another host
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub enum AuthError {
+    Protocol(ProtocolError),
+    // the token was missing or wrong
+    Rejected(String),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Protocol(e) => write!(f, "the handshake failed: {}", e),
+            AuthError::Rejected(reason) => write!(f, "the handshake was rejected: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+impl From<ProtocolError> for AuthError {
+    fn from(e: ProtocolError) -> Self {
+        AuthError::Protocol(e)
+    }
+}
+
+// 32 random bytes from the os, hex encoded
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    getrandom::getrandom(&mut bytes).expect("the os failed to provide random bytes");
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// writes the token to a file only the core's user can read, for clients that are not started by the core
+pub fn write_token_file(path: &Path, token: &str) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        transport::create_runtime_dir(dir)?;
+    }
+
+    // an existing file keeps its permissions, so it is replaced instead of truncated
+    let _ = fs::remove_file(path);
+    let mut file = fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)?;
+    file.write_all(token.as_bytes())?;
+    file.write_all(b"\n")
+}
+
+// the listening side of the handshake
+pub async fn accept<T: Transport>(stream: &mut WrappedStream<T>, token: &str) -> Result<(), AuthError> {
+    accept_with(stream, token, |_| Document::new()).await
+}
+
+// like accept, the fields returned by answer (which gets the received handshake) are added to
+// the answer of an accepted handshake, e.g. the compression of the gui connection
+pub async fn accept_with<T: Transport>(stream: &mut WrappedStream<T>, token: &str, answer: impl FnOnce(&Document) -> Document) -> Result<(), AuthError> {
+    let hello = wrapped_stream::timeout(HANDSHAKE_TIMEOUT, stream.recv_bson(MessageType::Handshake)).await?;
+
+    let authenticated = match hello.get_str("token") {
+        Ok(received) => constant_time_eq(received.as_bytes(), token.as_bytes()),
+        Err(_) => false,
+    };
+
+    let mut reply = doc! { "authenticated": authenticated };
+    if authenticated {
+        reply.extend(answer(&hello));
+    }
+    stream.send_bson(&reply).await?;
+
+    if authenticated {
+        Ok(())
+    } else if hello.contains_key("token") {
+        Err(AuthError::Rejected("wrong token".to_string()))
+    } else {
+        Err(AuthError::Rejected("no token sent".to_string()))
+    }
+}
+
+// the connecting side of the handshake
+pub async fn present<T: Transport>(stream: &mut WrappedStream<T>, token: &str) -> Result<(), AuthError> {
+    stream.send_bson(&doc! { "token": token }).await?;
+
+    let answer = wrapped_stream::timeout(HANDSHAKE_TIMEOUT, stream.recv_bson(MessageType::Handshake)).await?;
+
+    match answer.get_bool("authenticated") {
+        Ok(true) => Ok(()),
+        _ => Err(AuthError::Rejected("the plugin did not accept the token".to_string())),
+    }
+}
+
+// compares without returning early, so the time taken tells nothing about how much of a guess was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use tokio::net::UnixStream;
+
+    use super::*;
+
+    fn pair() -> (WrappedStream<UnixStream>, WrappedStream<UnixStream>) {
+        let (a, b) = UnixStream::pair().unwrap();
+        (WrappedStream::new(a), WrappedStream::new(b))
+    }
+
+    #[tokio::test]
+    async fn the_right_token_is_accepted() {
+        let (mut core, mut plugin) = pair();
+        let (accepted, presented) = tokio::join!(accept(&mut core, "secret"), present(&mut plugin, "secret"));
+        assert!(accepted.is_ok());
+        assert!(presented.is_ok());
+    }
+
+    #[tokio::test]
+    async fn a_wrong_token_is_rejected_on_both_sides() {
+        let (mut core, mut plugin) = pair();
+        let (accepted, presented) = tokio::join!(accept(&mut core, "secret"), present(&mut plugin, "guess"));
+        assert!(matches!(accepted, Err(AuthError::Rejected(_))));
+        assert!(matches!(presented, Err(AuthError::Rejected(_))));
+    }
+
+    #[tokio::test]
+    async fn a_handshake_without_a_token_is_rejected() {
+        let (mut core, mut plugin) = pair();
+        let handshake = doc! { "name": "camera" };
+        let (accepted, _) = tokio::join!(accept(&mut core, "secret"), plugin.send_bson(&handshake));
+        assert!(matches!(accepted, Err(AuthError::Rejected(reason)) if reason == "no token sent"));
+        assert_eq!(plugin.recv_bson(MessageType::Handshake).await.unwrap(), doc! { "authenticated": false });
+    }
+
+    #[tokio::test]
+    async fn a_silent_peer_times_out() {
+        let (mut core, _plugin) = pair();
+        match accept(&mut core, "secret").await {
+            Err(AuthError::Protocol(e)) => assert!(e.is_timeout()),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn the_answer_gets_the_extra_fields_only_if_authenticated() {
+        let (mut core, mut plugin) = pair();
+        let answer = |hello: &Document| doc! { "echo": hello.get_str("token").unwrap_or_default() };
+        let handshake = doc! { "token": "secret" };
+        let (accepted, _) = tokio::join!(accept_with(&mut core, "secret", answer), plugin.send_bson(&handshake));
+        assert!(accepted.is_ok());
+        assert_eq!(plugin.recv_bson(MessageType::Handshake).await.unwrap(), doc! { "authenticated": true, "echo": "secret" });
+    }
+
+    #[test]
+    fn tokens_are_compared_completely() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(!constant_time_eq(b"", b"secret"));
+    }
+
+    #[test]
+    fn generated_tokens_differ() {
+        let (a, b) = (generate_token(), generate_token());
+        assert_eq!(a.len(), 64);
+        assert!(a.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn the_token_file_is_only_readable_by_the_core_user() {
+        let dir = std::env::temp_dir().join(format!("palleon-auth-test-{}", std::process::id()));
+        let path = dir.join("token");
+        let _ = fs::remove_dir_all(&dir);
+
+        // an existing file that others could read is replaced
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&path, "old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        write_token_file(&path, "secret").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "secret\n");
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
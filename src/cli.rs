@@ -0,0 +1,60 @@
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+use log::LevelFilter;
+
+use palleon_core::config::Overrides;
+
+// the command line interface of the core
+// everything that is not given here is taken from the config file, the overrides
+// are mostly useful when running multiple cores from the same config or from a systemd unit
+
+#[derive(Parser, Debug)]
+#[command(version, about = "the core of palleon, connects input plugins, data plugins and the gui")]
+pub struct Cli {
+    /// path to the config file
+    #[arg(short, long, default_value = "config.toml", global = true)]
+    pub config: PathBuf,
+
+    /// overrides bind_addr of the config file
+    #[arg(long, global = true)]
+    pub bind_addr: Option<String>,
+
+    /// overrides bind_port_gui of the config file
+    #[arg(long, global = true)]
+    pub bind_port_gui: Option<i32>,
+
+    /// log level (off, error, warn, info, debug, trace), takes precedence over RUST_LOG
+    #[arg(long, global = true)]
+    pub log_level: Option<LevelFilter>,
+
+    #[command(subcommand)]
+    pub command: Option<CliCommand>,
+}
+
+#[derive(Subcommand, Debug, Clone, PartialEq, Eq)]
+pub enum CliCommand {
+    /// start all plugins and run the core (the default)
+    Run,
+    /// parse and validate the config and print the resolved plugins and ports without starting anything
+    CheckConfig,
+    /// print the version of the core
+    Version,
+    /// play the plugin side of a recorded connection back against the core (as the command of a plugin)
+    Replay {
+        /// the recording, see record_dir
+        recording: PathBuf,
+        /// keep the delays between the messages of the plugin as recorded
+        #[arg(long)]
+        realtime: bool,
+    },
+}
+
+impl Cli {
+    pub fn overrides(&self) -> Overrides {
+        Overrides {
+            bind_addr: self.bind_addr.clone(),
+            bind_port_gui: self.bind_port_gui,
+        }
+    }
+}
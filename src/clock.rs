@@ -0,0 +1,198 @@
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use log::{debug, info, warn};
+
+use crate::config::{TimestampConfig, TimestampKey};
+
+// an image has two times: when the camera captured it (if the input plugin tells, see
+// FrameMetadata) and when it arrived at the core, the timestamp config of the plugin says which
+// one the results of the data plugins are stored under
+// the capture time is in the clock of the plugin, so the difference to the arrival time (the
+// offset) is the latency plus the difference between the clocks, the latency varies, but its
+// minimum over a while is about the same, so a changing minimum means the clock of the plugin
+// is drifting away from the core's
+// the minimum of the first window of drift_window_secs is the reference, every following window
+// is compared with it, a drift of more than max_drift_ms is reported (once, until it is back)
+// while the clock drifts, the images are stored under their arrival time even with the key
+// capture, so the history of the data manager stays in order
+
+pub struct Clock {
+    name: String,
+    config: TimestampConfig,
+    // the minimal offset of the first complete window, in ms
+    reference: Option<i64>,
+    window: Window,
+    drifting: bool,
+}
+
+struct Window {
+    started: Instant,
+    min_offset: Option<i64>,
+}
+
+impl Window {
+    fn new(started: Instant) -> Self {
+        Window { started, min_offset: None }
+    }
+}
+
+impl Clock {
+    pub fn new(name: &str, config: &TimestampConfig) -> Self {
+        Clock { name: name.to_string(), config: config.clone(), reference: None, window: Window::new(Instant::now()), drifting: false }
+    }
+
+    // the timestamp of an image that arrived at arrival, with the capture time the plugin sent
+    pub fn timestamp(&mut self, arrival: SystemTime, capture: Option<SystemTime>) -> SystemTime {
+        let Some(capture) = capture else { return arrival; };
+
+        self.observe(millis(arrival) - millis(capture), Instant::now());
+
+        match self.config.key {
+            TimestampKey::Capture if !self.drifting => capture,
+            TimestampKey::Capture | TimestampKey::Arrival => arrival,
+        }
+    }
+
+    // an offset observed at now, which closes the window once it is drift_window_secs old
+    fn observe(&mut self, offset: i64, now: Instant) {
+        self.window.min_offset = Some(self.window.min_offset.map_or(offset, |min| min.min(offset)));
+
+        if now.saturating_duration_since(self.window.started).as_secs() < self.config.drift_window_secs {
+            return;
+        }
+        let min_offset = self.window.min_offset.unwrap_or(offset);
+        self.window = Window::new(now);
+
+        let Some(reference) = self.reference else {
+            debug!("the clock of plugin {:?} is {} ms behind the core's (including the latency)", self.name, min_offset);
+            self.reference = Some(min_offset);
+            return;
+        };
+
+        let drift = reference - min_offset;
+        let drifting = self.config.max_drift_ms > 0 && drift.unsigned_abs() > self.config.max_drift_ms;
+        match (self.drifting, drifting) {
+            (false, true) => warn!("the clock of plugin {:?} drifted {} ms {} the core's since it connected, using the arrival time until it is back", self.name, drift.abs(), if drift > 0 { "ahead of" } else { "behind" }),
+            (true, false) => info!("the clock of plugin {:?} is back in sync with the core's ({} ms drift), using the capture time again", self.name, drift),
+            _ => debug!("the clock of plugin {:?} drifted {} ms", self.name, drift),
+        }
+        self.drifting = drifting;
+    }
+}
+
+// a time as ms since the epoch, negative before it
+fn millis(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => i64::try_from(since.as_millis()).unwrap_or(i64::MAX),
+        Err(e) => -i64::try_from(e.duration().as_millis()).unwrap_or(i64::MAX),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn clock(max_drift_ms: u64) -> (Clock, Instant) {
+        let config = TimestampConfig { key: TimestampKey::Capture, max_drift_ms, drift_window_secs: 10 };
+        let clock = Clock::new("camera", &config);
+        let started = clock.window.started;
+        (clock, started)
+    }
+
+    // the offsets, observed one second apart from second on
+    fn observe(clock: &mut Clock, started: Instant, second: u64, offsets: &[i64]) {
+        for (i, offset) in offsets.iter().enumerate() {
+            clock.observe(*offset, started + Duration::from_secs(second + i as u64));
+        }
+    }
+
+    #[test]
+    fn the_minimum_of_the_first_window_is_the_reference() {
+        let (mut clock, started) = clock(1000);
+
+        observe(&mut clock, started, 0, &[50, 30, 40]);
+        assert_eq!(clock.reference, None);
+
+        // the observation at 10 s closes the window, and is part of it
+        observe(&mut clock, started, 10, &[60]);
+        assert_eq!(clock.reference, Some(30));
+        assert!(!clock.drifting);
+    }
+
+    #[test]
+    fn a_drift_is_reported_until_the_clocks_agree_again() {
+        let (mut clock, started) = clock(1000);
+        observe(&mut clock, started, 0, &[30]);
+        observe(&mut clock, started, 10, &[30]);
+
+        // within max_drift_ms
+        observe(&mut clock, started, 11, &[900, 1030]);
+        observe(&mut clock, started, 20, &[1500]);
+        assert!(!clock.drifting);
+
+        // the plugin's clock fell behind by 1100 ms
+        observe(&mut clock, started, 21, &[1130, 1500]);
+        observe(&mut clock, started, 30, &[1200]);
+        assert!(clock.drifting);
+
+        // the minimum of a window counts, not single late frames
+        observe(&mut clock, started, 31, &[40, 2000]);
+        observe(&mut clock, started, 40, &[2000]);
+        assert!(!clock.drifting);
+        assert_eq!(clock.reference, Some(30));
+    }
+
+    #[test]
+    fn a_clock_ahead_of_the_core_drifts_as_well() {
+        let (mut clock, started) = clock(1000);
+        observe(&mut clock, started, 0, &[30]);
+        observe(&mut clock, started, 10, &[30]);
+
+        observe(&mut clock, started, 11, &[-1500]);
+        observe(&mut clock, started, 20, &[0]);
+        assert!(clock.drifting);
+    }
+
+    #[test]
+    fn a_max_drift_of_0_never_reports() {
+        let (mut clock, started) = clock(0);
+        observe(&mut clock, started, 0, &[30]);
+        observe(&mut clock, started, 10, &[30]);
+
+        observe(&mut clock, started, 11, &[100_000]);
+        observe(&mut clock, started, 20, &[100_000]);
+        assert!(!clock.drifting);
+    }
+
+    #[test]
+    fn a_drifting_clock_falls_back_to_the_arrival_time_until_it_is_back_in_sync() {
+        let arrival = UNIX_EPOCH + Duration::from_secs(1000);
+        let capture = arrival - Duration::from_millis(40);
+        let (mut clock, started) = clock(1000);
+        observe(&mut clock, started, 0, &[30]);
+        observe(&mut clock, started, 10, &[30]);
+
+        observe(&mut clock, started, 11, &[1500]);
+        observe(&mut clock, started, 20, &[1500]);
+        assert_eq!(clock.timestamp(arrival, Some(capture)), arrival);
+
+        observe(&mut clock, started, 21, &[30]);
+        observe(&mut clock, started, 30, &[30]);
+        assert_eq!(clock.timestamp(arrival, Some(capture)), capture);
+    }
+
+    #[test]
+    fn the_timestamp_is_the_capture_or_the_arrival_time() {
+        let arrival = UNIX_EPOCH + Duration::from_secs(1000);
+        let capture = arrival - Duration::from_millis(40);
+
+        let (mut clock, _) = clock(1000);
+        assert_eq!(clock.timestamp(arrival, Some(capture)), capture);
+        assert_eq!(clock.timestamp(arrival, None), arrival);
+
+        let mut clock = Clock::new("camera", &TimestampConfig { key: TimestampKey::Arrival, ..TimestampConfig::default() });
+        assert_eq!(clock.timestamp(arrival, Some(capture)), arrival);
+    }
+}
@@ -0,0 +1,214 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::Deserialize;
+
+use crate::wrapped_stream::{MessageType, ProtocolError};
+
+// the frames of a connection can be compressed, if both sides agree on an algorithm:
+// - plugins list the algorithms they support in the capabilities of their hello, the core
+//   accepts the first one of the plugin's `compression` config that the plugin supports
+// - the gui lists them as "compression" in its handshake, the core answers with the first one
+//   of `gui_compression` the gui supports
+// from then on the payload of every frame (after the handshake and the hello) is compressed,
+// the length in front of it is the compressed length
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Zstd,
+    Lz4,
+}
+
+const ZSTD_LEVEL: i32 = 3;
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::Zstd => f.pad("zstd"),
+            Compression::Lz4 => f.pad("lz4"),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zstd" => Ok(Compression::Zstd),
+            "lz4" => Ok(Compression::Lz4),
+            _ => Err(format!("unknown compression {:?}", s)),
+        }
+    }
+}
+
+impl Compression {
+    // the first of offered (in that order) that is in supported
+    pub fn negotiate(offered: &[Compression], supported: &[String]) -> Option<Compression> {
+        offered.iter().copied().find(|c| supported.iter().any(|s| s.parse() == Ok(*c)))
+    }
+
+    pub fn compress(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Compression::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL).expect("compressing a frame with zstd failed"),
+            Compression::Lz4 => lz4_flex::compress_prepend_size(data),
+        }
+    }
+
+    // both formats contain the size of the decompressed data, which is checked against the
+    // limit of message before anything is allocated for it
+    pub fn decompress(self, data: &[u8], message: MessageType, limit: u32) -> Result<Vec<u8>, ProtocolError> {
+        let invalid = |e: &dyn fmt::Display| ProtocolError::Compression(format!("{}: {}", self, e));
+
+        let (size, data) = match self {
+            Compression::Zstd => match zstd::zstd_safe::get_frame_content_size(data) {
+                Ok(Some(size)) => (size, data),
+                Ok(None) => return Err(invalid(&"the frame does not contain its decompressed size")),
+                Err(e) => return Err(invalid(&format!("{:?}", e))),
+            },
+            Compression::Lz4 => {
+                let (size, data) = lz4_flex::block::uncompressed_size(data).map_err(|e| invalid(&e))?;
+                (size as u64, data)
+            }
+        };
+
+        if size > limit as u64 {
+            return Err(ProtocolError::OversizedFrame { message, size: size.min(u32::MAX as u64) as u32, limit });
+        }
+
+        match self {
+            Compression::Zstd => {
+                let decompressed = zstd::bulk::decompress(data, size as usize).map_err(|e| invalid(&e))?;
+                if decompressed.len() as u64 != size {
+                    return Err(invalid(&"the decompressed size does not match the frame"));
+                }
+                Ok(decompressed)
+            }
+            Compression::Lz4 => {
+                let mut decompressed = vec![0u8; size as usize];
+                let written = lz4_flex::block::decompress_into(data, &mut decompressed).map_err(|e| invalid(&e))?;
+                if written != decompressed.len() {
+                    return Err(invalid(&"the decompressed size does not match the frame"));
+                }
+                Ok(decompressed)
+            }
+        }
+    }
+}
+
+// how well the compression of a connection works, summed up per plugin (or "gui") since the
+// core started
+
+#[derive(Default)]
+pub struct CompressionStats {
+    frames: AtomicU64,
+    // before compressing or after decompressing
+    raw_bytes: AtomicU64,
+    // what went over the connection
+    wire_bytes: AtomicU64,
+}
+
+impl CompressionStats {
+    pub fn add(&self, raw_bytes: usize, wire_bytes: usize) {
+        self.frames.fetch_add(1, Ordering::Relaxed);
+        self.raw_bytes.fetch_add(raw_bytes as u64, Ordering::Relaxed);
+        self.wire_bytes.fetch_add(wire_bytes as u64, Ordering::Relaxed);
+    }
+}
+
+impl fmt::Display for CompressionStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let raw_bytes = self.raw_bytes.load(Ordering::Relaxed);
+        let wire_bytes = self.wire_bytes.load(Ordering::Relaxed);
+        let ratio = if wire_bytes == 0 { 1.0 } else { raw_bytes as f64 / wire_bytes as f64 };
+        write!(f, "{} frames, {} bytes compressed to {} bytes (ratio {:.2})", self.frames.load(Ordering::Relaxed), raw_bytes, wire_bytes, ratio)
+    }
+}
+
+static COMPRESSION_STATS: Mutex<BTreeMap<String, Arc<CompressionStats>>> = Mutex::new(BTreeMap::new());
+
+// the stats of name, shared by all of its connections
+pub fn stats(name: &str) -> Arc<CompressionStats> {
+    COMPRESSION_STATS.lock().unwrap().entry(name.to_string()).or_default().clone()
+}
+
+pub fn all_stats() -> Vec<(String, Arc<CompressionStats>)> {
+    COMPRESSION_STATS.lock().unwrap().iter().map(|(name, stats)| (name.clone(), stats.clone())).collect()
+}
+
+// the compression of one connection
+pub struct Compressor {
+    pub compression: Compression,
+    stats: Arc<CompressionStats>,
+}
+
+impl Compressor {
+    pub fn new(compression: Compression, name: &str) -> Self {
+        Compressor { compression, stats: stats(name) }
+    }
+
+    pub fn compress(&self, data: &[u8]) -> Vec<u8> {
+        let compressed = self.compression.compress(data);
+        self.stats.add(data.len(), compressed.len());
+        compressed
+    }
+
+    pub fn decompress(&self, data: &[u8], message: MessageType, limit: u32) -> Result<Vec<u8>, ProtocolError> {
+        let decompressed = self.compression.decompress(data, message, limit)?;
+        self.stats.add(decompressed.len(), data.len());
+        Ok(decompressed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame() -> Vec<u8> {
+        (0..10_000u32).map(|i| (i % 7) as u8).collect()
+    }
+
+    #[test]
+    fn frames_survive_compression() {
+        for compression in [Compression::Zstd, Compression::Lz4] {
+            let compressed = compression.compress(&frame());
+            assert!(compressed.len() < frame().len(), "{} did not compress", compression);
+            assert_eq!(compression.decompress(&compressed, MessageType::Image, 10_000).unwrap(), frame());
+        }
+    }
+
+    #[test]
+    fn the_limit_is_checked_before_decompressing() {
+        for compression in [Compression::Zstd, Compression::Lz4] {
+            let compressed = compression.compress(&frame());
+            match compression.decompress(&compressed, MessageType::Image, 9_999) {
+                Err(ProtocolError::OversizedFrame { size, limit, .. }) => assert_eq!((size, limit), (10_000, 9_999)),
+                result => panic!("{}: {:?}", compression, result.map(|data| data.len())),
+            }
+        }
+    }
+
+    #[test]
+    fn garbage_is_a_compression_error() {
+        for compression in [Compression::Zstd, Compression::Lz4] {
+            // lz4 takes the first 4 bytes as the size, zstd does not find its magic number
+            let garbage = [4, 0, 0, 0, 0xff, 0xff, 0xff, 0xff];
+            assert!(matches!(compression.decompress(&garbage, MessageType::Data, 1024), Err(ProtocolError::Compression(_))), "{}", compression);
+        }
+    }
+
+    #[test]
+    fn the_first_offered_algorithm_the_other_side_supports_is_chosen() {
+        let supported = |names: &[&str]| names.iter().map(|name| name.to_string()).collect::<Vec<_>>();
+        let offered = [Compression::Lz4, Compression::Zstd];
+
+        assert_eq!(Compression::negotiate(&offered, &supported(&["zstd", "lz4"])), Some(Compression::Lz4));
+        assert_eq!(Compression::negotiate(&offered, &supported(&["brotli", "zstd"])), Some(Compression::Zstd));
+        assert_eq!(Compression::negotiate(&offered, &supported(&["brotli"])), None);
+        assert_eq!(Compression::negotiate(&[], &supported(&["zstd"])), None);
+    }
+}
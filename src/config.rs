@@ -1,53 +1,457 @@
-use std::{fs, io};
-use std::collections::HashMap;
-use std::path::Path;
+use std::{env, fmt, fs, io};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::time::Duration;
+use indexmap::IndexMap;
 use serde::{Deserialize};
 
+use crate::compression::Compression;
+use crate::transport::Address;
+
 // config is the rust representation of the config.toml
 // its being deserialized using the toml and serde package
 // they also _kind of_ take care of validating the input,
-// but not completely
+// but not completely - the rest is done by validate() below
+
+// the plugins are kept in the order they are written in the config, which makes
+// everything derived from that order (like the ports) the same on every run
 
 #[derive(Deserialize, Debug)]
 pub struct Config {
     pub bind_addr: String,
-    pub bind_port_range_start: i32,
+    #[serde(default)]
+    pub port_allocation: PortAllocation,
+    pub bind_port_range_start: Option<i32>,
     pub bind_port_gui: i32,
+    // the token the gui has to send, generated and written to <runtime_dir>/gui.token if not set
+    pub gui_token: Option<String>,
+    // the compression algorithms offered to the gui, in order of preference (none by default)
+    #[serde(default)]
+    pub gui_compression: Vec<Compression>,
+    // where the unix sockets of the plugins are created, see runtime_dir()
+    pub runtime_dir: Option<PathBuf>,
+    // how long a stopping plugin gets to exit on its own and then after the SIGTERM
+    #[serde(default = "default_shutdown_grace_period_ms")]
+    pub shutdown_grace_period_ms: u64,
     #[serde(alias = "data")]
-    pub data_plugins: HashMap<String, PluginConfig>,
+    pub data_plugins: IndexMap<String, PluginConfig>,
     #[serde(alias = "input")]
-    pub input_plugins: HashMap<String, PluginConfig>,
+    pub input_plugins: IndexMap<String, PluginConfig>,
+    // the range ports plugins keep from the config running before a reload, see keep_ports()
+    #[serde(skip)]
+    pub kept_ports: HashMap<(PluginKind, String), i32>,
 }
 
-#[derive(Deserialize, Debug)]
+fn default_shutdown_grace_period_ms() -> u64 {
+    5000
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
 pub struct PluginConfig {
+    #[serde(default)]
+    pub mode: PluginMode,
+    #[serde(default)]
+    pub transport: PluginTransport,
+    // command and working_directory are required in the spawn mode only
+    #[serde(default)]
     pub command: Vec<String>,
     pub environment: Option<HashMap<String, String>>,
+    #[serde(default)]
     pub working_directory: String,
+    // explicit port, takes precedence over the port_allocation
+    pub port: Option<i32>,
+    // host:port (or the socket path with the unix transport) the core connects to, required in the connect mode
+    pub connect_addr: Option<String>,
+    // a fixed token instead of a generated one, see auth
+    // in the connect mode the core authenticates itself with it (and only if it is set)
+    pub token: Option<String>,
+    // a plugin written before the handshake and the hello existed (protocol version 0), it is
+    // neither asked for a token nor expected to send a hello, see plugin
+    #[serde(default)]
+    pub legacy: bool,
+    #[serde(default)]
+    pub restart: RestartConfig,
+    // how many lines of the plugin's output are kept in memory for crash reports
+    #[serde(default = "default_output_tail_lines")]
+    pub output_tail_lines: usize,
+    // additionally write the plugin's output to this file
+    pub log_file: Option<LogFileConfig>,
+    #[serde(default)]
+    pub limits: FrameLimits,
+    // the compression algorithms offered to the plugin, in order of preference (none by default)
+    #[serde(default)]
+    pub compression: Vec<Compression>,
+    // frames are passed through shared memory instead of the connection, if the plugin supports it
+    pub shm: Option<ShmConfig>,
+    // record every connection of the plugin to a file in this directory, see recorder
+    pub record_dir: Option<PathBuf>,
+    // how many frames an input plugin in the push mode may send ahead, 0 keeps it in the poll
+    // mode even if it supports the push mode (see input_plugins)
+    #[serde(default = "default_push_credits")]
+    pub push_credits: u32,
+    // what an input plugin that has no frame gets, see IdleConfig
+    #[serde(default)]
+    pub idle: IdleConfig,
+    // which time the images of an input plugin are stored under, see clock
+    #[serde(default)]
+    pub timestamps: TimestampConfig,
+    // how long a data plugin gets for the result of an image, a plugin that takes longer is
+    // disconnected (and restarted according to its restart policy)
+    #[serde(default = "default_result_timeout_ms")]
+    pub result_timeout_ms: u64,
+}
+
+fn default_output_tail_lines() -> usize {
+    100
+}
+
+fn default_push_credits() -> u32 {
+    4
+}
+
+fn default_result_timeout_ms() -> u64 {
+    5000
+}
+
+// the same as an empty table in the config, for configs that are built in code (e.g. by the tests)
+impl Default for PluginConfig {
+    fn default() -> Self {
+        PluginConfig {
+            mode: PluginMode::default(),
+            transport: PluginTransport::default(),
+            command: vec![],
+            environment: None,
+            working_directory: String::new(),
+            port: None,
+            connect_addr: None,
+            token: None,
+            legacy: false,
+            restart: RestartConfig::default(),
+            output_tail_lines: default_output_tail_lines(),
+            log_file: None,
+            limits: FrameLimits::default(),
+            compression: vec![],
+            shm: None,
+            record_dir: None,
+            push_credits: default_push_credits(),
+            idle: IdleConfig::default(),
+            timestamps: TimestampConfig::default(),
+            result_timeout_ms: default_result_timeout_ms(),
+        }
+    }
+}
+
+// a log file that is rotated once it gets bigger than max_bytes, the old ones are
+// kept as path.1 (the newest) up to path.<max_files>, relative to the core's working directory
+
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+pub struct LogFileConfig {
+    pub path: PathBuf,
+    #[serde(default = "default_log_file_max_bytes")]
+    pub max_bytes: u64,
+    #[serde(default = "default_log_file_max_files")]
+    pub max_files: usize,
+}
+
+fn default_log_file_max_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_log_file_max_files() -> usize {
+    5
+}
+
+// how the core gets in touch with a plugin
+// - spawn: the core listens on the plugin's port and starts the command, which connects to it
+// - listen: the core only listens on the plugin's port, the plugin is started by someone else
+//   (e.g. by hand in a debugger) and connects whenever it is ready
+// - connect: the core connects to a plugin that listens on connect_addr, e.g. on another machine
+// in the listen and connect mode there is no process, so only the connection is supervised
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PluginMode {
+    #[default]
+    Spawn,
+    Listen,
+    Connect,
+}
+
+// the biggest frame the core accepts from the plugin per type of message, in bytes
+// the length of a frame is checked before anything is allocated for it
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(default)]
+pub struct FrameLimits {
+    // the hello (or the init document of a data plugin of protocol version 0)
+    pub hello_bytes: u32,
+    // a frame sent by an input plugin
+    pub image_bytes: u32,
+    // a result sent by a data plugin
+    pub data_bytes: u32,
+}
+
+impl Default for FrameLimits {
+    fn default() -> Self {
+        FrameLimits {
+            hello_bytes: 1024 * 1024,
+            image_bytes: 64 * 1024 * 1024,
+            data_bytes: 16 * 1024 * 1024,
+        }
+    }
+}
+
+// the ring buffer in shared memory the frames of a plugin are passed through, see shm
+// it only works if the plugin runs on the same machine as the core
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(default)]
+pub struct ShmConfig {
+    pub slots: u32,
+    // the biggest frame that fits into a slot, bigger ones are sent over the connection
+    pub slot_bytes: u32,
+}
+
+impl Default for ShmConfig {
+    fn default() -> Self {
+        ShmConfig {
+            slots: 4,
+            slot_bytes: 8 * 1024 * 1024,
+        }
+    }
+}
+
+// how long the core waits before asking an input plugin that had no frame again
+// - fixed: always initial_backoff_ms
+// - exponential: initial_backoff_ms, doubled for every no_frame in a row up to max_backoff_ms
+// a plugin may suggest the delay with retry_after_ms in its no_frame (protocol version 2 on),
+// which is used instead (at least 10 ms, limited to max_backoff_ms) unless honor_retry_after is false
+// a plugin without a frame for warn_after_secs is reported once (0 never reports it)
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct IdleConfig {
+    pub backoff: IdleBackoff,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    pub honor_retry_after: bool,
+    pub warn_after_secs: u64,
+}
+
+impl Default for IdleConfig {
+    fn default() -> Self {
+        IdleConfig {
+            backoff: IdleBackoff::Fixed,
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 10_000,
+            honor_retry_after: true,
+            warn_after_secs: 30,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum IdleBackoff {
+    Fixed,
+    Exponential,
+}
+
+// the time the results for an image are stored under (and the image is sent with)
+// - capture: the capture_time the input plugin sent with the frame, the arrival time if it sent none
+// - arrival: the time the frame arrived at the core
+// with capture times the clock of the plugin is compared with the core's (see clock), a drift of
+// more than max_drift_ms within drift_window_secs windows is reported (0 never reports it)
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct TimestampConfig {
+    pub key: TimestampKey,
+    pub max_drift_ms: u64,
+    pub drift_window_secs: u64,
+}
+
+impl Default for TimestampConfig {
+    fn default() -> Self {
+        TimestampConfig {
+            key: TimestampKey::Capture,
+            max_drift_ms: 1000,
+            drift_window_secs: 10,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum TimestampKey {
+    Capture,
+    Arrival,
+}
+
+// how the core and the plugin are connected
+// - tcp: on bind_addr and the plugin's port, the plugin gets PALLEON_HOST and PALLEON_PORT
+// - unix: on the socket <runtime_dir>/<kind>-<name>.sock, the plugin gets PALLEON_SOCKET
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PluginTransport {
+    #[default]
+    Tcp,
+    Unix,
+}
+
+// what the supervisor does when a plugin stops
+// - never: the plugin stays stopped, the rest of the core keeps running
+// - on-failure: restart if the process failed or the connection handling stopped
+// - always: additionally restart if the process exited successfully
+// restarts are delayed by an exponential backoff, and the supervisor gives up after
+// max_restarts or once crash_loop_restarts restarts happened within crash_loop_window_secs
+
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(default)]
+pub struct RestartConfig {
+    pub policy: RestartPolicy,
+    pub max_restarts: Option<u32>,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    pub crash_loop_restarts: u32,
+    pub crash_loop_window_secs: u64,
+}
+
+impl Default for RestartConfig {
+    fn default() -> Self {
+        RestartConfig {
+            policy: RestartPolicy::OnFailure,
+            max_restarts: None,
+            initial_backoff_ms: 500,
+            max_backoff_ms: 30_000,
+            crash_loop_restarts: 5,
+            crash_loop_window_secs: 60,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    Never,
+    OnFailure,
+    Always,
+}
+
+// how plugins without an explicit port get theirs
+// - range: the n-th such plugin gets bind_port_range_start + n (input plugins first)
+// - ephemeral: the os picks a free port, the plugin learns it from PALLEON_PORT
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PortAllocation {
+    #[default]
+    Range,
+    Ephemeral,
+}
+
+// values given on the command line take precedence over the ones in the config file
+
+#[derive(Default, Debug)]
+pub struct Overrides {
+    pub bind_addr: Option<String>,
+    pub bind_port_gui: Option<i32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PluginKind {
+    Input,
+    Data,
+}
+
+impl fmt::Display for PluginKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PluginKind::Input => f.pad("input"),
+            PluginKind::Data => f.pad("data"),
+        }
+    }
+}
+
+// everything that can go wrong while loading the config
+// a semantically invalid config does not stop at the first problem, instead
+// every problem found is collected so that all of them can be fixed at once
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Parse(toml::de::Error),
+    Invalid(Vec<ValidationError>),
+}
+
+// one problem in the config, location is the dotted toml path of the
+// offending key, e.g. "input.looping.command"
+
+#[derive(Debug)]
+pub struct ValidationError {
+    pub location: String,
+    pub message: String,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "reading the config failed: {}", e),
+            ConfigError::Parse(e) => write!(f, "parsing the config failed: {}", e),
+            ConfigError::Invalid(errors) => {
+                write!(f, "the config contains {} error(s):", errors.len())?;
+                for error in errors {
+                    write!(f, "\n  {}", error)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<io::Error> for ConfigError {
+    fn from(e: io::Error) -> Self {
+        ConfigError::Io(e)
+    }
+}
+
+impl From<toml::de::Error> for ConfigError {
+    fn from(e: toml::de::Error) -> Self {
+        ConfigError::Parse(e)
+    }
 }
 
 // a plugin has to start another process (the plugin - wow, who would have thought)
 // the rust way of representing another not started process is the Command, so it's
 // logical to implement a From
-// in this case it's a TryFrom because the toml input is not validated enough, which means
-// one could pass an empty array [] as command, which of course, won't work
+// it's a TryFrom anyway: PluginConfig::validate already refuses an empty command (and one
+// that can't be found or executed), but a PluginConfig that never went through it could still
+// have an empty array [] as command, so that's an error here instead of a panic on command[0]
+// (Plugin::new turns it into an io::Error like any other failed start)
 
 impl TryFrom<&PluginConfig> for Command {
     type Error = &'static str;
 
     fn try_from(pc: &PluginConfig) -> Result<Self, Self::Error> {
-        // what's said above is validated exactly here...
-        if pc.command.len() < 1 {
+        // the only check validate does that spawning can't do without
+        if pc.command.is_empty() {
             Err("a plugin command must contain a path to an executable")
         } else {
             // select the executable
             let mut command = Command::new(&pc.command[0]);
 
             // add the arguments
-            for i in 1..pc.command.len() {
-                command.arg(&pc.command[i]);
-            }
+            command.args(&pc.command[1..]);
 
             // add additional environment variables (if there are some)
             match &pc.environment {
@@ -68,8 +472,570 @@ impl TryFrom<&PluginConfig> for Command {
     }
 }
 
+impl Config {
+    pub fn shutdown_grace_period(&self) -> Duration {
+        Duration::from_millis(self.shutdown_grace_period_ms)
+    }
+
+    // the configured runtime_dir, or $XDG_RUNTIME_DIR/palleon, or <tmp>/palleon-<uid>
+    pub fn runtime_dir(&self) -> PathBuf {
+        if let Some(dir) = &self.runtime_dir {
+            return dir.clone();
+        }
+
+        match env::var_os("XDG_RUNTIME_DIR") {
+            Some(dir) => Path::new(&dir).join("palleon"),
+            None => env::temp_dir().join(format!("palleon-{}", unsafe { libc::getuid() })),
+        }
+    }
+
+    // where the shared memory of the plugins is created: /dev/shm if there is one, the
+    // runtime_dir otherwise
+    pub fn shm_dir(&self) -> PathBuf {
+        let dev_shm = Path::new("/dev/shm");
+        if dev_shm.is_dir() {
+            dev_shm.to_path_buf()
+        } else {
+            self.runtime_dir()
+        }
+    }
+
+    // where the core listens for the plugin or connects to it, port is the one of plugin_ports()
+    pub fn plugin_address(&self, kind: PluginKind, name: &str, plugin: &PluginConfig, port: i32) -> Address {
+        match (plugin.mode, plugin.transport) {
+            (PluginMode::Connect, PluginTransport::Tcp) => Address::Tcp(plugin.connect_addr.clone().unwrap_or_default()),
+            (PluginMode::Connect, PluginTransport::Unix) => Address::Unix(plugin.connect_addr.clone().unwrap_or_default().into()),
+            (_, PluginTransport::Tcp) => Address::Tcp(format!("{}:{}", self.bind_addr, port)),
+            (_, PluginTransport::Unix) => Address::Unix(self.runtime_dir().join(format!("{}-{}.sock", kind, name))),
+        }
+    }
+
+    // all plugins in config order, input plugins first
+    pub fn plugins(&self) -> impl Iterator<Item=(PluginKind, &String, &PluginConfig)> {
+        let input = self.input_plugins.iter().map(|(name, plugin)| (PluginKind::Input, name, plugin));
+        let data = self.data_plugins.iter().map(|(name, plugin)| (PluginKind::Data, name, plugin));
+
+        input.chain(data)
+    }
+
+    // every plugin together with the port it is going to listen on
+    // a port of 0 means that the os chooses one when binding
+    // plugins that don't listen on a port (connect mode or unix transport) get 0 and don't use up a port of the range
+    // after a reload the plugins that were running keep their port of the range, new ones get the
+    // next port of the range that nobody uses
+    pub fn plugin_ports(&self) -> Vec<(PluginKind, &String, &PluginConfig, i32)> {
+        let mut next_port = self.bind_port_range_start.unwrap_or_default();
+
+        // without kept ports this is empty, so the n-th plugin gets the n-th port of the range
+        let mut used_ports = HashSet::new();
+        if !self.kept_ports.is_empty() {
+            used_ports.insert(self.bind_port_gui);
+            used_ports.extend(self.plugins().filter(|(_, _, plugin)| plugin.uses_port()).filter_map(|(_, _, plugin)| plugin.port));
+            used_ports.extend(self.kept_ports.values());
+        }
+
+        self.plugins()
+            .map(|(kind, name, plugin)| {
+                let port = match (plugin.port, self.port_allocation) {
+                    _ if !plugin.uses_port() => 0,
+                    (Some(port), _) => port,
+                    (None, PortAllocation::Ephemeral) => 0,
+                    (None, PortAllocation::Range) => match self.kept_ports.get(&(kind, name.clone())) {
+                        Some(port) => *port,
+                        None => {
+                            while used_ports.contains(&next_port) {
+                                next_port += 1;
+                            }
+                            next_port += 1;
+                            next_port - 1
+                        }
+                    },
+                };
+                (kind, name, plugin, port)
+            })
+            .collect()
+    }
+
+    // lets the plugins of this (reloaded) config keep the ports of the range they got in the
+    // running config, so adding or removing a plugin doesn't move (and restart) the others
+    // a port that is now taken by an explicit port or the gui is given up, and so is everything
+    // if the range itself changed
+    pub fn keep_ports(&mut self, running: &Config) {
+        self.kept_ports.clear();
+        if self.port_allocation != PortAllocation::Range || running.port_allocation != PortAllocation::Range || self.bind_port_range_start != running.bind_port_range_start {
+            return;
+        }
+
+        let mut claimed: HashSet<i32> = self.plugins().filter(|(_, _, plugin)| plugin.uses_port()).filter_map(|(_, _, plugin)| plugin.port).collect();
+        claimed.insert(self.bind_port_gui);
+        let in_range = |plugin: &PluginConfig| plugin.uses_port() && plugin.port.is_none();
+
+        let kept_ports = running.plugin_ports().into_iter()
+            .filter(|(kind, name, plugin, port)| {
+                let plugins = match kind {
+                    PluginKind::Input => &self.input_plugins,
+                    PluginKind::Data => &self.data_plugins,
+                };
+                in_range(plugin) && !claimed.contains(port) && plugins.get(*name).is_some_and(in_range)
+            })
+            .map(|(kind, name, _, port)| ((kind, name.clone()), port))
+            .collect();
+        self.kept_ports = kept_ports;
+    }
+
+    // check everything serde can't check on its own
+    // returns all problems found, an empty vec means the config is fine
+    pub fn validate(&self) -> Vec<ValidationError> {
+        let mut errors = vec![];
+
+        let mut error = |location: String, message: String| errors.push(ValidationError { location, message });
+
+        if u16::try_from(self.bind_port_gui).is_err() {
+            error("bind_port_gui".to_string(), format!("port {} is outside of the valid range 0-65535", self.bind_port_gui));
+        }
+
+        if self.gui_token.as_ref().is_some_and(|token| token.is_empty()) {
+            error("gui_token".to_string(), "must not be empty".to_string());
+        }
+
+        match self.bind_port_range_start {
+            Some(port) if u16::try_from(port).is_err() => {
+                error("bind_port_range_start".to_string(), format!("port {} is outside of the valid range 0-65535", port));
+            }
+            None if self.port_allocation == PortAllocation::Range && self.plugins().any(|(_, _, plugin)| plugin.uses_port() && plugin.port.is_none()) => {
+                error("bind_port_range_start".to_string(), "is required when plugins without a port use the port_allocation \"range\"".to_string());
+            }
+            _ => {}
+        }
+
+        // every port may be used only once, ephemeral ports (0) are unique by definition
+        let mut used_ports = HashMap::from([(self.bind_port_gui, "bind_port_gui".to_string())]);
+
+        for (kind, name, plugin, port) in self.plugin_ports() {
+            let location = match plugin.port {
+                Some(_) => format!("{}.{}.port", kind, name),
+                None => format!("{}.{}", kind, name),
+            };
+
+            if u16::try_from(port).is_err() {
+                error(location, format!("port {} is outside of the valid range 0-65535", port));
+            } else if port != 0 {
+                if let Some(other) = used_ports.get(&port) {
+                    error(location, format!("port {} is already used by {}", port, other));
+                } else {
+                    used_ports.insert(port, location);
+                }
+            }
+        }
+
+        for name in self.data_plugins.keys() {
+            if self.input_plugins.contains_key(name) {
+                error(format!("data.{}", name), format!("the name {:?} is already used by an input plugin", name));
+            }
+        }
+
+        // the path of a unix socket is limited to the size of sockaddr_un.sun_path (including the nul)
+        for (kind, name, plugin, port) in self.plugin_ports() {
+            if plugin.transport != PluginTransport::Unix || plugin.mode == PluginMode::Connect {
+                continue;
+            }
+            if name.contains('/') {
+                error(format!("{}.{}", kind, name), "the name of a plugin with the unix transport must not contain a \"/\"".to_string());
+            } else if let Address::Unix(path) = self.plugin_address(kind, name, plugin, port) {
+                if path.as_os_str().len() >= 108 {
+                    error(format!("{}.{}", kind, name), format!("the socket path {:?} is too long, use a shorter runtime_dir", path));
+                }
+            }
+        }
+
+        for (section, plugins) in [("input", &self.input_plugins), ("data", &self.data_plugins)] {
+            for (name, plugin) in plugins {
+                for (key, message) in plugin.validate() {
+                    error(format!("{}.{}.{}", section, name, key), message);
+                }
+            }
+        }
+
+        // every frame a pushing plugin has in flight needs a slot of its own (see shm)
+        for (name, plugin) in &self.input_plugins {
+            if let Some(shm) = plugin.shm.filter(|shm| shm.slots > 0 && shm.slots < plugin.push_credits) {
+                error(format!("input.{}.shm.slots", name), format!("must not be less than push_credits ({}), but is {}", plugin.push_credits, shm.slots));
+            }
+        }
+
+        errors
+    }
+}
+
+impl PluginConfig {
+    // does the core listen on a tcp port for this plugin?
+    pub fn uses_port(&self) -> bool {
+        self.mode != PluginMode::Connect && self.transport == PluginTransport::Tcp
+    }
+
+    pub fn result_timeout(&self) -> Duration {
+        Duration::from_millis(self.result_timeout_ms)
+    }
+
+    // returns (key, message) pairs, the caller knows where the plugin is located
+    fn validate(&self) -> Vec<(&'static str, String)> {
+        let mut errors = vec![];
+
+        match (self.mode, &self.connect_addr) {
+            (PluginMode::Connect, None) => errors.push(("connect_addr", "is required in the connect mode".to_string())),
+            (PluginMode::Connect, Some(_)) => {}
+            (_, Some(_)) => errors.push(("connect_addr", "is only used in the connect mode".to_string())),
+            (_, None) => {}
+        }
+
+        if self.token.as_ref().is_some_and(|token| token.is_empty()) {
+            errors.push(("token", "must not be empty".to_string()));
+        }
+
+        if self.legacy {
+            // a plugin without a hello can't agree on any of these
+            for (key, used) in [("token", self.token.is_some()), ("compression", !self.compression.is_empty()), ("shm", self.shm.is_some())] {
+                if used {
+                    errors.push((key, "can't be used by a legacy plugin".to_string()));
+                }
+            }
+        }
+
+        if self.mode == PluginMode::Connect && self.port.is_some() {
+            errors.push(("port", "is not used in the connect mode, use connect_addr".to_string()));
+        } else if self.transport == PluginTransport::Unix && self.port.is_some() {
+            errors.push(("port", "is not used with the unix transport".to_string()));
+        }
+
+        for (key, limit) in [("limits.hello_bytes", self.limits.hello_bytes), ("limits.image_bytes", self.limits.image_bytes), ("limits.data_bytes", self.limits.data_bytes)] {
+            if limit == 0 {
+                errors.push((key, "must be greater than 0".to_string()));
+            }
+        }
+
+        if let Some(shm) = &self.shm {
+            for (key, value) in [("shm.slots", shm.slots), ("shm.slot_bytes", shm.slot_bytes)] {
+                if value == 0 {
+                    errors.push((key, "must be greater than 0".to_string()));
+                }
+            }
+            if shm.slot_bytes > self.limits.image_bytes {
+                errors.push(("shm.slot_bytes", format!("must not be greater than limits.image_bytes ({})", self.limits.image_bytes)));
+            }
+        }
+
+        if self.restart.initial_backoff_ms > self.restart.max_backoff_ms {
+            errors.push(("restart.initial_backoff_ms", format!("must not be greater than max_backoff_ms ({})", self.restart.max_backoff_ms)));
+        }
+
+        if self.idle.initial_backoff_ms == 0 {
+            errors.push(("idle.initial_backoff_ms", "must be greater than 0".to_string()));
+        }
+        if self.idle.initial_backoff_ms > self.idle.max_backoff_ms {
+            errors.push(("idle.initial_backoff_ms", format!("must not be greater than max_backoff_ms ({})", self.idle.max_backoff_ms)));
+        }
+
+        if self.timestamps.drift_window_secs == 0 {
+            errors.push(("timestamps.drift_window_secs", "must be greater than 0".to_string()));
+        }
+
+        if self.result_timeout_ms == 0 {
+            errors.push(("result_timeout_ms", "must be greater than 0".to_string()));
+        }
+
+        if self.mode != PluginMode::Spawn {
+            // there is no process, so there is nothing to run and no output to capture
+            for (key, used) in [("command", !self.command.is_empty()), ("working_directory", !self.working_directory.is_empty()), ("log_file", self.log_file.is_some())] {
+                if used {
+                    errors.push((key, "is only used in the spawn mode".to_string()));
+                }
+            }
+            return errors;
+        }
+
+        if let Some(log_file) = &self.log_file {
+            if log_file.max_bytes == 0 {
+                errors.push(("log_file.max_bytes", "must be greater than 0".to_string()));
+            }
+            if log_file.path.parent().is_some_and(|dir| !dir.as_os_str().is_empty() && !dir.is_dir()) {
+                errors.push(("log_file.path", format!("the directory of {:?} does not exist", log_file.path)));
+            }
+        }
+
+        let working_directory = Path::new(&self.working_directory);
+        if !working_directory.is_dir() {
+            errors.push(("working_directory", format!("{:?} is not an existing directory", self.working_directory)));
+        }
+
+        match self.command.first() {
+            None => errors.push(("command", "a plugin command must contain a path to an executable".to_string())),
+            Some(program) => {
+                match resolve_executable(program, working_directory) {
+                    None => errors.push(("command", format!("{:?} could not be found", program))),
+                    Some(path) if !is_executable(&path) => errors.push(("command", format!("{:?} is not executable", path))),
+                    Some(_) => {}
+                }
+            }
+        }
+
+        errors
+    }
+}
+
+// find the file that will be executed when running program, relative paths
+// are interpreted relative to the working directory of the plugin and plain
+// names are looked up in PATH - just like spawning the command would do
+
+fn resolve_executable(program: &str, working_directory: &Path) -> Option<PathBuf> {
+    let program = Path::new(program);
+
+    if program.components().count() > 1 {
+        let path = working_directory.join(program);
+        return if path.exists() { Some(path) } else { None };
+    }
+
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(program))
+        .find(|path| path.is_file())
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::metadata(path).map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0).unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
 // wrap all loading into one simple function
 
-pub fn load(p: &Path) -> Result<Config, io::Error> {
-    Ok(toml::from_str(fs::read_to_string(p)?.as_str()).expect("parsing toml config failed"))
-}
\ No newline at end of file
+pub fn load(p: &Path, overrides: &Overrides) -> Result<Config, ConfigError> {
+    let mut cfg: Config = toml::from_str(fs::read_to_string(p)?.as_str())?;
+
+    if let Some(bind_addr) = &overrides.bind_addr {
+        cfg.bind_addr = bind_addr.clone();
+    }
+    if let Some(bind_port_gui) = overrides.bind_port_gui {
+        cfg.bind_port_gui = bind_port_gui;
+    }
+
+    let errors = cfg.validate();
+    if !errors.is_empty() {
+        return Err(ConfigError::Invalid(errors));
+    }
+
+    Ok(cfg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a config without problems: the gui on 9000 and the plugins listening from 9100 on
+    fn config() -> Config {
+        Config {
+            bind_addr: "127.0.0.1".to_string(),
+            port_allocation: PortAllocation::Range,
+            bind_port_range_start: Some(9100),
+            bind_port_gui: 9000,
+            gui_token: None,
+            gui_compression: vec![],
+            runtime_dir: Some(PathBuf::from("/tmp/palleon-config-test")),
+            shutdown_grace_period_ms: default_shutdown_grace_period_ms(),
+            data_plugins: IndexMap::new(),
+            input_plugins: IndexMap::new(),
+            kept_ports: HashMap::new(),
+        }
+    }
+
+    // the listen mode, so nothing has to exist on disk
+    fn plugin() -> PluginConfig {
+        PluginConfig { mode: PluginMode::Listen, ..PluginConfig::default() }
+    }
+
+    // the locations of all problems found
+    fn problems(cfg: &Config) -> Vec<String> {
+        cfg.validate().into_iter().map(|e| e.location).collect()
+    }
+
+    #[test]
+    fn a_valid_config_has_no_problems() {
+        let mut cfg = config();
+        cfg.input_plugins.insert("camera".to_string(), plugin());
+        cfg.data_plugins.insert("activity".to_string(), plugin());
+
+        assert_eq!(problems(&cfg), Vec::<String>::new());
+    }
+
+    #[test]
+    fn every_problem_is_reported() {
+        let mut cfg = config();
+        cfg.bind_port_gui = 70000;
+        cfg.input_plugins.insert("camera".to_string(), PluginConfig { token: Some(String::new()), ..plugin() });
+        cfg.data_plugins.insert("camera".to_string(), plugin());
+
+        assert_eq!(problems(&cfg), ["bind_port_gui", "data.camera", "input.camera.token"]);
+    }
+
+    #[test]
+    fn ports_must_be_unique() {
+        let mut cfg = config();
+        cfg.input_plugins.insert("camera".to_string(), PluginConfig { port: Some(9000), ..plugin() });
+        // gets 9100 from the range
+        cfg.data_plugins.insert("a".to_string(), plugin());
+        cfg.data_plugins.insert("b".to_string(), PluginConfig { port: Some(9100), ..plugin() });
+
+        assert_eq!(problems(&cfg), ["input.camera.port", "data.b.port"]);
+    }
+
+    #[test]
+    fn ephemeral_ports_and_unix_sockets_do_not_collide() {
+        let mut cfg = config();
+        cfg.port_allocation = PortAllocation::Ephemeral;
+        cfg.bind_port_range_start = None;
+        cfg.input_plugins.insert("a".to_string(), plugin());
+        cfg.input_plugins.insert("b".to_string(), plugin());
+        cfg.data_plugins.insert("c".to_string(), PluginConfig { transport: PluginTransport::Unix, ..plugin() });
+
+        assert_eq!(problems(&cfg), Vec::<String>::new());
+        assert!(cfg.plugin_ports().iter().all(|(_, _, _, port)| *port == 0));
+    }
+
+    #[test]
+    fn the_port_range_is_required_for_plugins_without_a_port() {
+        let mut cfg = config();
+        cfg.bind_port_range_start = None;
+        cfg.input_plugins.insert("camera".to_string(), plugin());
+
+        assert_eq!(problems(&cfg), ["bind_port_range_start"]);
+    }
+
+    #[test]
+    fn connect_addr_belongs_to_the_connect_mode() {
+        let mut cfg = config();
+        cfg.input_plugins.insert("missing".to_string(), PluginConfig { mode: PluginMode::Connect, ..plugin() });
+        cfg.input_plugins.insert("unused".to_string(), PluginConfig { connect_addr: Some("10.0.0.2:9000".to_string()), ..plugin() });
+        cfg.input_plugins.insert("port".to_string(), PluginConfig { mode: PluginMode::Connect, connect_addr: Some("10.0.0.2:9000".to_string()), port: Some(9001), ..plugin() });
+
+        assert_eq!(problems(&cfg), ["input.missing.connect_addr", "input.unused.connect_addr", "input.port.port"]);
+    }
+
+    #[test]
+    fn legacy_plugins_negotiate_nothing() {
+        let mut cfg = config();
+        let legacy = || PluginConfig { legacy: true, ..plugin() };
+        cfg.input_plugins.insert("plain".to_string(), legacy());
+        cfg.data_plugins.insert("token".to_string(), PluginConfig { token: Some("secret".to_string()), ..legacy() });
+        cfg.data_plugins.insert("compressed".to_string(), PluginConfig { compression: vec![Compression::Zstd], ..legacy() });
+        cfg.data_plugins.insert("shared".to_string(), PluginConfig { shm: Some(ShmConfig { slots: 2, slot_bytes: 1024 }), ..legacy() });
+
+        assert_eq!(problems(&cfg), ["data.token.token", "data.compressed.compression", "data.shared.shm"]);
+    }
+
+    #[test]
+    fn a_pushing_plugin_needs_a_slot_for_every_credit() {
+        let mut cfg = config();
+        let shm = |slots| Some(ShmConfig { slots, slot_bytes: 1024 });
+        cfg.input_plugins.insert("enough".to_string(), PluginConfig { push_credits: 4, shm: shm(4), ..plugin() });
+        cfg.input_plugins.insert("polled".to_string(), PluginConfig { push_credits: 0, shm: shm(1), ..plugin() });
+        cfg.input_plugins.insert("short".to_string(), PluginConfig { push_credits: 4, shm: shm(3), ..plugin() });
+        // data plugins are never in the push mode
+        cfg.data_plugins.insert("data".to_string(), PluginConfig { push_credits: 4, shm: shm(1), ..plugin() });
+
+        assert_eq!(problems(&cfg), ["input.short.shm.slots"]);
+    }
+
+    #[test]
+    fn unix_sockets_need_a_usable_path() {
+        let mut cfg = config();
+        let unix = || PluginConfig { transport: PluginTransport::Unix, ..plugin() };
+        cfg.input_plugins.insert("a/b".to_string(), unix());
+        cfg.input_plugins.insert("x".repeat(100), unix());
+        cfg.input_plugins.insert("port".to_string(), PluginConfig { port: Some(9001), ..unix() });
+
+        assert_eq!(problems(&cfg), ["input.a/b", format!("input.{}", "x".repeat(100)).as_str(), "input.port.port"]);
+    }
+
+    #[test]
+    fn shm_needs_slots_that_fit_the_frame_limit() {
+        let mut cfg = config();
+        cfg.input_plugins.insert("empty".to_string(), PluginConfig { shm: Some(ShmConfig { slots: 0, slot_bytes: 0 }), ..plugin() });
+        let limits = FrameLimits { image_bytes: 1024, ..FrameLimits::default() };
+        cfg.input_plugins.insert("big".to_string(), PluginConfig { shm: Some(ShmConfig { slots: 4, slot_bytes: 2048 }), limits, ..plugin() });
+
+        assert_eq!(problems(&cfg), ["input.empty.shm.slots", "input.empty.shm.slot_bytes", "input.big.shm.slot_bytes"]);
+    }
+
+    #[test]
+    fn frame_limits_must_not_be_0() {
+        let mut cfg = config();
+        cfg.data_plugins.insert("a".to_string(), PluginConfig { limits: FrameLimits { hello_bytes: 0, image_bytes: 0, data_bytes: 0 }, ..plugin() });
+
+        assert_eq!(problems(&cfg), ["data.a.limits.hello_bytes", "data.a.limits.image_bytes", "data.a.limits.data_bytes"]);
+    }
+
+    #[test]
+    fn backoffs_must_not_start_above_their_maximum() {
+        let mut cfg = config();
+        let restart = RestartConfig { initial_backoff_ms: 2000, max_backoff_ms: 1000, ..RestartConfig::default() };
+        let idle = IdleConfig { initial_backoff_ms: 2000, max_backoff_ms: 1000, ..IdleConfig::default() };
+        cfg.input_plugins.insert("a".to_string(), PluginConfig { restart, idle, ..plugin() });
+        cfg.input_plugins.insert("b".to_string(), PluginConfig { idle: IdleConfig { initial_backoff_ms: 0, ..IdleConfig::default() }, ..plugin() });
+
+        assert_eq!(problems(&cfg), ["input.a.restart.initial_backoff_ms", "input.a.idle.initial_backoff_ms", "input.b.idle.initial_backoff_ms"]);
+    }
+
+    #[test]
+    fn the_drift_window_must_not_be_0() {
+        let mut cfg = config();
+        cfg.input_plugins.insert("camera".to_string(), PluginConfig { timestamps: TimestampConfig { drift_window_secs: 0, ..TimestampConfig::default() }, ..plugin() });
+
+        assert_eq!(problems(&cfg), ["input.camera.timestamps.drift_window_secs"]);
+    }
+
+    #[test]
+    fn the_result_timeout_must_not_be_0() {
+        let mut cfg = config();
+        cfg.data_plugins.insert("a".to_string(), PluginConfig { result_timeout_ms: 0, ..plugin() });
+
+        assert_eq!(problems(&cfg), ["data.a.result_timeout_ms"]);
+    }
+
+    #[test]
+    fn the_spawn_mode_needs_an_existing_command() {
+        let mut cfg = config();
+        cfg.data_plugins.insert("missing".to_string(), PluginConfig { working_directory: "/".to_string(), ..PluginConfig::default() });
+        cfg.data_plugins.insert("unknown".to_string(), PluginConfig { working_directory: "/".to_string(), command: vec!["palleon-no-such-program".to_string()], ..PluginConfig::default() });
+        cfg.data_plugins.insert("sh".to_string(), PluginConfig { working_directory: "/".to_string(), command: vec!["sh".to_string()], ..PluginConfig::default() });
+        // there is no process to run in the listen mode
+        cfg.data_plugins.insert("listen".to_string(), PluginConfig { command: vec!["sh".to_string()], ..plugin() });
+
+        assert_eq!(problems(&cfg), ["data.missing.command", "data.unknown.command", "data.listen.command"]);
+    }
+
+    #[test]
+    fn kept_ports_give_way_to_explicit_ports_and_a_new_range() {
+        let mut running = config();
+        running.input_plugins.insert("a".to_string(), plugin());
+        running.input_plugins.insert("b".to_string(), plugin());
+
+        // b's port 9101 is now the explicit port of c, so b gets the next free one
+        let mut cfg = config();
+        cfg.input_plugins.insert("a".to_string(), plugin());
+        cfg.input_plugins.insert("b".to_string(), plugin());
+        cfg.input_plugins.insert("c".to_string(), PluginConfig { port: Some(9101), ..plugin() });
+        cfg.keep_ports(&running);
+        let ports: Vec<_> = cfg.plugin_ports().into_iter().map(|(_, _, _, port)| port).collect();
+        assert_eq!(ports, [9100, 9102, 9101]);
+        assert_eq!(problems(&cfg), Vec::<String>::new());
+
+        cfg.bind_port_range_start = Some(9200);
+        cfg.keep_ports(&running);
+        let ports: Vec<_> = cfg.plugin_ports().into_iter().map(|(_, _, _, port)| port).collect();
+        assert_eq!(ports, [9200, 9201, 9101]);
+    }
+}
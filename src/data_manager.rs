@@ -9,6 +9,7 @@ use bson::Bson;
 // amount of data can easily be stored in RAM
 // for every data plugin it stores at max the last 10_000 values
 
+#[derive(Default)]
 pub struct DataManager {
     values: HashMap<String, HashMap<String, Vec<(SystemTime, Bson)>>>,
 }
@@ -17,15 +18,13 @@ const MAX_VALUES: usize = 10000;
 
 impl DataManager {
     pub fn new() -> Self {
-        DataManager {
-            values: HashMap::new(),
-        }
+        DataManager::default()
     }
 
 
     pub fn add(&mut self, plugin_name: String, source_name: String, time: SystemTime, value: Bson) {
         // get corresponding data vector
-        let vec = self.values.entry(plugin_name).or_insert(HashMap::new()).entry(source_name).or_insert(vec![]);
+        let vec = self.values.entry(plugin_name).or_default().entry(source_name).or_default();
 
         // add data
         vec.push((time, value));
@@ -38,19 +37,16 @@ impl DataManager {
 
     pub fn get_last(&self, plugin_name: String, source_name: &String, x: usize) -> Option<Vec<(SystemTime, Bson)>> {
         // is there SOMETHING stored in the values hash map?
-        let value_name = self.values.get(&plugin_name);
-        if value_name.is_none() { return None; }
-        let value_name = value_name.unwrap().get(source_name);
-        if value_name.is_none() { return None; }
+        let value_name = self.values.get(&plugin_name)?.get(source_name)?;
 
         // ..yes, so copy the last x values and return them
         let mut collected = vec![];
-        for datum in value_name.unwrap().iter().rev() {
+        for datum in value_name.iter().rev() {
             if collected.len() >= x {
                 break;
             }
             collected.push((datum.0, datum.1.clone()));
         }
-        return Some(collected);
+        Some(collected)
     }
 }
@@ -1,29 +1,41 @@
+use std::io;
 use std::sync::{Arc, Mutex};
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
+use async_trait::async_trait;
 use bson::{Bson, Document};
-use crossbeam_channel::{bounded, Receiver, Sender};
-use log::info;
+use flume::{bounded, Receiver, Sender};
+use log::{debug, info};
 
-use crate::{Config, DataManager, Plugin};
+use crate::{Config, DataManager};
+use crate::config::{PluginConfig, PluginKind};
 use crate::image::Image;
-use crate::plugin::Handler;
-use crate::wrapped_stream::WrappedStream;
+use crate::plugin::{Context, Handler, HandlerError, Plugin, Session, StopOutcome};
+use crate::protocol::{self, Codec, ImageData, Message};
+use crate::wrapped_stream::{self, MessageType, ProtocolError};
+
+// a plugin (of protocol version 2 or later) that got no image for this long is pinged,
+// so a plugin that hangs or whose connection silently broke is noticed without an image
+const PING_INTERVAL: Duration = Duration::from_secs(10);
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+// one value returned by a data plugin: (data plugin, input source, timestamp, value)
+pub type PluginData = (String, String, SystemTime, Bson);
 
 #[derive(Clone)]
 pub struct DataPluginHandler {
     image_rx: Receiver<Image>,
-    data_tx: Sender<(String, String, SystemTime, Bson)>,
+    data_tx: Sender<PluginData>,
     data_mgr: Arc<Mutex<DataManager>>,
+    result_timeout: Duration,
 }
 
 impl DataPluginHandler {
-    pub fn collect_plugin_data(&self, source: &String, plugin_dependencies: &Document) -> Document {
+    pub fn collect_plugin_data(&self, source: &String, plugin_dependencies: &[(String, usize)]) -> Document {
         let mut requested_plugin_data = Document::new();
 
         for (plugin_name, nr_history) in plugin_dependencies {
-            let nr_history = nr_history.as_i32().expect("received invalid nr for the history for dependencies") as usize;
-            if let Some(src_data) = self.data_mgr.lock().unwrap().get_last(plugin_name.clone(), source, nr_history) {
+            if let Some(src_data) = self.data_mgr.lock().unwrap().get_last(plugin_name.clone(), source, *nr_history) {
                 let mut data = vec![];
 
                 for (time, bson) in src_data {
@@ -41,62 +53,106 @@ impl DataPluginHandler {
     }
 }
 
+#[async_trait]
 impl Handler for DataPluginHandler {
-    fn handle(&self, data_plugin_name: &String, mut stream: WrappedStream) {
-        // TODO clean this mess up using bson
-        info!("received connection for plugin {:?}", data_plugin_name);
+    async fn handle(&self, data_plugin_name: &str, mut codec: Codec, session: Session) -> Result<(), HandlerError> {
+        info!("received connection for plugin {:?} (protocol version {}, {:?})", data_plugin_name, session.protocol_version, session.accepted);
+        if let Some(schema) = &session.capabilities.output_schema {
+            debug!("output schema of plugin {:?}: {}", data_plugin_name, schema);
+        }
 
-        // received dependencies
-        let plugin_init = stream.recv_bson();
-        let wants_image = plugin_init.get_bool("image").expect("data from plugin was invalid");
-        let plugin_dependencies = plugin_init.get_document("dependencies").expect("expected 'dependencies' key in document");
+        // received dependencies, part of the hello
+        let plugin_init = &session.hello;
+        let wants_image = plugin_init.get_bool("image")
+            .map_err(|_| ProtocolError::UnexpectedMessage("expected a bool 'image'".to_string())).context("reading the init document")?;
+        let plugin_dependencies = dependencies(plugin_init).context("reading the init document")?;
 
         loop {
             // exc: once had the case that the data plugin was not entering its loop such that
             //      there was no exception from the plugin and it seemed like "the connection broke"
             //      without a reason but in reality it falsely closed normally
-            let image = self.image_rx.recv().expect("somehow this channel collapsed.");
+            // the channel is only closed if the plugin is being stopped, ask it to shut down
+            let image = match tokio::time::timeout(PING_INTERVAL, self.image_rx.recv_async()).await {
+                Ok(Ok(image)) => image,
+                Ok(Err(_)) => {
+                    let _ = codec.send(&Message::Shutdown).await;
+                    return Ok(());
+                }
+                Err(_) => {
+                    if !codec.is_legacy() {
+                        codec.ping(PING_TIMEOUT).await.context("pinging the plugin")?;
+                    }
+                    continue;
+                }
+            };
 
             // send data from other plugins
-            let requested_plugin_data = self.collect_plugin_data(&image.input_source, plugin_dependencies);
-            stream.send_bson(&requested_plugin_data).expect("could not send collected data");
+            let requested_plugin_data = self.collect_plugin_data(&image.input_source, &plugin_dependencies);
+            codec.send(&Message::DependencyData(requested_plugin_data)).await.context("sending the data of the dependencies")?;
 
             // image tx
-            let timestamp = image.timestamp.clone();
+            let timestamp = image.timestamp;
             let input_source_name = image.input_source.clone();
 
-            let mut doc = Document::from(image);
-            if !wants_image {
-                doc.remove("data").expect("could not remove data key from document");
-            }
-            stream.send_bson(&doc).expect("could not send image data");
+            let data = if wants_image { ImageData::Inline } else { ImageData::Omitted };
+            codec.send(&Message::Image { image, data }).await.context("sending an image")?;
 
-            // data rx
-            let data = stream.recv_bson();
+            // data rx, a plugin that takes too long is disconnected, the main loop doesn't wait
+            // for it any longer either
+            let data = match wrapped_stream::timeout(self.result_timeout, codec.recv(MessageType::Data)).await.context("receiving the data")? {
+                Message::Result(data) => data,
+                message => return Err(protocol::unexpected(&message, "a result")).context("receiving the data"),
+            };
 
             // data tx
-            self.data_tx.send((data_plugin_name.clone(), input_source_name, timestamp, Bson::from(data))).expect("TODO: panic message");
+            if self.data_tx.send_async((data_plugin_name.to_string(), input_source_name, timestamp, Bson::from(data))).await.is_err() {
+                // the plugin is being stopped, ask it to shut down
+                let _ = codec.send(&Message::Shutdown).await;
+                return Ok(());
+            }
         };
     }
 }
 
+// {"<plugin name>": <nr of values>, ..} -> [(plugin name, nr of values), ..]
+fn dependencies(plugin_init: &Document) -> Result<Vec<(String, usize)>, ProtocolError> {
+    let invalid = |message: &str| ProtocolError::UnexpectedMessage(message.to_string());
+
+    plugin_init.get_document("dependencies")
+        .map_err(|_| invalid("expected a document 'dependencies'"))?
+        .iter()
+        .map(|(plugin_name, nr_history)| match nr_history.as_i32().map(usize::try_from) {
+            Some(Ok(nr_history)) => Ok((plugin_name.clone(), nr_history)),
+            _ => Err(invalid("expected a positive number of values for every dependency")),
+        })
+        .collect()
+}
 
-pub fn start(cfg: &Config, data_mgr: &Arc<Mutex<DataManager>>) -> (Vec<Plugin>, Vec<Sender<Image>>, Vec<Receiver<(String, String, SystemTime, Bson)>>) {
-    let mut image_txs = vec![];
-    let mut data_rxs = vec![];
-    let mut plugins = vec![];
 
-    for (i, (name, plugin)) in (&cfg.data_plugins).into_iter().enumerate() {
-        let (image_tx, image_rx): (Sender<Image>, Receiver<Image>) = bounded(0);
-        let (data_tx, data_rx): (Sender<(String, String, SystemTime, Bson)>, Receiver<(String, String, SystemTime, Bson)>) = bounded(0);
+// a running data plugin and the channels to send it images and receive its data
 
-        let bind_port = cfg.bind_port_range_start + cfg.input_plugins.len() as i32 + i as i32;
-        let plugin = Plugin::new(name, &cfg.bind_addr, bind_port, plugin, Box::new(DataPluginHandler { image_rx, data_tx, data_mgr: data_mgr.clone() }));
+pub struct DataPlugin {
+    pub plugin: Plugin,
+    pub image_tx: Sender<Image>,
+    pub data_rx: Receiver<PluginData>,
+    pub result_timeout: Duration,
+}
 
-        plugins.push(plugin);
-        image_txs.push(image_tx);
-        data_rxs.push(data_rx);
+impl DataPlugin {
+    pub fn stop(self, grace_period: Duration) -> StopOutcome {
+        // drop the channels first, so the handler can't block on them
+        drop(self.image_tx);
+        drop(self.data_rx);
+        self.plugin.stop(grace_period)
     }
+}
+
+pub fn start_plugin(cfg: &Config, data_mgr: &Arc<Mutex<DataManager>>, name: &String, plugin: &PluginConfig, bind_port: i32) -> io::Result<DataPlugin> {
+    let (image_tx, image_rx): (Sender<Image>, Receiver<Image>) = bounded(0);
+    let (data_tx, data_rx): (Sender<PluginData>, Receiver<PluginData>) = bounded(0);
+
+    let result_timeout = plugin.result_timeout();
+    let plugin = Plugin::new(cfg, PluginKind::Data, name, plugin, bind_port, Box::new(DataPluginHandler { image_rx, data_tx, data_mgr: data_mgr.clone(), result_timeout }))?;
 
-    (plugins, image_txs, data_rxs)
+    Ok(DataPlugin { plugin, image_tx, data_rx, result_timeout })
 }
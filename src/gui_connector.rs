@@ -1,87 +1,129 @@
-use std::{io, thread};
-use std::io::Write;
-use std::net::{TcpListener, TcpStream};
+use std::io;
+use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::time::{Duration, SystemTime};
 
 use bson::{Bson, doc, Document};
-use crossbeam_channel::{bounded, Receiver, Sender};
+use flume::{bounded, Receiver, Sender};
+use log::{info, warn};
+use tokio::net::{TcpListener, TcpStream};
 
+use crate::auth;
+use crate::compression::Compression;
 use crate::Config;
+use crate::data_plugins::PluginData;
 use crate::image::Image;
+use crate::protocol::Message;
+use crate::wrapped_stream::{self, ProtocolError, WrappedStream};
 
-pub static GUI_HANDLER_RUNNING: AtomicBool = AtomicBool::new(false);
+// whether a gui is connected to the gui connector of one run of the core (see start), nothing
+// has to be sent to the gui while it is not
+pub type GuiConnected = Arc<AtomicBool>;
+
+// the images, the data, the idle input plugins (see Message::Status) and the control messages of the gui
+pub type GuiChannels = (Sender<Image>, Sender<PluginData>, Sender<Vec<String>>, Receiver<(String, String)>);
+
+// waits for an image or a datum, then sends everything that is queued at that moment
+async fn handle_stream(mut stream: WrappedStream<TcpStream>, image_rx: &Receiver<Image>, data_rx: &Receiver<PluginData>, status_rx: &Receiver<Vec<String>>, _control_tx: &Sender<(String, String)>) -> Result<bool, ProtocolError> {
+    let to_bson = |datum: PluginData| Bson::Array(vec![
+        Bson::String(datum.0),
+        Bson::String(datum.1),
+        Bson::DateTime(bson::DateTime::from_system_time(datum.2)),
+        datum.3,
+    ]);
 
-fn handle_stream(mut stream: TcpStream, image_rx: &Receiver<Image>, data_rx: &Receiver<(String, String, SystemTime, Bson)>, control_tx: &Sender<(String, String)>) -> Result<bool, io::Error> {
     loop {
-        // collect all images in the queue
-        let images = {
-            let mut images = vec![];
-            loop {
-                let image = image_rx.recv_timeout(Duration::from_millis(5));
-                if image.is_err() { break; }
-                let image = image.unwrap();
-                images.push(Bson::Document(Document::from(image)));
+        let mut images = vec![];
+        let mut data = vec![];
+
+        tokio::select! {
+            Ok(image) = image_rx.recv_async() => images.push(image),
+            Ok(datum) = data_rx.recv_async() => data.push(to_bson(datum)),
+            Ok(idle) = status_rx.recv_async() => {
+                stream.send_with_32bit_integer_length(Message::Status { idle }.encode()?).await?;
+                continue;
             }
-            Bson::Array(images)
-        };
+            else => return Ok(false),
+        }
+
+        // collect all images in the queue
+        images.extend(image_rx.drain());
 
         // collect all responses from the database
-        let data = {
-            let mut data = vec![];
-            loop {
-                let datum = data_rx.recv_timeout(Duration::from_millis(5));
-                if datum.is_err() { break; }
-                let datum = datum.unwrap();
-                data.push(Bson::Array(vec![
-                    Bson::String(datum.0),
-                    Bson::String(datum.1.clone()),
-                    Bson::DateTime(bson::DateTime::from_system_time(datum.2)),
-                    datum.3,
-                ]));
-            }
-            Bson::Array(data)
-        };
+        data.extend(data_rx.drain().map(to_bson));
 
-        // create dict/hashmap/document to send to the gui
         // should be sufficiently fast using bson
-        let doc = doc! {
-            "images": images,
-            "data": data,
-        };
-        let mut buf = Vec::new();
-        doc.to_writer(&mut buf).unwrap();
-
-        stream.write(u32::to_le_bytes(buf.len() as u32).as_ref())?;
-        stream.write(&buf)?;
+        stream.send_with_32bit_integer_length(Message::Update { images, data }.encode()?).await?;
 
         // TODO receive control
     }
 }
 
-pub fn start(cfg: &Config) -> (Sender<Image>, Sender<(String, String, SystemTime, Bson)>, Receiver<(String, String)>) {
+// the gui has to authenticate like a plugin (see auth), with the gui_token of the config
+// or a generated one, which is written to <runtime_dir>/gui.token
+// the gui lists the compression algorithms it supports as "compression" in the handshake, the
+// core answers with the one it picked from gui_compression (or null), see compression
+pub fn start(cfg: &Config) -> io::Result<(GuiConnected, GuiChannels)> {
     // create channels with a size of 10 (small buffer)
     let (image_tx, image_rx) = bounded(10);
     let (data_tx, data_rx) = bounded(10);
+    let (status_tx, status_rx) = bounded(1);
     let (control_tx, control_rx) = bounded(10);
 
     let bind_str = format!("{}:{}", cfg.bind_addr, cfg.bind_port_gui);
+    let listener = std::net::TcpListener::bind(&bind_str)
+        .and_then(|listener| listener.set_nonblocking(true).map(|_| listener))
+        .and_then(TcpListener::from_std)
+        .map_err(|e| io::Error::new(e.kind(), format!("binding {} for the gui failed: {}", bind_str, e)))?;
 
-    // only one gui connection at a time
-    thread::spawn(move || {
-        let listener = TcpListener::bind(bind_str).expect("binding the gui listener failed");
-
-        for stream in listener.incoming() {
-            let stream = stream.expect("opening the gui's tcp stream failed");
-            // to always know if there is a gui running...
-            GUI_HANDLER_RUNNING.store(true, Ordering::SeqCst);
-            let _ = handle_stream(stream, &image_rx, &data_rx, &control_tx);
-            // store if there is a handler running :D
-            GUI_HANDLER_RUNNING.store(false, Ordering::SeqCst);
+    let token = match &cfg.gui_token {
+        Some(token) => token.clone(),
+        None => {
+            let token = auth::generate_token();
+            let token_file = cfg.runtime_dir().join("gui.token");
+            auth::write_token_file(&token_file, &token)
+                .map_err(|e| io::Error::new(e.kind(), format!("writing the gui token file {:?} failed: {}", token_file, e)))?;
+            info!("the token of the gui is in {:?}", token_file);
+            token
         }
+    };
 
-        drop(listener);
+    let offered = cfg.gui_compression.clone();
+    let connected = GuiConnected::default();
+    let handler_connected = connected.clone();
+
+    // only one gui connection at a time
+    tokio::spawn(async move {
+        loop {
+            let (stream, peer_addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("accepting a gui connection failed: {}", e);
+                    continue;
+                }
+            };
+
+            let mut stream = WrappedStream::new(stream);
+            let mut compression = None;
+            let answer = |handshake: &Document| {
+                let supported: Vec<String> = handshake.get_array("compression")
+                    .map(|algorithms| algorithms.iter().filter_map(|a| a.as_str().map(str::to_string)).collect())
+                    .unwrap_or_default();
+                compression = Compression::negotiate(&offered, &supported);
+                doc! { "compression": compression.map(|c| Bson::String(c.to_string())).unwrap_or(Bson::Null) }
+            };
+            if let Err(e) = auth::accept_with(&mut stream, &token, answer).await {
+                if let auth::AuthError::Protocol(e) = &e {
+                    wrapped_stream::count_rejected_frame("gui", e);
+                }
+                warn!("rejected a gui connection from {}: {}", peer_addr, e);
+                continue;
+            }
+            stream.set_compression(compression, "gui");
+            handler_connected.store(true, Ordering::SeqCst);
+            let _ = handle_stream(stream, &image_rx, &data_rx, &status_rx, &control_tx).await;
+            handler_connected.store(false, Ordering::SeqCst);
+        }
     });
 
-    (image_tx, data_tx, control_rx)
+    Ok((connected, (image_tx, data_tx, status_tx, control_rx)))
 }
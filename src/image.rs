@@ -1,38 +1,127 @@
 use std::time::SystemTime;
 
 use bson::{Bson, doc, Document};
-use bson::spec::BinarySubtype;
-use serde::Serialize;
+use bytes::Bytes;
 
 // struct to hold all data that is relevant to identify exactly one frame AND the frame itself
 // i.e. the primary key is (timestamp, source) and the frame is data
+// data is reference counted and immutable, so handing an image to every data plugin and the
+// gui clones the pointer, not the frame (see protocol for how it is serialized)
+// timestamp is either the capture time (in metadata) or the arrival time, see clock
 
-#[derive(Clone, Serialize)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Image {
-    pub data: Vec<u8>,
+    pub data: Bytes,
     pub timestamp: SystemTime,
+    // when the frame arrived at the core
+    pub arrival_time: SystemTime,
     pub input_source: String,
+    pub metadata: FrameMetadata,
 }
 
 impl Image {
-    pub fn new(data: Vec<u8>, input_source: String) -> Self {
+    // an image that just arrived, stamped with the arrival time
+    pub fn new(data: impl Into<Bytes>, input_source: String, metadata: FrameMetadata) -> Self {
+        let now = SystemTime::now();
         Image {
-            data,
-            timestamp: SystemTime::now(),
+            data: data.into(),
+            timestamp: now,
+            arrival_time: now,
             input_source,
+            metadata,
         }
     }
 }
 
-impl From<Image> for Document {
-    fn from(image: Image) -> Self {
-        doc! {
-            "data": Bson::Binary(bson::Binary {
-                subtype: BinarySubtype::Generic,
-                bytes: image.data,
-            }),
-            "input_source": image.input_source,
-            "timestamp": Bson::DateTime(bson::DateTime::from_system_time(image.timestamp)),
+// what an input plugin tells about a frame (from protocol version 2 on, in the "metadata" of the
+// frame message), the core only passes it on to the data plugins and the gui
+// everything is optional, a plugin that sends nothing leaves the data plugins guessing as before
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct FrameMetadata {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    // the layout of raw pixels, e.g. "bgr8" or "gray8"
+    pub pixel_format: Option<String>,
+    // how the frame is encoded, e.g. "raw", "jpeg" or "png"
+    pub encoding: Option<String>,
+    // when the camera took the frame (in its clock)
+    pub capture_time: Option<SystemTime>,
+    // the number of the frame, as counted by the camera
+    pub sequence: Option<u64>,
+    // anything else the plugin wants to pass on, e.g. {"exposure_us": 8000}
+    pub tags: Document,
+}
+
+impl FrameMetadata {
+    pub fn is_empty(&self) -> bool {
+        *self == FrameMetadata::default()
+    }
+
+    // only the fields that are set
+    pub fn to_document(&self) -> Document {
+        let mut doc = doc! {};
+
+        if let Some(width) = self.width {
+            doc.insert("width", width as i64);
+        }
+        if let Some(height) = self.height {
+            doc.insert("height", height as i64);
+        }
+        if let Some(pixel_format) = &self.pixel_format {
+            doc.insert("pixel_format", pixel_format);
+        }
+        if let Some(encoding) = &self.encoding {
+            doc.insert("encoding", encoding);
         }
+        if let Some(capture_time) = self.capture_time {
+            doc.insert("capture_time", bson::DateTime::from_system_time(capture_time));
+        }
+        if let Some(sequence) = self.sequence {
+            doc.insert("sequence", i64::try_from(sequence).unwrap_or(i64::MAX));
+        }
+        if !self.tags.is_empty() {
+            doc.insert("tags", self.tags.clone());
+        }
+
+        doc
+    }
+
+    // the name of the first field with the wrong type if it fails, unknown fields are ignored
+    pub fn from_document(doc: &Document) -> Result<FrameMetadata, &'static str> {
+        // integers are 32 or 64 bit depending on the plugin's bson library
+        fn integer<T: TryFrom<i64>>(doc: &Document, key: &'static str) -> Result<Option<T>, &'static str> {
+            match doc.get(key) {
+                None | Some(Bson::Null) => Ok(None),
+                Some(Bson::Int32(n)) => T::try_from(i64::from(*n)).map(Some).map_err(|_| key),
+                Some(Bson::Int64(n)) => T::try_from(*n).map(Some).map_err(|_| key),
+                Some(_) => Err(key),
+            }
+        }
+        fn string(doc: &Document, key: &'static str) -> Result<Option<String>, &'static str> {
+            match doc.get(key) {
+                None | Some(Bson::Null) => Ok(None),
+                Some(Bson::String(s)) => Ok(Some(s.clone())),
+                Some(_) => Err(key),
+            }
+        }
+
+        Ok(FrameMetadata {
+            width: integer(doc, "width")?,
+            height: integer(doc, "height")?,
+            pixel_format: string(doc, "pixel_format")?,
+            encoding: string(doc, "encoding")?,
+            capture_time: match doc.get("capture_time") {
+                None | Some(Bson::Null) => None,
+                Some(Bson::DateTime(time)) => Some(time.to_system_time()),
+                Some(_) => return Err("capture_time"),
+            },
+            sequence: integer(doc, "sequence")?,
+            tags: match doc.get("tags") {
+                None | Some(Bson::Null) => Document::new(),
+                Some(Bson::Document(tags)) => tags.clone(),
+                Some(_) => return Err("tags"),
+            },
+        })
     }
 }
@@ -1,59 +1,336 @@
-use std::thread;
-use std::time::Duration;
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
-use crossbeam_channel::{bounded, Receiver, Sender};
-use log::{debug, info};
+use async_trait::async_trait;
+use flume::{bounded, Receiver, Sender};
+use log::{debug, info, warn};
 
-use crate::{Config, Plugin};
-use crate::image::Image;
-use crate::plugin::Handler;
-use crate::wrapped_stream::WrappedStream;
+use crate::Config;
+use crate::clock::Clock;
+use crate::config::{IdleBackoff, IdleConfig, PluginConfig, PluginKind, TimestampConfig};
+use crate::image::{FrameMetadata, Image};
+use crate::plugin::{Context, Handler, HandlerError, Plugin, Session, StopOutcome};
+use crate::protocol::{self, Codec, Message};
+use crate::wrapped_stream::{MessageType, ProtocolError};
+
+// an input plugin is either polled, i.e. asked for every frame with a request_frame, or it
+// pushes its frames (if it supports that and the core accepted it in the hello)
+// in the push mode the plugin may send as many frames ahead as the credits it got, it starts
+// with the credits of the hello and gets one back with a credit message for every frame the
+// main loop took, so a plugin that is faster than the data plugins can't flood the core
+
+// the shortest retry_after of a plugin that is honored, so a plugin that keeps answering with a
+// retry_after of 0 can't make the core ask it in a busy loop
+const MIN_RETRY_AFTER_MS: u64 = 10;
+
+// how often a handler that waits for a pushed frame (or for the end of the idle backoff) checks
+// whether the plugin is being stopped
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(100);
 
 #[derive(Clone)]
 pub struct InputPluginHandler {
     image_tx: Sender<Image>,
+    idle: IdleConfig,
+    // shared with the InputPlugin, see Idle
+    is_idle: Arc<AtomicBool>,
+    timestamps: TimestampConfig,
+}
+
+// how long a connection has been without a frame, see IdleConfig
+// a plugin that has been without a frame for warn_after_secs is idle until the next frame (or
+// the end of the connection), which the core reports in the alive line and to the gui
+struct Idle {
+    config: IdleConfig,
+    // no_frames in a row
+    no_frames: u32,
+    // the last frame or the start of the connection
+    since: Instant,
+    reported: bool,
+    is_idle: Arc<AtomicBool>,
+}
+
+impl Idle {
+    fn new(config: &IdleConfig, is_idle: &Arc<AtomicBool>) -> Self {
+        is_idle.store(false, Ordering::SeqCst);
+        Idle { config: config.clone(), no_frames: 0, since: Instant::now(), reported: false, is_idle: is_idle.clone() }
+    }
+
+    // how long to wait before asking for a frame again
+    fn no_frame(&mut self, retry_after: Option<Duration>) -> Duration {
+        self.no_frames += 1;
+
+        let backoff = match (retry_after, self.config.backoff) {
+            (Some(retry_after), _) if self.config.honor_retry_after => u64::try_from(retry_after.as_millis()).unwrap_or(u64::MAX).max(MIN_RETRY_AFTER_MS),
+            (_, IdleBackoff::Fixed) => self.config.initial_backoff_ms,
+            // initial_backoff * 2^(no_frames - 1)
+            (_, IdleBackoff::Exponential) => self.config.initial_backoff_ms.saturating_mul(1 << (self.no_frames - 1).min(31)),
+        };
+        Duration::from_millis(backoff.min(self.config.max_backoff_ms))
+    }
+
+    fn frame(&mut self, name: &str) {
+        if self.reported {
+            info!("plugin {:?} sends frames again after {} s without", name, self.since.elapsed().as_secs());
+        }
+        *self = Idle::new(&self.config, &self.is_idle);
+    }
+
+    // reports the plugin once it has been without a frame for warn_after_secs
+    fn check(&mut self, name: &str) {
+        if self.reported || self.config.warn_after_secs == 0 || self.since.elapsed() < Duration::from_secs(self.config.warn_after_secs) {
+            return;
+        }
+        warn!("plugin {:?} has not sent a frame for {} s", name, self.since.elapsed().as_secs());
+        self.reported = true;
+        self.is_idle.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Drop for Idle {
+    fn drop(&mut self) {
+        self.is_idle.store(false, Ordering::SeqCst);
+    }
 }
 
+#[async_trait]
 impl Handler for InputPluginHandler {
-    fn handle(&self, input_plugin_name: &String, mut stream: WrappedStream) {
-        // TODO clean this mess up using bson
-        info!("received connection for plugin {:?}", input_plugin_name);
+    async fn handle(&self, input_plugin_name: &str, mut codec: Codec, session: Session) -> Result<(), HandlerError> {
+        info!("received connection for plugin {:?} (protocol version {}, {:?})", input_plugin_name, session.protocol_version, session.accepted);
+
+        let mut idle = Idle::new(&self.idle, &self.is_idle);
+        let mut clock = Clock::new(input_plugin_name, &self.timestamps);
+        if session.accepted.push {
+            return self.receive_pushed(input_plugin_name, codec, session.accepted.credits, idle, clock).await;
+        }
 
         loop {
-            stream.write(b"i").expect("could not send request for image");
+            // an idle plugin never gets to send an image, so it has to be checked whether it is
+            // being stopped before asking it again
+            if self.image_tx.is_disconnected() {
+                let _ = codec.send(&Message::Shutdown).await;
+                return Ok(());
+            }
+
+            codec.send(&Message::RequestFrame).await.context("requesting an image")?;
+
+            match codec.recv(MessageType::Image).await.context("receiving an image")? {
+                Message::NoFrame { retry_after } => {
+                    idle.check(input_plugin_name);
+                    let backoff = idle.no_frame(retry_after);
+                    debug!("no frame from {:?}, asking again in {:?}", input_plugin_name, backoff);
+                    self.sleep_unless_stopped(backoff).await;
+                }
+                Message::Frame { data, metadata } => {
+                    idle.frame(input_plugin_name);
+                    if self.image_tx.send_async(image(input_plugin_name, data, metadata, &mut clock)).await.is_err() {
+                        // the plugin is being stopped, ask it to shut down
+                        let _ = codec.send(&Message::Shutdown).await;
+                        return Ok(());
+                    }
+
+                    // generates too much output, only practicable if nr of incoming frames is not that high
+                    debug!("received one frame from {:?}", input_plugin_name);
+                }
+                message => return Err(protocol::unexpected(&message, "a frame")).context("receiving an image"),
+            }
+        }
+    }
+}
 
-            let mode = stream.recv_32bit_integer();
+impl InputPluginHandler {
+    // sleeps for the backoff, but not beyond the plugin being stopped
+    async fn sleep_unless_stopped(&self, backoff: Duration) {
+        let deadline = tokio::time::Instant::now() + backoff;
+        while tokio::time::Instant::now() < deadline && !self.image_tx.is_disconnected() {
+            tokio::time::sleep_until(deadline.min(tokio::time::Instant::now() + STOP_CHECK_INTERVAL)).await;
+        }
+    }
 
-            if mode == 0 {
-                info!("no data (sleeping for one second)");
-                thread::sleep(Duration::from_secs(1));
-            } else if mode == 1 {
-                let mut buf = stream.recv_based_on_32bit_integer();
+    async fn receive_pushed(&self, input_plugin_name: &str, mut codec: Codec, mut credits: u32, mut idle: Idle, mut clock: Clock) -> Result<(), HandlerError> {
+        loop {
+            // the plugin may not send anything for a long time, so the closed channel of a plugin
+            // that is being stopped has to be noticed while waiting
+            let received = {
+                let receiving = codec.recv(MessageType::Image);
+                tokio::pin!(receiving);
+                loop {
+                    tokio::select! {
+                        received = &mut receiving => break Some(received),
+                        _ = tokio::time::sleep(STOP_CHECK_INTERVAL) => {
+                            if self.image_tx.is_disconnected() {
+                                break None;
+                            }
+                            idle.check(input_plugin_name);
+                        }
+                    }
+                }
+            };
+            let Some(received) = received else {
+                let _ = codec.send(&Message::Shutdown).await;
+                return Ok(());
+            };
+
+            match received.context("receiving an image")? {
+                // a plugin that has no frame at the moment simply sends nothing
+                Message::NoFrame { .. } => {}
+                Message::Frame { data, metadata } => {
+                    idle.frame(input_plugin_name);
+                    credits = credits.checked_sub(1)
+                        .ok_or_else(|| ProtocolError::UnexpectedMessage("a frame without a credit".to_string()))
+                        .context("receiving an image")?;
 
-                self.image_tx.send(Image::new(buf, input_plugin_name.clone())).expect("TODO: panic message");
+                    if self.image_tx.send_async(image(input_plugin_name, data, metadata, &mut clock)).await.is_err() {
+                        let _ = codec.send(&Message::Shutdown).await;
+                        return Ok(());
+                    }
 
-                // generates too much output, only practicable if nr of incoming frames is not that high
-                debug!("received one frame from {:?}", input_plugin_name);
+                    codec.send(&Message::Credit { frames: 1 }).await.context("granting a credit")?;
+                    credits += 1;
+                    debug!("received one frame from {:?}", input_plugin_name);
+                }
+                message => return Err(protocol::unexpected(&message, "a frame")).context("receiving an image"),
             }
         }
     }
 }
 
-pub fn start(cfg: &Config) -> (Vec<Plugin>, Vec<Receiver<Image>>) {
-    let mut image_receivers = vec![];
-    let mut plugins = vec![];
+// the image of a frame that just arrived, with the timestamp the config of the plugin asks for
+fn image(input_plugin_name: &str, data: Vec<u8>, metadata: FrameMetadata, clock: &mut Clock) -> Image {
+    let mut image = Image::new(data, input_plugin_name.to_string(), metadata);
+    image.timestamp = clock.timestamp(image.arrival_time, image.metadata.capture_time);
+    image
+}
 
-    for (i, (name, plugin)) in (&cfg.input_plugins).into_iter().enumerate() {
-        let (image_tx, image_rx) = bounded(0);
+// a running input plugin and the channel its images arrive on
 
-        let plugin = Plugin::new(name, &cfg.bind_addr, cfg.bind_port_range_start + i as i32, plugin, Box::new(InputPluginHandler { image_tx }));
+pub struct InputPlugin {
+    pub plugin: Plugin,
+    pub image_rx: Receiver<Image>,
+    is_idle: Arc<AtomicBool>,
+}
 
-        image_receivers.push(image_rx);
-        plugins.push(plugin);
+impl InputPlugin {
+    // whether the plugin has been without a frame for its idle.warn_after_secs, see Idle
+    pub fn is_idle(&self) -> bool {
+        self.is_idle.load(Ordering::SeqCst)
     }
 
-    (plugins, image_receivers)
+    pub fn stop(self, grace_period: Duration) -> StopOutcome {
+        // drop the receiver first, so the handler can't block on sending an image
+        drop(self.image_rx);
+        self.plugin.stop(grace_period)
+    }
 }
 
+pub fn start_plugin(cfg: &Config, name: &String, plugin: &PluginConfig, bind_port: i32) -> io::Result<InputPlugin> {
+    let (image_tx, image_rx) = bounded(0);
+    let is_idle = Arc::new(AtomicBool::new(false));
+
+    let handler = InputPluginHandler { image_tx, idle: plugin.idle.clone(), is_idle: is_idle.clone(), timestamps: plugin.timestamps.clone() };
+    let plugin = Plugin::new(cfg, PluginKind::Input, name, plugin, bind_port, Box::new(handler))?;
+
+    Ok(InputPlugin { plugin, image_rx, is_idle })
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::Document;
+    use tokio::net::UnixStream;
+
+    use super::*;
+    use crate::plugin::{Accepted, Capabilities};
+    use crate::transport::Stream;
+    use crate::wrapped_stream::WrappedStream;
+
+    fn session() -> Session {
+        Session { protocol_version: 2, plugin_name: None, plugin_version: None, capabilities: Capabilities::default(), accepted: Accepted::default(), hello: Document::new() }
+    }
+
+    fn codec(stream: UnixStream) -> Codec {
+        Codec::new(WrappedStream::new(Stream::Unix(stream)), PluginKind::Input, "camera", &session(), None)
+    }
 
+    #[tokio::test]
+    async fn an_idle_plugin_is_asked_to_shut_down_during_the_backoff() {
+        let (core, plugin) = UnixStream::pair().unwrap();
+        let (image_tx, image_rx) = bounded(0);
+        let idle = IdleConfig { initial_backoff_ms: 10_000, max_backoff_ms: 10_000, ..IdleConfig::default() };
+        let handler = InputPluginHandler { image_tx, idle, is_idle: Arc::new(AtomicBool::new(false)), timestamps: TimestampConfig::default() };
+        let handling = tokio::spawn(async move { handler.handle("camera", codec(core), session()).await });
 
+        let mut plugin = codec(plugin);
+        assert_eq!(plugin.recv(MessageType::Hello).await.unwrap(), Message::RequestFrame);
+        plugin.send(&Message::NoFrame { retry_after: None }).await.unwrap();
+
+        // the plugin is stopped while the handler waits out the 10 s backoff
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(image_rx);
+        let message = tokio::time::timeout(Duration::from_secs(1), plugin.recv(MessageType::Hello)).await.unwrap().unwrap();
+        assert_eq!(message, Message::Shutdown);
+        assert!(handling.await.unwrap().is_ok());
+    }
+
+    fn idle(backoff: IdleBackoff, honor_retry_after: bool) -> Idle {
+        let config = IdleConfig { backoff, initial_backoff_ms: 100, max_backoff_ms: 1000, honor_retry_after, warn_after_secs: 1 };
+        Idle::new(&config, &Arc::new(AtomicBool::new(false)))
+    }
+
+    fn backoffs(idle: &mut Idle, retry_after: Option<Duration>, n: usize) -> Vec<u128> {
+        (0..n).map(|_| idle.no_frame(retry_after).as_millis()).collect()
+    }
+
+    #[test]
+    fn the_fixed_backoff_stays_the_same() {
+        assert_eq!(backoffs(&mut idle(IdleBackoff::Fixed, true), None, 3), [100, 100, 100]);
+    }
+
+    #[test]
+    fn the_exponential_backoff_doubles_up_to_the_maximum() {
+        let mut idle = idle(IdleBackoff::Exponential, true);
+        assert_eq!(backoffs(&mut idle, None, 6), [100, 200, 400, 800, 1000, 1000]);
+
+        // a frame starts over
+        idle.frame("camera");
+        assert_eq!(backoffs(&mut idle, None, 2), [100, 200]);
+
+        // no overflow after many no frames in a row
+        assert_eq!(backoffs(&mut idle, None, 100).last(), Some(&1000));
+    }
+
+    #[test]
+    fn the_retry_after_of_the_plugin_is_honored_up_to_the_maximum() {
+        assert_eq!(backoffs(&mut idle(IdleBackoff::Exponential, true), Some(Duration::from_millis(30)), 3), [30, 30, 30]);
+        assert_eq!(backoffs(&mut idle(IdleBackoff::Fixed, true), Some(Duration::from_secs(60)), 1), [1000]);
+        assert_eq!(backoffs(&mut idle(IdleBackoff::Fixed, false), Some(Duration::from_millis(30)), 1), [100]);
+    }
+
+    #[test]
+    fn a_retry_after_of_0_does_not_make_the_core_busy_poll() {
+        assert_eq!(backoffs(&mut idle(IdleBackoff::Fixed, true), Some(Duration::ZERO), 3), [10, 10, 10]);
+        assert_eq!(backoffs(&mut idle(IdleBackoff::Fixed, true), Some(Duration::from_millis(9)), 1), [10]);
+    }
+
+    #[test]
+    fn a_plugin_is_idle_from_warn_after_secs_until_the_next_frame() {
+        let is_idle = Arc::new(AtomicBool::new(false));
+        let mut idle = Idle::new(&IdleConfig { warn_after_secs: 1, ..IdleConfig::default() }, &is_idle);
+
+        idle.check("camera");
+        assert!(!is_idle.load(Ordering::SeqCst));
+
+        idle.since -= Duration::from_secs(2);
+        idle.check("camera");
+        assert!(is_idle.load(Ordering::SeqCst));
+
+        idle.frame("camera");
+        assert!(!is_idle.load(Ordering::SeqCst));
+
+        // and until the end of the connection
+        idle.since -= Duration::from_secs(2);
+        idle.check("camera");
+        drop(idle);
+        assert!(!is_idle.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,225 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use flume::{Receiver, RecvTimeoutError, Selector, Sender, SendTimeoutError};
+use log::{debug, error, info, warn};
+
+use crate::config::Config;
+use crate::data_manager::DataManager;
+use crate::plugin::StopOutcome;
+use crate::reload::ConfigWatcher;
+use crate::shutdown::ShutdownSignal;
+use crate::supervisor::{RunningPlugins, Supervisor};
+
+// the core as a library, the binary (main.rs) only parses the command line and calls run,
+// the integration tests (see tests/) run it with a config built in code
+
+pub mod auth;
+pub mod clock;
+pub mod compression;
+pub mod config;
+pub mod input_plugins;
+pub mod data_plugins;
+pub mod image;
+pub mod plugin;
+pub mod plugin_output;
+pub mod recorder;
+pub mod protocol;
+pub mod data_manager;
+pub mod gui_connector;
+pub mod reload;
+pub mod replay;
+pub mod shm;
+pub mod shutdown;
+pub mod transport;
+pub mod supervisor;
+pub mod wrapped_stream;
+
+// exit codes of the core
+pub const EXIT_OK: i32 = 0;
+pub const EXIT_ERROR: i32 = 1;
+// the core shut down, but at least one plugin did not react to the SIGTERM and had to be killed
+pub const EXIT_PLUGINS_KILLED: i32 = 2;
+
+// how long the main loop waits for an image before it supervises the plugins again
+const IMAGE_WAIT: Duration = Duration::from_millis(50);
+
+// the data plugin's result_timeout ends the connection of a plugin that is late with its result,
+// the main loop waits that much longer, so it sees the closed channel instead of racing it
+const RESULT_GRACE: Duration = Duration::from_millis(500);
+
+// the blocking send and recv of the channels, except that they give up at the deadline or if a
+// shutdown is requested in the meantime (a plugin that never answers must neither hold up the
+// frames of the other plugins for long nor prevent the shutdown)
+// both return whether it worked, i.e. false if the channel is closed, on the deadline or on shutdown
+
+fn send_until<T>(tx: &Sender<T>, mut value: T, deadline: Instant, shutdown: &ShutdownSignal) -> bool {
+    loop {
+        let wait = deadline.saturating_duration_since(Instant::now()).min(Duration::from_millis(100));
+        match tx.send_timeout(value, wait) {
+            Ok(()) => return true,
+            Err(SendTimeoutError::Timeout(v)) if !shutdown.is_requested() && Instant::now() < deadline => value = v,
+            Err(_) => return false,
+        }
+    }
+}
+
+fn recv_until<T>(rx: &Receiver<T>, deadline: Instant, shutdown: &ShutdownSignal) -> Option<T> {
+    loop {
+        let wait = deadline.saturating_duration_since(Instant::now()).min(Duration::from_millis(100));
+        match rx.recv_timeout(wait) {
+            Ok(value) => return Some(value),
+            Err(RecvTimeoutError::Timeout) if !shutdown.is_requested() && Instant::now() < deadline => continue,
+            Err(_) => return None,
+        }
+    }
+}
+
+// runs until a shutdown is requested, returns the exit code
+// it has to be called within a tokio runtime, which runs the connections of the plugins and the gui
+// without a watcher the config is never reloaded
+pub fn run(mut cfg: Config, mut watcher: Option<ConfigWatcher>, shutdown: ShutdownSignal) -> i32 {
+    let data_manager = Arc::new(Mutex::new(DataManager::new()));
+
+    let (gui_connected, (gui_image_tx, gui_data_tx, gui_status_tx, _gui_control_rx)) = match gui_connector::start(&cfg) {
+        Ok(channels) => channels,
+        Err(e) => {
+            error!("starting the gui connector failed: {}", e);
+            return EXIT_ERROR;
+        }
+    };
+
+    let mut plugins = match RunningPlugins::start_all(&cfg, &data_manager) {
+        Ok(plugins) => plugins,
+        Err(e) => {
+            error!("starting the plugins failed: {}", e);
+            return EXIT_ERROR;
+        }
+    };
+    let mut supervisor = Supervisor::new();
+
+    let mut secondly_printer_timer = Instant::now();
+
+    while !shutdown.is_requested() {
+        supervisor.supervise(&cfg, &mut plugins, &data_manager);
+
+        // spread the images from input to data, waiting until any input plugin sent one (or
+        // for the next round of supervising), a plugin that stopped is left to the supervisor
+        let image = if plugins.input.is_empty() {
+            thread::sleep(IMAGE_WAIT);
+            None
+        } else {
+            plugins.input.values()
+                .fold(Selector::new(), |selector, input_plugin| selector.recv(&input_plugin.image_rx, Result::ok))
+                .wait_timeout(IMAGE_WAIT)
+                .ok()
+                .flatten()
+        };
+
+        if let Some(image) = image {
+            // distribute that image to all connected data plugins, a plugin that is not connected
+            // (yet) is skipped, e.g. one that was restarted and died before it connected, a plugin
+            // that stopped in the meantime can't receive it and is left to the supervisor
+            let sent = Instant::now();
+            let receiving_plugins: Vec<_> = plugins.data.iter()
+                .filter(|(name, data_plugin)| {
+                    if !data_plugin.plugin.is_connected() {
+                        supervisor.skipped_frame(name);
+                        return false;
+                    }
+                    send_until(&data_plugin.image_tx, image.clone(), sent + data_plugin.result_timeout, &shutdown)
+                })
+                .map(|(_, data_plugin)| data_plugin)
+                .collect();
+            // if there is a gui connected, also send that image to the gui
+            if gui_connected.load(Ordering::SeqCst) {
+                let _ = gui_image_tx.send(image);
+            }
+
+            // now wait BLOCKING-ly for EVERY data plugin to return something
+            // (or to stop while processing the image, to miss its result_timeout, or for a shutdown)
+            // the results are only stored once all of them are there, a handler collects the data
+            // of the dependencies after it took the image, which must not include this frame
+            let results: Vec<_> = receiving_plugins.into_iter()
+                .filter_map(|data_plugin| recv_until(&data_plugin.data_rx, sent + data_plugin.result_timeout + RESULT_GRACE, &shutdown))
+                .collect();
+
+            for data in results {
+                // if there is a gui connected, also send the returned data to the gui
+                if gui_connected.load(Ordering::SeqCst) {
+                    let _ = gui_data_tx.send((data.0.clone(), data.1.clone(), data.2, data.3.clone()));
+                }
+
+                // add the returned data to the data manager
+                data_manager.lock().unwrap().add(data.0, data.1.clone(), data.2, data.3);
+            }
+        }
+
+        // if the last "print" more than 1 second ago, print what every is in this if-case
+        // this is for regular debug/status messages
+        // because otherwise it wouldn't be clear if the core is still running
+        if secondly_printer_timer.elapsed() > Duration::from_secs(1) {
+            let (waiting, stopped) = supervisor.counts();
+            let rejected: u64 = wrapped_stream::rejected_frames().iter().map(|(_, _, count)| count).sum();
+            let skipped: u64 = supervisor.skipped_frames().map(|(_, count)| count).sum();
+            let idle: Vec<String> = plugins.input.iter().filter(|(_, input_plugin)| input_plugin.is_idle()).map(|(name, _)| name.clone()).collect();
+            info!("alive ({} plugins running, {} input plugins idle, {} waiting for a restart, {} stopped, {} frames rejected, {} frames skipped)", plugins.input.len() + plugins.data.len(), idle.len(), waiting, stopped, rejected, skipped);
+            if gui_connected.load(Ordering::SeqCst) {
+                let _ = gui_status_tx.try_send(idle);
+            }
+            for (name, stats) in compression::all_stats() {
+                debug!("compression of {:?}: {}", name, stats);
+            }
+
+            // debug print last 10 values in the DataManager from the activity plugin
+            if let Some(data_time_series) = data_manager.lock().unwrap().get_last(String::from("activity"),&String::from("activity"), 10) {
+                for (i, (timestamp, data)) in data_time_series.iter().enumerate() {
+                    debug!("{}: {} {:?}", i, timestamp.duration_since(UNIX_EPOCH).unwrap().as_millis(), data);
+                }
+            }
+
+            // apply changes of the config file, only the plugins that changed are touched
+            if let Some(new_cfg) = watcher.as_mut().and_then(|watcher| watcher.poll(&cfg)) {
+                let diff = reload::diff(&cfg, &new_cfg);
+                if diff.is_empty() {
+                    info!("the config did not change any plugin");
+                } else {
+                    reload::apply(&new_cfg, &diff, &mut plugins, &mut supervisor, &data_manager);
+                }
+                cfg = new_cfg;
+            }
+
+            secondly_printer_timer = Instant::now();
+        }
+    }
+    info!("shutting down, waiting up to {:?} for every plugin to exit", cfg.shutdown_grace_period() * 2);
+
+    let mut exit_code = EXIT_OK;
+    let mut stopped = plugins.stop_all(cfg.shutdown_grace_period());
+    stopped.extend(supervisor.finish_reaping());
+    for (name, outcome) in stopped {
+        match outcome {
+            StopOutcome::Exited => info!("plugin {:?} exited", name),
+            StopOutcome::Terminated => warn!("plugin {:?} did not exit on its own and was terminated", name),
+            StopOutcome::Killed => {
+                error!("plugin {:?} did not react to SIGTERM and was killed", name);
+                exit_code = EXIT_PLUGINS_KILLED;
+            }
+        }
+    }
+
+    for (name, message, count) in wrapped_stream::rejected_frames() {
+        warn!("rejected {} oversized {} frames of {:?}", count, message, name);
+    }
+    for (name, count) in supervisor.skipped_frames() {
+        warn!("data plugin {:?} missed {} frames because it was not connected", name, count);
+    }
+    for (name, stats) in compression::all_stats() {
+        info!("compression of {:?}: {}", name, stats);
+    }
+
+    info!("shut down");
+    exit_code
+}
@@ -1,100 +1,92 @@
-use std::path::Path;
-use std::sync::{Arc, Mutex};
-use std::sync::atomic::Ordering;
-use std::time::{Duration, Instant, UNIX_EPOCH};
+use std::process;
 
-use log::{debug, info};
+use clap::Parser;
 
-use crate::config::Config;
-use crate::data_manager::DataManager;
-use crate::gui_connector::GUI_HANDLER_RUNNING;
-use crate::plugin::Plugin;
+use palleon_core::{config, replay, EXIT_ERROR};
+use palleon_core::config::PluginMode;
+use palleon_core::reload::ConfigWatcher;
+use palleon_core::shutdown::ShutdownSignal;
+use palleon_core::transport::Address;
 
-mod config;
-mod input_plugins;
-mod data_plugins;
-mod image;
-mod plugin;
-mod data_manager;
-mod gui_connector;
-mod wrapped_stream;
-
-fn check_everything_running(input_plugins: &mut Vec<Plugin>, data_plugins: &mut Vec<Plugin>) {
-    let iter = input_plugins.iter_mut().chain(data_plugins.iter_mut());
-
-    if iter.map(|k| k.has_erroneously_stopped()).any(|k| k.is_some()) {
-        // if ANYTHING (subprocess/child or thread) exists unexpectedly stop the
-        // core (and everything else) one could handle this gracefully, but that would
-        // be way more complicated
-        // TODO kill other? atm kill -9 $(ps aux | grep python | grep palleon | awk "{ print \$2 }")
-        panic!("some thread or plugin process unexpectedly exited.")
-    }
-}
+use crate::cli::{Cli, CliCommand};
 
+mod cli;
 
 fn main() {
-    env_logger::init();
-
-    let cfg = config::load(Path::new("config.toml")).expect("loading config failed");
-
-    let data_manager = Arc::new(Mutex::new(DataManager::new()));
-
-    let (gui_image_tx, gui_data_tx, gui_control_rx) = gui_connector::start(&cfg);
-
-    let (mut input_plugins, image_rxs) = input_plugins::start(&cfg);
-    let (mut data_plugins, image_txs, data_rxs) = data_plugins::start(&cfg, &data_manager);
-
-    let mut secondly_printer_timer = Instant::now();
-
-    loop {
-        check_everything_running(&mut input_plugins, &mut data_plugins);
-
-        // spread all images from input to data
-        for image_rx in &image_rxs {
-            // try to receive image
-            let image = image_rx.recv_timeout(Duration::from_millis(5));
-            if image.is_err() { continue; }
+    let cli = Cli::parse();
 
-            // distribute that image to all data plugins
-            let image = image.unwrap();
-            for image_tx in &image_txs {
-                image_tx.send(image.clone()).expect("failed adding to queue for data plugins");
-            }
-            // if there is a gui connected, also send that image to the gui
-            if GUI_HANDLER_RUNNING.load(Ordering::SeqCst) {
-                let _ = gui_image_tx.send(image);
-            }
-
-            for data_rx in &data_rxs {
-                // now wait BLOCKING-ly for EVERY data plugin to return something
-                let data = data_rx.recv();
-
-                // data     input
-                let data = data.unwrap();
-                // if there is a gui connected, also send the returned data to the gui
-                if GUI_HANDLER_RUNNING.load(Ordering::SeqCst) {
-                    let _ = gui_data_tx.send((data.0.clone(), data.1.clone(), data.2, data.3.clone()));
+    let mut logger = env_logger::Builder::from_default_env();
+    if let Some(level) = cli.log_level {
+        logger.filter_level(level);
+    }
+    logger.init();
+
+    match cli.command.clone().unwrap_or(CliCommand::Run) {
+        CliCommand::Version => println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+        CliCommand::Replay { recording, realtime } => process::exit(replay::run(&recording, realtime)),
+        CliCommand::CheckConfig => check_config(&cli),
+        CliCommand::Run => {
+            // errors before the core runs are printed instead of logged, so they are seen even
+            // with --log-level off
+            let cfg = match config::load(&cli.config, &cli.overrides()) {
+                Ok(cfg) => cfg,
+                Err(e) => {
+                    eprintln!("{}: {}", cli.config.display(), e);
+                    process::exit(EXIT_ERROR);
                 }
-
-                // add the returned data to the data manager
-                data_manager.lock().unwrap().add(data.0, data.1.clone(), data.2, data.3);
-            }
+            };
+
+            let watcher = ConfigWatcher::new(&cli.config, cli.overrides()).unwrap_or_else(|e| {
+                eprintln!("watching the config failed: {}", e);
+                process::exit(EXIT_ERROR);
+            });
+            let shutdown = ShutdownSignal::register().unwrap_or_else(|e| {
+                eprintln!("registering the signal handlers failed: {}", e);
+                process::exit(EXIT_ERROR);
+            });
+
+            // the sockets of the plugins and the gui are handled by tasks on this runtime,
+            // everything else stays on this thread
+            let runtime = tokio::runtime::Runtime::new().unwrap_or_else(|e| {
+                eprintln!("starting the async runtime failed: {}", e);
+                process::exit(EXIT_ERROR);
+            });
+            let _runtime = runtime.enter();
+
+            process::exit(palleon_core::run(cfg, Some(watcher), shutdown));
         }
+    }
+}
 
-        // if the last "print" more than 1 second ago, print what every is in this if-case
-        // this is for regular debug/status messages
-        // because otherwise it wouldn't be clear if the core is still running
-        if secondly_printer_timer.elapsed() > Duration::from_secs(1) {
-            info!("alive");
-
-            // debug print last 10 values in the DataManager from the activity plugin
-            if let Some(data_time_series) = data_manager.lock().unwrap().get_last(String::from("activity"),&String::from("activity"), 10) {
-                for (i, (timestamp, data)) in data_time_series.iter().enumerate() {
-                    debug!("{}: {} {:?}", i, timestamp.duration_since(UNIX_EPOCH).unwrap().as_millis(), data);
-                }
-            }
-
-            secondly_printer_timer = Instant::now();
+// loads and validates the config, then prints what would be started without starting it
+fn check_config(cli: &Cli) {
+    let cfg = match config::load(&cli.config, &cli.overrides()) {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            eprintln!("{}: {}", cli.config.display(), e);
+            process::exit(EXIT_ERROR);
         }
+    };
+
+    println!("{}: ok", cli.config.display());
+    println!("gui: {}:{}", cfg.bind_addr, cfg.bind_port_gui);
+    println!();
+    let rows: Vec<_> = cfg.plugin_ports().into_iter().map(|(kind, name, plugin, port)| {
+        let address = match cfg.plugin_address(kind, name, plugin, port) {
+            Address::Tcp(_) if port == 0 && plugin.uses_port() => format!("{}:ephemeral", cfg.bind_addr),
+            address => address.to_string(),
+        };
+        match plugin.mode {
+            PluginMode::Spawn => (kind, name, address, plugin.command.join(" ")),
+            PluginMode::Listen => (kind, name, address, "(listen mode, started externally)".to_string()),
+            PluginMode::Connect => (kind, name, format!("-> {}", address), "(connect mode, started externally)".to_string()),
+        }
+    }).collect();
+
+    // unix socket paths can get long, the column grows with them
+    let width = rows.iter().map(|row| row.2.len()).max().unwrap_or_default().max(22);
+    println!("{:<6} {:<20} {:<width$} command", "kind", "name", "address");
+    for (kind, name, address, command) in rows {
+        println!("{:<6} {:<20} {:<width$} {}", kind, name, address, command);
     }
 }
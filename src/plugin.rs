@@ -1,63 +1,787 @@
-use std::net::TcpListener;
-use std::process::{Child, Command};
+use std::{fmt, io};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
-use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
-use log::info;
+use async_trait::async_trait;
+use bson::{Bson, doc, Document};
+use log::{info, warn};
+use tokio::runtime::Handle;
+use tokio::task::{JoinHandle, JoinSet};
 
-use crate::config::PluginConfig;
-use crate::wrapped_stream::WrappedStream;
+use crate::auth;
+use crate::compression::Compression;
+use crate::config::{Config, FrameLimits, PluginConfig, PluginKind, PluginMode, ShmConfig};
+use crate::plugin_output::PluginOutput;
+use crate::shm::ShmRing;
+use crate::protocol::{self, Codec};
+use crate::recorder::Recorder;
+use crate::transport::{Address, Listener};
+use crate::wrapped_stream::{self, MessageType, ProtocolError, WrappedStream};
 
 // implements the functionality shared by input and data plugins, which is
-// - starting the plugin
-// - starting the tcp listener thread
+// - starting the plugin (only in the spawn mode)
+// - spawning the socket task, which either listens for the plugin or connects to it
+//   (over tcp or a unix socket, see transport), on the tokio runtime of the core
 // it allows it's streams to be used by a Handler that contains a function
 // which holds the functionality that is specific for each type of plugin
 
 pub struct Plugin {
-    pub socket_thread: JoinHandle<()>,
-    pub plugin_process: Child,
+    // taken once it finished, see has_erroneously_stopped()
+    socket_task: Option<JoinHandle<Result<(), HandlerError>>>,
+    // the runtime the socket task runs on, to wait for it outside of the runtime
+    runtime: Handle,
+    // whether the plugin is connected and past the hello, i.e. its handler is running
+    connected: Arc<AtomicBool>,
+    // process and output only exist in the spawn mode
+    pub plugin_process: Option<Child>,
+    pub output: Option<PluginOutput>,
+    pub started: Instant,
+    // a plugin of protocol version 0 can't be asked to shut down, see stop
+    legacy: bool,
 }
 
 pub enum PluginStoppedReason {
-    SocketThread,
-    PluginProcess,
+    // the socket task ended, with the error that ended it (None if it panicked)
+    Connection(Option<HandlerError>),
+    PluginProcess(ExitStatus),
 }
 
+impl PluginStoppedReason {
+    // everything except a process that exited successfully is a failure
+    pub fn is_failure(&self) -> bool {
+        match self {
+            PluginStoppedReason::Connection(_) => true,
+            PluginStoppedReason::PluginProcess(status) => !status.success(),
+        }
+    }
+}
+
+impl fmt::Display for PluginStoppedReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PluginStoppedReason::Connection(Some(e)) => write!(f, "the connection failed while {}", e),
+            PluginStoppedReason::Connection(None) => write!(f, "the connection handling stopped"),
+            PluginStoppedReason::PluginProcess(status) => write!(f, "the process exited ({})", status),
+        }
+    }
+}
+
+// how a plugin ended up stopping, from most to least graceful
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopOutcome {
+    Exited,
+    Terminated,
+    Killed,
+}
+
+// a handler returns Ok once the plugin was asked to shut down (i.e. its channels were closed),
+// everything else ends the connection with an error that says what the handler was doing
+
+#[async_trait]
 pub trait Handler: Send + Sync {
-    fn handle(&self, name: &String, stream: WrappedStream);
+    async fn handle(&self, name: &str, codec: Codec, session: Session) -> Result<(), HandlerError>;
+}
+
+#[derive(Debug)]
+pub struct HandlerError {
+    pub context: &'static str,
+    pub error: ProtocolError,
+}
+
+impl fmt::Display for HandlerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.context, self.error)
+    }
+}
+
+impl std::error::Error for HandlerError {}
+
+// adds the context to the error of a send or recv, e.g. stream.recv_bson().context("receiving the data")?
+pub trait Context<T> {
+    fn context(self, context: &'static str) -> Result<T, HandlerError>;
+}
+
+impl<T> Context<T> for Result<T, ProtocolError> {
+    fn context(self, context: &'static str) -> Result<T, HandlerError> {
+        self.map_err(|error| HandlerError { context, error })
+    }
+}
+
+// the hello exchange, which follows the authentication (see auth) on every connection
+// - the plugin sends {"protocol_version": <n>, "name": .., "version": .., "capabilities": {..}},
+//   data plugins add their "image" and "dependencies" to the same document
+// - the core answers {"protocol_version": <n>, "accepted": {..}} with the version both sides
+//   speak and the capabilities it accepted, everything not accepted must not be used
+// plugins written before the handshake and the hello existed speak version 0 and have to be
+// configured as legacy: they don't authenticate, a data plugin's first document is its init
+// document instead of a hello, an input plugin sends nothing and waits for the first request,
+// neither gets an answer
+// what follows the hello depends on the version as well, see protocol
+
+// the newest protocol version the core speaks
+pub const PROTOCOL_VERSION: i32 = 2;
+
+// what a plugin declares it is able to do
+#[derive(Debug, Clone, Default)]
+pub struct Capabilities {
+    // the plugin sends frames on its own instead of being asked for each one
+    pub push: bool,
+    // compression algorithms in order of preference
+    pub compression: Vec<String>,
+    // the plugin is able to pass frames through shared memory, see shm
+    pub shm: bool,
+    // how many frames (or results) the plugin handles per message
+    pub batch_size: u32,
+    // describes the values a data plugin returns
+    pub output_schema: Option<Document>,
+}
+
+// the capabilities the core accepted
+#[derive(Debug, Clone, PartialEq)]
+pub struct Accepted {
+    pub push: bool,
+    // the frames a plugin in the push mode may send before it gets the first credit back
+    pub credits: u32,
+    pub compression: Option<Compression>,
+    // the ring the frames are passed through is only created after accepting, so the answer
+    // holds its path (see hello)
+    pub shm: Option<ShmConfig>,
+    pub batch_size: u32,
+}
+
+impl Default for Accepted {
+    // what every plugin of protocol version 0 gets
+    fn default() -> Self {
+        Accepted { push: false, credits: 0, compression: None, shm: None, batch_size: 1 }
+    }
+}
+
+// the result of the hello exchange, passed to the handler of the connection
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub protocol_version: i32,
+    pub plugin_name: Option<String>,
+    pub plugin_version: Option<String>,
+    pub capabilities: Capabilities,
+    pub accepted: Accepted,
+    // the complete hello (or, for a data plugin of version 0, its init document)
+    pub hello: Document,
+}
+
+impl Session {
+    fn legacy(hello: Document) -> Self {
+        Session { protocol_version: 0, plugin_name: None, plugin_version: None, capabilities: Capabilities::default(), accepted: Accepted::default(), hello }
+    }
+}
+
+impl Capabilities {
+    fn from_document(doc: &Document) -> Self {
+        Capabilities {
+            push: doc.get_bool("push").unwrap_or(false),
+            shm: doc.get_bool("shm").unwrap_or(false),
+            compression: doc.get_array("compression")
+                .map(|algorithms| algorithms.iter().filter_map(|a| a.as_str().map(str::to_string)).collect())
+                .unwrap_or_default(),
+            batch_size: doc.get_i32("batch_size").ok().and_then(|n| u32::try_from(n).ok()).unwrap_or(1).max(1),
+            output_schema: doc.get_document("output_schema").ok().cloned(),
+        }
+    }
+
+    // the core does not support batches (yet), so every plugin is told to fall back to the
+    // behaviour of version 0 there
+    // the push mode, compression and shared memory are accepted if the plugin's config offers
+    // them, see input_plugins, compression and shm, the push mode and shared memory only with
+    // typed messages
+    // both together are fine, the config has at least as many slots as credits (see shm)
+    fn accept(&self, name: &str, protocol_version: i32, offer: &Offer) -> Accepted {
+        let mut accepted = Accepted::default();
+
+        if self.push && protocol_version >= protocol::TYPED_MESSAGES_VERSION && offer.push_credits > 0 {
+            accepted.push = true;
+            accepted.credits = offer.push_credits;
+        } else if self.push {
+            info!("plugin {:?} supports push mode, which is declined", name);
+        }
+        if self.shm && protocol_version >= protocol::TYPED_MESSAGES_VERSION {
+            accepted.shm = offer.shm;
+        }
+        accepted.compression = Compression::negotiate(&offer.compression, &self.compression);
+        if accepted.compression.is_none() && !self.compression.is_empty() {
+            info!("plugin {:?} supports compression ({}), which is declined", name, self.compression.join(", "));
+        }
+        if self.batch_size > accepted.batch_size {
+            info!("plugin {:?} supports batches of {}, which is declined", name, self.batch_size);
+        }
+
+        accepted
+    }
+}
+
+impl Accepted {
+    fn to_document(&self) -> Document {
+        doc! {
+            "push": self.push,
+            "credits": self.credits as i32,
+            "compression": self.compression.map(|c| Bson::String(c.to_string())).unwrap_or(Bson::Null),
+            "batch_size": self.batch_size as i32,
+        }
+    }
+}
+
+// with an accepted compression every frame after the answer is compressed
+// with accepted shared memory the ring is created before answering, a ring that can't be
+// created is declined
+async fn hello(kind: PluginKind, name: &str, stream: &mut WrappedStream, offer: &Offer, legacy: bool) -> Result<(Session, Option<ShmRing>), ProtocolError> {
+    if legacy {
+        let received = match kind {
+            PluginKind::Input => Document::new(),
+            PluginKind::Data => stream.recv_bson(MessageType::Hello).await?,
+        };
+        return Ok((Session::legacy(received), None));
+    }
+
+    let received = stream.recv_bson(MessageType::Hello).await?;
+    let Ok(version) = received.get_i32("protocol_version") else {
+        return Err(ProtocolError::UnexpectedMessage("the hello contains no protocol_version (a plugin of version 0 has to be configured as legacy)".to_string()));
+    };
+    if version < 1 {
+        return Err(ProtocolError::UnexpectedMessage(format!("invalid protocol_version {}", version)));
+    }
+
+    let capabilities = received.get_document("capabilities").map(Capabilities::from_document).unwrap_or_default();
+    let protocol_version = version.min(PROTOCOL_VERSION);
+    let mut accepted = capabilities.accept(name, protocol_version, offer);
+
+    let shm = accepted.shm.and_then(|config| {
+        let path = offer.shm_dir.join(format!("palleon-{}-{}-{}.shm", std::process::id(), kind, name));
+        ShmRing::create(path.clone(), config)
+            .inspect_err(|e| warn!("creating the shared memory {:?} for plugin {:?} failed, declining it: {}", path, name, e))
+            .ok()
+    });
+    if shm.is_none() {
+        accepted.shm = None;
+    }
+
+    let mut answer = accepted.to_document();
+    answer.insert("shm", shm.as_ref().map(|shm| Bson::Document(shm.to_document())).unwrap_or(Bson::Null));
+    stream.send_bson(&doc! { "protocol_version": protocol_version, "accepted": answer }).await?;
+    stream.set_compression(accepted.compression, name);
+
+    let session = Session {
+        protocol_version,
+        plugin_name: received.get_str("name").ok().map(str::to_string),
+        plugin_version: received.get_str("version").ok().map(str::to_string),
+        capabilities,
+        accepted,
+        hello: received,
+    };
+
+    if session.plugin_name.as_deref().is_some_and(|n| n != name) {
+        warn!("plugin {:?} calls itself {:?}", name, session.plugin_name.as_deref().unwrap_or_default());
+    }
+    info!("plugin {:?} ({} {}) speaks protocol version {}", name,
+          session.plugin_name.as_deref().unwrap_or("unnamed"), session.plugin_version.as_deref().unwrap_or("unversioned"), protocol_version);
+    if session.accepted.push {
+        info!("plugin {:?} pushes its frames, up to {} ahead", name, session.accepted.credits);
+    }
+    if let Some(compression) = session.accepted.compression {
+        info!("the frames of plugin {:?} are compressed with {}", name, compression);
+    }
+    if let Some(shm) = &shm {
+        info!("the frames of plugin {:?} are passed through the shared memory {:?}", name, shm.path());
+    }
+
+    Ok((session, shm))
 }
 
 
 impl Plugin {
-    // has the subprocess or the thread finished?
+    // a plugin that is not connected (yet) can't take an image, its handler isn't running
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::SeqCst)
+    }
+
+    // has the subprocess or the socket task finished?
     pub fn has_erroneously_stopped(&mut self) -> Option<PluginStoppedReason> {
-        if self.socket_thread.is_finished() { Some(PluginStoppedReason::SocketThread) } else if self.plugin_process.try_wait().unwrap().is_some() { Some(PluginStoppedReason::PluginProcess) } else { None }
+        if self.socket_task.as_ref().is_some_and(|t| t.is_finished()) {
+            let result = self.runtime.block_on(self.socket_task.take().unwrap());
+            Some(PluginStoppedReason::Connection(result.ok().and_then(|r| r.err())))
+        } else {
+            self.plugin_process.as_ref().and_then(|p| exit_status(p).unwrap()).map(PluginStoppedReason::PluginProcess)
+        }
+    }
+
+    // bind the listener, start the subprocess and spawn the socket task
+    // the listener is bound before anything else is started, so an address that is already in use
+    // is reported to the caller and a port of 0 is resolved before the plugin needs it
+    // in the listen mode the same happens without starting a process, in the connect mode
+    // there is no listener either and the task connects to the plugin at address instead
+    // it has to be called within the tokio runtime of the core, which runs the socket tasks
+    // every connection has to authenticate with the plugin's token (see auth), which is passed
+    // to a spawned plugin and written to <runtime_dir>/<kind>-<name>.token in the listen mode,
+    // except for legacy plugins, which have no token
+    pub fn new(cfg: &Config, kind: PluginKind, name: &String, plugin: &PluginConfig, port: i32, handler: Box<dyn Handler>) -> io::Result<Plugin> {
+        let address = cfg.plugin_address(kind, name, plugin, port);
+        let runtime = Handle::current();
+        let push_credits = if kind == PluginKind::Input { plugin.push_credits } else { 0 };
+        let offer = Offer { compression: plugin.compression.clone(), shm: plugin.shm, shm_dir: cfg.shm_dir(), push_credits };
+        let connected = Arc::new(AtomicBool::new(false));
+        let task = SocketTask { name: name.clone(), kind, limits: plugin.limits, offer, record_dir: plugin.record_dir.clone(), legacy: plugin.legacy, handler, connected: connected.clone() };
+
+        if plugin.mode == PluginMode::Connect {
+            info!("connecting to plugin {:?} at {}", name, address);
+
+            let socket_task = runtime.spawn(connect(task, address, plugin.token.clone()));
+            return Ok(Plugin { socket_task: Some(socket_task), runtime, connected, plugin_process: None, output: None, started: Instant::now(), legacy: plugin.legacy });
+        }
+
+        let token = (!plugin.legacy).then(|| plugin.token.clone().unwrap_or_else(auth::generate_token));
+        if let (PluginMode::Listen, Some(token), None) = (plugin.mode, &token, &plugin.token) {
+            let token_file = cfg.runtime_dir().join(format!("{}-{}.token", kind, name));
+            auth::write_token_file(&token_file, token)
+                .map_err(|e| io::Error::new(e.kind(), format!("writing the token file {:?} for plugin {:?} failed: {}", token_file, name, e)))?;
+            info!("the token of plugin {:?} is in {:?}", name, token_file);
+        }
+
+        let listener = Listener::bind(&address)
+            .map_err(|e| io::Error::new(e.kind(), format!("binding {} for plugin {:?} failed: {}", address, name, e)))?;
+        let local_addr = listener.local_addr()?;
+
+        if plugin.mode == PluginMode::Listen {
+            info!("waiting for plugin {:?} to connect to {}", name, local_addr);
+            let socket_task = runtime.spawn(listen(task, listener, token));
+            return Ok(Plugin { socket_task: Some(socket_task), runtime, connected, plugin_process: None, output: None, started: Instant::now(), legacy: plugin.legacy });
+        }
+
+        info!("starting plugin {:?} using {}", name, local_addr);
+
+        let output = PluginOutput::open(name, plugin)
+            .map_err(|e| io::Error::new(e.kind(), format!("opening the log file of plugin {:?} failed: {}", name, e)))?;
+
+        let mut cmd = Command::try_from(plugin).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        match &local_addr {
+            Address::Tcp(addr) => {
+                let addr: SocketAddr = addr.parse().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                cmd.env("PALLEON_HOST", addr.ip().to_string());
+                cmd.env("PALLEON_PORT", addr.port().to_string());
+            }
+            Address::Unix(path) => { cmd.env("PALLEON_SOCKET", path); }
+        }
+        if let Some(token) = &token {
+            cmd.env("PALLEON_TOKEN", token);
+        }
+        // the plugin gets its own process group, so everything it starts can be stopped together with it
+        cmd.process_group(0);
+        cmd.stdout(Stdio::piped());
+        cmd.stderr(Stdio::piped());
+        // nothing may fail after the process started, there would be nobody to stop it
+        let mut child = cmd.spawn()?;
+        output.capture(&mut child);
+
+        // the socket task is spawned last, an error before drops the listener, which frees the
+        // address for the next attempt (the plugin may connect before the task accepts, the
+        // connection waits in the backlog of the listener)
+        let socket_task = runtime.spawn(listen(task, listener, token));
+
+        Ok(Plugin { socket_task: Some(socket_task), runtime, connected, plugin_process: Some(child), output: Some(output), started: Instant::now(), legacy: plugin.legacy })
     }
 
-    // create the thread and the start the subprocess
-    pub fn new(name: &String, bind_addr: &String, bind_port: i32, plugin: &PluginConfig, handler: Box<dyn Handler>) -> Plugin {
-        let bind_str = format!("{}:{}", bind_addr, bind_port);
+    // intentionally stop the plugin, i.e. stop the process and end the socket task
+    // the channels of the handler have to be dropped before, which makes the handler tell the
+    // plugin to shut down (and prevents it from blocking forever on them)
+    // the plugin gets grace_period to exit on its own, then it is sent a SIGTERM and after
+    // another grace_period a SIGKILL, all signals are sent to the whole process group
+    // a legacy plugin gets the SIGTERM right away, there is no shutdown message in its protocol
+    // the process is only reaped at the very end, until then its pid (the id of its process
+    // group) can't be reused, so the signals can't hit anybody else
+    pub fn stop(mut self, grace_period: Duration) -> StopOutcome {
+        // the socket task asks the plugin to shut down as soon as its channels are closed and then
+        // ends without an error, possibly before getting here
+        // without a socket task (or if it failed) there is nobody who could have told the plugin
+        // to shut down (without a process the plugin is not ours to stop)
+        let asked_to_shut_down = match self.socket_task.take() {
+            Some(socket_task) if socket_task.is_finished() => matches!(self.runtime.block_on(socket_task), Ok(Ok(()))),
+            socket_task => {
+                self.socket_task = socket_task;
+                self.socket_task.is_some()
+            }
+        };
+        let outcome = if self.plugin_process.is_none() || (asked_to_shut_down && !self.legacy && self.wait_for_exit(grace_period)) {
+            StopOutcome::Exited
+        } else {
+            self.signal_process_group(libc::SIGTERM);
+            if self.wait_for_exit(grace_period) {
+                StopOutcome::Terminated
+            } else {
+                self.signal_process_group(libc::SIGKILL);
+                StopOutcome::Killed
+            }
+        };
+
+        // whatever the plugin started and left behind
+        self.signal_process_group(libc::SIGKILL);
+        if let Some(process) = &mut self.plugin_process {
+            let _ = process.wait();
+        }
+
+        // ending the socket task closes the connection and the listener, it is waited for so the
+        // address can be bound again right away
+        if let Some(socket_task) = self.socket_task.take() {
+            socket_task.abort();
+            let _ = self.runtime.block_on(socket_task);
+        }
+
+        outcome
+    }
 
-        info!("starting plugin {:?} using {}:{}", name, bind_addr, bind_port);
+    // true if the process exited within the timeout (or there is none), it is not reaped
+    fn wait_for_exit(&mut self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
 
-        let name = name.clone();
-        let socket_thread = thread::spawn(move || {
-            let listener = TcpListener::bind(&bind_str).expect("binding failed");
+        let Some(process) = &self.plugin_process else { return true; };
 
-            for stream in listener.incoming() {
-                let stream = stream.expect("opening the tcp stream failed");
-                handler.handle(&name, WrappedStream::new(stream));
+        loop {
+            match exit_status(process) {
+                Ok(None) if Instant::now() < deadline => thread::sleep(Duration::from_millis(20)),
+                Ok(None) => return false,
+                // an error means there is nothing left to wait for
+                Ok(Some(_)) | Err(_) => return true,
             }
+        }
+    }
+
+    fn signal_process_group(&self, signal: libc::c_int) {
+        let Some(process) = &self.plugin_process else { return; };
+
+        // the process group id is the pid of the plugin process, see process_group(0)
+        // a negative pid addresses the whole group
+        unsafe { libc::kill(-(process.id() as libc::pid_t), signal); }
+    }
+}
+
+// the exit status of the process if it exited, like Child::try_wait, but the process is left a
+// zombie (WNOWAIT), see Plugin::stop
+fn exit_status(process: &Child) -> io::Result<Option<ExitStatus>> {
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    if unsafe { libc::waitid(libc::P_PID, process.id(), &mut info, libc::WEXITED | libc::WNOHANG | libc::WNOWAIT) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // still running
+    if unsafe { info.si_pid() } == 0 {
+        return Ok(None);
+    }
+
+    // the status as waitpid reports it
+    let status = unsafe { info.si_status() };
+    let raw = match info.si_code {
+        libc::CLD_EXITED => (status & 0xff) << 8,
+        libc::CLD_DUMPED => status | 0x80,
+        _ => status,
+    };
+    Ok(Some(ExitStatus::from_raw(raw)))
+}
+
+// what the socket task of every mode works with
+struct SocketTask {
+    name: String,
+    kind: PluginKind,
+    limits: FrameLimits,
+    offer: Offer,
+    record_dir: Option<PathBuf>,
+    legacy: bool,
+    handler: Box<dyn Handler>,
+    connected: Arc<AtomicBool>,
+}
+
+// marks the plugin as connected while the handler runs, until it returns or the task is aborted
+struct Connected(Arc<AtomicBool>);
+
+impl Connected {
+    fn new(connected: &Arc<AtomicBool>) -> Self {
+        connected.store(true, Ordering::SeqCst);
+        Connected(connected.clone())
+    }
+}
+
+impl Drop for Connected {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+// records the connection after the handshake (so the token is not part of the recording)
+// a recording that can't be created is only logged
+fn start_recording(stream: &mut WrappedStream, dir: &Path, kind: PluginKind, name: &str) {
+    match Recorder::create(dir, kind, name) {
+        Ok(recorder) => {
+            info!("recording the connection of plugin {:?} to {:?}", name, recorder.path());
+            stream.record(recorder);
+        }
+        Err(e) => warn!("creating a recording for plugin {:?} in {:?} failed: {}", name, dir, e),
+    }
+}
+
+// what the plugin's config allows the core to accept in the hello
+struct Offer {
+    compression: Vec<Compression>,
+    shm: Option<ShmConfig>,
+    shm_dir: PathBuf,
+    // 0 declines the push mode, always for data plugins
+    push_credits: u32,
+}
+
+// the socket task of the spawn and listen mode, it handles exactly one connection: the first one
+// that gets through the handshake and the hello
+// every accepted connection authenticates and says hello in its own task, so a peer that never
+// sends anything can't hold up the plugin, once one got through the ones still in the handshake
+// are closed and no further connections are accepted (they wait in the backlog until the task
+// ends and the listener is closed with it)
+// connections that fail to authenticate or to say hello are logged and closed, the task ends with
+// the handler, i.e. once the plugin was asked to shut down or with the error of the connection
+// (which is reported to the supervisor, its restart policy decides whether there is a new listener)
+// without a token (a legacy plugin) the connections don't authenticate
+async fn listen(task: SocketTask, listener: Listener, token: Option<String>) -> Result<(), HandlerError> {
+    let SocketTask { name, kind, limits, offer, record_dir, legacy, handler, connected } = task;
+    let offer = Arc::new(offer);
+    let token = token.map(Arc::new);
+    let mut pending = JoinSet::new();
+
+    let (stream, session, shm) = loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let stream = WrappedStream::with_limits(accepted.map_err(ProtocolError::from).context("accepting a connection")?, limits);
+                pending.spawn(establish(stream, name.clone(), kind, token.clone(), offer.clone(), record_dir.clone(), legacy));
+            }
+            Some(established) = pending.join_next() => {
+                if let Ok(Some(established)) = established {
+                    break established;
+                }
+            }
+        }
+    };
+    pending.abort_all();
+
+    let codec = Codec::new(stream, kind, &name, &session, shm);
+    let _connected = Connected::new(&connected);
+    // Ok means the plugin was asked to shut down
+    handler.handle(&name, codec, session).await.inspect_err(|e| wrapped_stream::count_rejected_frame(&name, &e.error))
+}
+
+// authenticates an accepted connection and says hello, None if either failed (which is logged)
+async fn establish(mut stream: WrappedStream, name: String, kind: PluginKind, token: Option<Arc<String>>, offer: Arc<Offer>, record_dir: Option<PathBuf>, legacy: bool) -> Option<(WrappedStream, Session, Option<ShmRing>)> {
+    if let Some(token) = &token {
+        if let Err(e) = auth::accept(&mut stream, token).await {
+            if let auth::AuthError::Protocol(e) = &e {
+                wrapped_stream::count_rejected_frame(&name, e);
+            }
+            warn!("rejected a connection for plugin {:?}: {}", name, e);
+            return None;
+        }
+    }
+    if let Some(dir) = &record_dir {
+        start_recording(&mut stream, dir, kind, &name);
+    }
+
+    match hello(kind, &name, &mut stream, &offer, legacy).await {
+        Ok((session, shm)) => Some((stream, session, shm)),
+        Err(e) => {
+            wrapped_stream::count_rejected_frame(&name, &e);
+            warn!("the hello of plugin {:?} failed, closing the connection: {}", name, e);
+            None
+        }
+    }
+}
+
+// the socket task of the connect mode, it connects to the plugin until it is stopped
+// a plugin that is not reachable (yet) is retried every second
+// with a token the core authenticates itself, a plugin that does not accept it is retried as well
+// an error of the handler ends the task, like in the listen mode
+async fn connect(task: SocketTask, addr: Address, token: Option<String>) -> Result<(), HandlerError> {
+    const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+    let SocketTask { name, kind, limits, offer, record_dir, legacy, handler, connected } = task;
+    let mut reported = false;
+
+    loop {
+        let stream = match addr.connect(RETRY_INTERVAL).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                // only the first failure is a warning, the plugin might just not be started yet
+                if !reported {
+                    warn!("connecting to plugin {:?} at {} failed, retrying: {}", name, addr, e);
+                    reported = true;
+                }
+                tokio::time::sleep(RETRY_INTERVAL).await;
+                continue;
+            }
+        };
+
+        let mut stream = WrappedStream::with_limits(stream, limits);
+        if let Some(token) = &token {
+            if let Err(e) = auth::present(&mut stream, token).await {
+                if let auth::AuthError::Protocol(e) = &e {
+                    wrapped_stream::count_rejected_frame(&name, e);
+                }
+                warn!("authenticating at plugin {:?} failed, retrying: {}", name, e);
+                tokio::time::sleep(RETRY_INTERVAL).await;
+                continue;
+            }
+        }
+
+        info!("connected to plugin {:?} at {}", name, addr);
+        reported = false;
+        if let Some(dir) = &record_dir {
+            start_recording(&mut stream, dir, kind, &name);
+        }
+
+        let (session, shm) = match hello(kind, &name, &mut stream, &offer, legacy).await {
+            Ok(negotiated) => negotiated,
+            Err(e) => {
+                wrapped_stream::count_rejected_frame(&name, &e);
+                warn!("the hello of plugin {:?} failed, retrying: {}", name, e);
+                tokio::time::sleep(RETRY_INTERVAL).await;
+                continue;
+            }
+        };
+
+        let codec = Codec::new(stream, kind, &name, &session, shm);
+        let _connected = Connected::new(&connected);
+        return handler.handle(&name, codec, session).await.inspect_err(|e| wrapped_stream::count_rejected_frame(&name, &e.error));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::{LogFileConfig, PluginTransport};
+
+    struct NoHandler;
+
+    #[async_trait]
+    impl Handler for NoHandler {
+        async fn handle(&self, _: &str, _: Codec, _: Session) -> Result<(), HandlerError> {
+            Ok(())
+        }
+    }
+
+    // tells the test the protocol version of a plugin that connected
+    struct Notify(flume::Sender<i32>);
+
+    #[async_trait]
+    impl Handler for Notify {
+        async fn handle(&self, _: &str, _: Codec, session: Session) -> Result<(), HandlerError> {
+            let _ = self.0.send_async(session.protocol_version).await;
+            Ok(())
+        }
+    }
+
+    fn config() -> Config {
+        toml::from_str("bind_addr = \"127.0.0.1\"\nbind_port_gui = 0\n[input]\n[data]\n").unwrap()
+    }
+
+    fn free_port() -> i32 {
+        std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port() as i32
+    }
+
+    #[tokio::test]
+    async fn a_plugin_that_fails_to_start_frees_its_port() {
+        let cfg = config();
+        let port = free_port();
+        let plugin = PluginConfig { transport: PluginTransport::Tcp, command: vec!["/palleon/no/such/program".to_string()], working_directory: "/".to_string(), ..PluginConfig::default() };
+
+        let started = Plugin::new(&cfg, PluginKind::Input, &"camera".to_string(), &plugin, port, Box::new(NoHandler));
+        assert!(started.is_err());
+
+        std::net::TcpListener::bind(format!("127.0.0.1:{}", port)).expect("the port is still in use");
+    }
+
+    #[tokio::test]
+    async fn a_plugin_whose_log_file_fails_to_open_is_not_started() {
+        let cfg = config();
+        let started_file = std::env::temp_dir().join(format!("palleon-plugin-test-{}.started", std::process::id()));
+        let plugin = PluginConfig {
+            command: vec!["sh".to_string(), "-c".to_string(), format!("touch {:?}", started_file)],
+            working_directory: "/".to_string(),
+            log_file: Some(LogFileConfig { path: PathBuf::from("/palleon/no/such/dir/camera.log"), max_bytes: 1024, max_files: 1 }),
+            ..PluginConfig::default()
+        };
+
+        let started = Plugin::new(&cfg, PluginKind::Input, &"camera".to_string(), &plugin, free_port(), Box::new(NoHandler));
+        assert!(started.is_err());
+
+        // a process that was started anyway had more than enough time to get that far
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(!started_file.exists(), "the plugin was started");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_legacy_plugin_is_terminated_without_waiting_for_it() {
+        let cfg = config();
+        let plugin = PluginConfig { legacy: true, transport: PluginTransport::Tcp, command: vec!["sleep".to_string(), "30".to_string()], working_directory: "/".to_string(), ..PluginConfig::default() };
+        let plugin = Plugin::new(&cfg, PluginKind::Input, &"camera".to_string(), &plugin, free_port(), Box::new(NoHandler)).unwrap();
+
+        let stopping = Instant::now();
+        let outcome = tokio::task::spawn_blocking(move || plugin.stop(Duration::from_secs(5))).await.unwrap();
+        assert_eq!(outcome, StopOutcome::Terminated);
+        assert!(stopping.elapsed() < Duration::from_secs(5), "{:?}", stopping.elapsed());
+    }
+
+    #[test]
+    fn an_exited_process_is_left_for_stop_to_reap() {
+        let mut process = Command::new("sh").args(["-c", "exit 3"]).spawn().unwrap();
+        let status = loop {
+            if let Some(status) = exit_status(&process).unwrap() {
+                break status;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(status.code(), Some(3));
+
+        // the pid still belongs to the zombie
+        assert_eq!(unsafe { libc::kill(process.id() as libc::pid_t, 0) }, 0);
+        assert_eq!(process.wait().unwrap().code(), Some(3));
+    }
+
+    #[tokio::test]
+    async fn a_silent_connection_does_not_hold_up_the_plugin() {
+        let cfg = config();
+        let port = free_port();
+        let plugin = PluginConfig { mode: PluginMode::Listen, transport: PluginTransport::Tcp, token: Some("secret".to_string()), ..PluginConfig::default() };
+        let (connected_tx, connected_rx) = flume::bounded(1);
+        let _plugin = Plugin::new(&cfg, PluginKind::Data, &"activity".to_string(), &plugin, port, Box::new(Notify(connected_tx))).unwrap();
+
+        let addr = format!("127.0.0.1:{}", port);
+        let _silent = tokio::net::TcpStream::connect(&addr).await.unwrap();
+
+        let mut stream = WrappedStream::new(tokio::net::TcpStream::connect(&addr).await.unwrap());
+        auth::present(&mut stream, "secret").await.unwrap();
+        stream.send_bson(&doc! { "protocol_version": 2, "name": "activity", "version": "test", "capabilities": {} }).await.unwrap();
+
+        // well before the silent connection's handshake times out
+        let connected = tokio::time::timeout(auth::HANDSHAKE_TIMEOUT / 2, connected_rx.recv_async()).await;
+        assert!(connected.is_ok(), "the plugin was not handled while the silent connection was authenticating");
+    }
 
-            drop(listener);
-        });
+    #[tokio::test]
+    async fn a_legacy_input_plugin_is_asked_for_frames_right_away() {
+        let cfg = config();
+        let port = free_port();
+        let plugin = PluginConfig { mode: PluginMode::Listen, transport: PluginTransport::Tcp, legacy: true, ..PluginConfig::default() };
+        let (connected_tx, connected_rx) = flume::bounded(1);
+        let _plugin = Plugin::new(&cfg, PluginKind::Input, &"camera".to_string(), &plugin, port, Box::new(Notify(connected_tx))).unwrap();
 
-        let mut cmd = Command::try_from(plugin).unwrap();
-        cmd.env("PALLEON_HOST", bind_addr);
-        cmd.env("PALLEON_PORT", bind_port.to_string());
-        let child = cmd.spawn().expect("starting the data plugin failed");
+        // neither a token nor a hello
+        let _stream = tokio::net::TcpStream::connect(format!("127.0.0.1:{}", port)).await.unwrap();
 
-        Plugin { socket_thread, plugin_process: child }
+        let version = tokio::time::timeout(Duration::from_millis(500), connected_rx.recv_async()).await;
+        assert_eq!(version.ok().and_then(Result::ok), Some(0));
     }
 }
@@ -0,0 +1,144 @@
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+use std::process::Child;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use log::{Level, log};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use crate::config::{LogFileConfig, PluginConfig};
+
+// captures stdout and stderr of a plugin process
+// every line is
+// - forwarded to the log, with the name of the plugin as target (stdout as info, stderr as warning)
+// - written to the plugin's log file (if configured)
+// - kept in memory, the last output_tail_lines lines are available for crash reports
+
+#[derive(Clone)]
+pub struct PluginOutput {
+    sink: OutputSink,
+}
+
+impl PluginOutput {
+    // opens the log file (if configured), which happens before the plugin is started, so a log
+    // file that can't be opened doesn't leave a running plugin behind
+    pub fn open(name: &str, plugin: &PluginConfig) -> io::Result<PluginOutput> {
+        let log_file = match &plugin.log_file {
+            None => None,
+            Some(cfg) => Some(Arc::new(Mutex::new(RotatingFile::open(cfg)?))),
+        };
+        let tail = Arc::new(Mutex::new(VecDeque::with_capacity(plugin.output_tail_lines)));
+
+        Ok(PluginOutput { sink: OutputSink { name: name.to_string(), tail, tail_lines: plugin.output_tail_lines, log_file } })
+    }
+
+    // the child has to be spawned with piped stdout and stderr
+    pub fn capture(&self, child: &mut Child) {
+        if let Some(stdout) = child.stdout.take() {
+            self.sink.forward("stdout", Level::Info, stdout);
+        }
+        if let Some(stderr) = child.stderr.take() {
+            self.sink.forward("stderr", Level::Warn, stderr);
+        }
+    }
+
+    // the last lines the plugin wrote, oldest first
+    pub fn tail(&self) -> Vec<String> {
+        self.sink.tail.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+// everything a thread reading one of the streams needs
+
+#[derive(Clone)]
+struct OutputSink {
+    name: String,
+    tail: Arc<Mutex<VecDeque<String>>>,
+    tail_lines: usize,
+    log_file: Option<Arc<Mutex<RotatingFile>>>,
+}
+
+impl OutputSink {
+    // reads the stream line by line in its own thread until the plugin closes it
+    fn forward(&self, stream_name: &'static str, level: Level, stream: impl Read + Send + 'static) {
+        let sink = self.clone();
+
+        thread::spawn(move || {
+            for line in BufReader::new(stream).split(b'\n') {
+                let Ok(line) = line else { break; };
+                let line = String::from_utf8_lossy(&line);
+                let line = line.trim_end_matches('\r');
+
+                log!(target: &sink.name, level, "{}", line);
+
+                if let Some(log_file) = &sink.log_file {
+                    if let Err(e) = log_file.lock().unwrap().write_line(stream_name, line) {
+                        log!(target: &sink.name, Level::Error, "writing the log file failed: {}", e);
+                    }
+                }
+
+                let mut tail = sink.tail.lock().unwrap();
+                if tail.len() >= sink.tail_lines {
+                    tail.pop_front();
+                }
+                if sink.tail_lines > 0 {
+                    tail.push_back(format!("[{}] {}", stream_name, line));
+                }
+            }
+        });
+    }
+}
+
+struct RotatingFile {
+    cfg: LogFileConfig,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(cfg: &LogFileConfig) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&cfg.path)?;
+        let size = file.metadata()?.len();
+
+        Ok(RotatingFile { cfg: cfg.clone(), file, size })
+    }
+
+    fn write_line(&mut self, stream_name: &str, line: &str) -> io::Result<()> {
+        let timestamp = OffsetDateTime::now_utc().format(&Rfc3339).unwrap_or_default();
+        let line = format!("{} [{}] {}\n", timestamp, stream_name, line);
+
+        if self.size > 0 && self.size + line.len() as u64 > self.cfg.max_bytes {
+            self.rotate()?;
+        }
+
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    // path.<max_files - 1> -> path.<max_files>, ..., path -> path.1
+    fn rotate(&mut self) -> io::Result<()> {
+        let numbered = |i: usize| {
+            let mut path = self.cfg.path.clone().into_os_string();
+            path.push(format!(".{}", i));
+            PathBuf::from(path)
+        };
+
+        for i in (1..self.cfg.max_files).rev() {
+            if numbered(i).exists() {
+                fs::rename(numbered(i), numbered(i + 1))?;
+            }
+        }
+        if self.cfg.max_files > 0 {
+            fs::rename(&self.cfg.path, numbered(1))?;
+        }
+
+        self.file = OpenOptions::new().create(true).write(true).truncate(true).open(&self.cfg.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
@@ -0,0 +1,604 @@
+use std::borrow::Cow;
+use std::io;
+use std::time::Duration;
+
+use bson::{Bson, doc, Document};
+use bson::spec::BinarySubtype;
+use bytes::Bytes;
+use log::{Level, log};
+use serde::Serialize;
+
+use crate::config::PluginKind;
+use crate::image::{FrameMetadata, Image};
+use crate::plugin::Session;
+use crate::shm::ShmRing;
+use crate::wrapped_stream::{self, MessageType, ProtocolError, WrappedStream};
+
+// the messages between the core and the plugins (and the gui)
+// from protocol version 2 on every message is one frame holding a bson document with the name
+// of the message in "type", e.g. {"type": "frame", "data": <binary>}, see Message::to_document
+// messages with images are encoded straight from the shared data of the images (see encode),
+// so the only copy of a frame is the one into the buffer that is sent
+// plugins of version 0 and 1 speak the implicit protocol of before, Codec translates the
+// messages from and to it (see LegacyCodec), so the handlers only deal with messages
+
+// the first protocol version with typed messages
+pub const TYPED_MESSAGES_VERSION: i32 = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    // core -> input plugin: send the next frame
+    RequestFrame,
+    // input plugin -> core: there is no frame at the moment, optionally with the time after which
+    // the plugin expects to have one again
+    NoFrame { retry_after: Option<Duration> },
+    // input plugin -> core: a frame, with what the plugin tells about it in "metadata" (optional)
+    Frame { data: Vec<u8>, metadata: FrameMetadata },
+    // core -> input plugin in the push mode: the plugin may send that many more frames
+    Credit { frames: u32 },
+    // core -> data plugin: the last values of the dependencies of the plugin for the next image,
+    // {"<plugin name>": [[<timestamp>, <value>], ..], ..}
+    DependencyData(Document),
+    // core -> data plugin: an image, {"data": <binary>, "input_source": .., "timestamp": .., "arrival_time": .., "metadata": {..}}
+    // (without "metadata" if the input plugin sent none)
+    Image { image: Image, data: ImageData },
+    // data plugin -> core: what the plugin made of the image
+    Result(Document),
+    // either side: something went wrong and the connection is closed
+    Error { message: String },
+    // either side: the other side has to answer with a Pong
+    Ping,
+    Pong,
+    // core -> plugin: exit
+    Shutdown,
+    // plugin -> core: a line for the log of the core, level is one of error, warn, info, debug, trace
+    Log { level: String, message: String },
+    // core -> gui: the images and the data since the last update
+    Update { images: Vec<Image>, data: Vec<Bson> },
+    // core -> gui: the names of the input plugins that have been without a frame for their
+    // idle.warn_after_secs, every second
+    Status { idle: Vec<String> },
+}
+
+// how the data of an image is sent
+#[derive(Debug, Clone, PartialEq)]
+pub enum ImageData {
+    Inline,
+    // a reference to the slot of the shared memory it is in, see shm
+    Shm(Document),
+    // the plugin does not want the image, only its input_source and timestamp
+    Omitted,
+}
+
+// the document of an image, borrowing the data
+#[derive(Serialize)]
+struct ImageDocument<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<&'a Bytes>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shm: Option<&'a Document>,
+    input_source: &'a str,
+    timestamp: bson::DateTime,
+    arrival_time: bson::DateTime,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<Document>,
+}
+
+impl<'a> ImageDocument<'a> {
+    fn new(image: &'a Image, data: &'a ImageData) -> Self {
+        ImageDocument {
+            data: matches!(data, ImageData::Inline).then_some(&image.data),
+            shm: match data {
+                ImageData::Shm(reference) => Some(reference),
+                _ => None,
+            },
+            input_source: &image.input_source,
+            timestamp: bson::DateTime::from_system_time(image.timestamp),
+            arrival_time: bson::DateTime::from_system_time(image.arrival_time),
+            metadata: (!image.metadata.is_empty()).then(|| image.metadata.to_document()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ImageMessage<'a> {
+    #[serde(rename = "type")]
+    name: &'static str,
+    image: ImageDocument<'a>,
+}
+
+#[derive(Serialize)]
+struct UpdateMessage<'a> {
+    #[serde(rename = "type")]
+    name: &'static str,
+    images: Vec<ImageDocument<'a>>,
+    data: &'a [Bson],
+}
+
+impl Message {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Message::RequestFrame => "request_frame",
+            Message::NoFrame { .. } => "no_frame",
+            Message::Frame { .. } => "frame",
+            Message::Credit { .. } => "credit",
+            Message::DependencyData(_) => "dependency_data",
+            Message::Image { .. } => "image",
+            Message::Result(_) => "result",
+            Message::Error { .. } => "error",
+            Message::Ping => "ping",
+            Message::Pong => "pong",
+            Message::Shutdown => "shutdown",
+            Message::Log { .. } => "log",
+            Message::Update { .. } => "update",
+            Message::Status { .. } => "status",
+        }
+    }
+
+    pub fn to_document(&self) -> Document {
+        let mut doc = doc! { "type": self.name() };
+
+        match self {
+            Message::RequestFrame | Message::NoFrame { retry_after: None } | Message::Ping | Message::Pong | Message::Shutdown => {}
+            Message::NoFrame { retry_after: Some(retry_after) } => {
+                doc.insert("retry_after_ms", i64::try_from(retry_after.as_millis()).unwrap_or(i64::MAX));
+            }
+            Message::Frame { data, metadata } => {
+                doc.insert("data", Bson::Binary(bson::Binary { subtype: BinarySubtype::Generic, bytes: data.clone() }));
+                if !metadata.is_empty() {
+                    doc.insert("metadata", metadata.to_document());
+                }
+            }
+            Message::Credit { frames } => { doc.insert("frames", *frames as i32); }
+            Message::DependencyData(data) => { doc.insert("data", data.clone()); }
+            Message::Image { image, data } => {
+                doc.insert("image", bson::to_document(&ImageDocument::new(image, data)).expect("an image is always a valid document"));
+            }
+            Message::Result(result) => { doc.insert("result", result.clone()); }
+            Message::Error { message } => { doc.insert("message", message); }
+            Message::Log { level, message } => {
+                doc.insert("level", level);
+                doc.insert("message", message);
+            }
+            Message::Update { images, data } => {
+                let images: Vec<Bson> = images.iter()
+                    .map(|image| Bson::Document(bson::to_document(&ImageDocument::new(image, &ImageData::Inline)).expect("an image is always a valid document")))
+                    .collect();
+                doc.insert("images", images);
+                doc.insert("data", data.clone());
+            }
+            Message::Status { idle } => { doc.insert("idle", idle.clone()); }
+        }
+
+        doc
+    }
+
+    // the bson of the message, like to_document, but the images are written straight from
+    // their data instead of being copied into a document first
+    pub fn encode(&self) -> Result<Vec<u8>, ProtocolError> {
+        let encoded = match self {
+            Message::Image { image, data } => bson::to_vec(&ImageMessage { name: self.name(), image: ImageDocument::new(image, data) }),
+            Message::Update { images, data } => bson::to_vec(&UpdateMessage {
+                name: self.name(),
+                images: images.iter().map(|image| ImageDocument::new(image, &ImageData::Inline)).collect(),
+                data,
+            }),
+            message => {
+                let mut buffer = Vec::new();
+                message.to_document().to_writer(&mut buffer).map(|_| buffer)
+            }
+        };
+
+        encoded.map_err(|e| ProtocolError::Io(io::Error::other(e)))
+    }
+
+    pub fn from_document(mut doc: Document) -> Result<Message, ProtocolError> {
+        let name = doc.get_str("type").map_err(|_| ProtocolError::UnexpectedMessage("a message without a 'type'".to_string()))?.to_string();
+        let missing = |field: &str| ProtocolError::UnexpectedMessage(format!("the message '{}' has no valid '{}'", name, field));
+
+        let message = match name.as_str() {
+            "request_frame" => Message::RequestFrame,
+            "no_frame" => Message::NoFrame {
+                retry_after: match doc.get("retry_after_ms") {
+                    None => None,
+                    Some(ms) => Some(millis(ms).ok_or_else(|| missing("retry_after_ms"))?),
+                },
+            },
+            "frame" => {
+                let metadata = metadata(&doc).map_err(|field| missing(&field))?;
+                match doc.remove("data") {
+                    Some(Bson::Binary(binary)) => Message::Frame { data: binary.bytes, metadata },
+                    _ => return Err(missing("data")),
+                }
+            }
+            "credit" => Message::Credit {
+                frames: doc.get_i32("frames").ok().and_then(|n| u32::try_from(n).ok()).ok_or_else(|| missing("frames"))?,
+            },
+            "dependency_data" => Message::DependencyData(take_document(&mut doc, "data").ok_or_else(|| missing("data"))?),
+            "image" => {
+                let (image, data) = take_document(&mut doc, "image").and_then(image_from_document).ok_or_else(|| missing("image"))?;
+                Message::Image { image, data }
+            }
+            "result" => Message::Result(take_document(&mut doc, "result").ok_or_else(|| missing("result"))?),
+            "error" => Message::Error { message: doc.get_str("message").map_err(|_| missing("message"))?.to_string() },
+            "ping" => Message::Ping,
+            "pong" => Message::Pong,
+            "shutdown" => Message::Shutdown,
+            "log" => Message::Log {
+                level: doc.get_str("level").map_err(|_| missing("level"))?.to_string(),
+                message: doc.get_str("message").map_err(|_| missing("message"))?.to_string(),
+            },
+            "update" => Message::Update {
+                images: doc.get_array("images").map_err(|_| missing("images"))?.iter()
+                    .map(|image| image.as_document().cloned().and_then(image_from_document).map(|(image, _)| image))
+                    .collect::<Option<_>>()
+                    .ok_or_else(|| missing("images"))?,
+                data: doc.get_array("data").map_err(|_| missing("data"))?.clone(),
+            },
+            "status" => Message::Status {
+                idle: doc.get_array("idle").map_err(|_| missing("idle"))?.iter()
+                    .map(|name| name.as_str().map(str::to_string))
+                    .collect::<Option<_>>()
+                    .ok_or_else(|| missing("idle"))?,
+            },
+            _ => return Err(ProtocolError::UnexpectedMessage(format!("unknown message type '{}'", name))),
+        };
+
+        Ok(message)
+    }
+}
+
+// the optional "metadata" of a frame or an image, the invalid field if it fails
+fn metadata(doc: &Document) -> Result<FrameMetadata, String> {
+    match doc.get("metadata") {
+        None => Ok(FrameMetadata::default()),
+        Some(Bson::Document(metadata)) => FrameMetadata::from_document(metadata).map_err(|field| format!("metadata.{}", field)),
+        Some(_) => Err("metadata".to_string()),
+    }
+}
+
+// a number of milliseconds, 32 or 64 bit depending on the plugin's bson library
+fn millis(value: &Bson) -> Option<Duration> {
+    let ms = match value {
+        Bson::Int32(ms) => i64::from(*ms),
+        Bson::Int64(ms) => *ms,
+        _ => return None,
+    };
+    u64::try_from(ms).ok().map(Duration::from_millis)
+}
+
+fn take_document(doc: &mut Document, key: &str) -> Option<Document> {
+    match doc.remove(key) {
+        Some(Bson::Document(document)) => Some(document),
+        _ => None,
+    }
+}
+
+// the data of an image that is not inline is empty
+fn image_from_document(mut doc: Document) -> Option<(Image, ImageData)> {
+    let (bytes, data) = match (doc.remove("data"), take_document(&mut doc, "shm")) {
+        (Some(Bson::Binary(binary)), _) => (Bytes::from(binary.bytes), ImageData::Inline),
+        (Some(_), _) => return None,
+        (None, Some(reference)) => (Bytes::new(), ImageData::Shm(reference)),
+        (None, None) => (Bytes::new(), ImageData::Omitted),
+    };
+
+    let timestamp = doc.get_datetime("timestamp").ok()?.to_system_time();
+    let image = Image {
+        data: bytes,
+        timestamp,
+        // older versions of the core only sent the timestamp, which was the arrival time
+        arrival_time: doc.get_datetime("arrival_time").map(|time| time.to_system_time()).unwrap_or(timestamp),
+        input_source: doc.get_str("input_source").ok()?.to_string(),
+        metadata: metadata(&doc).ok()?,
+    };
+
+    Some((image, data))
+}
+
+// sends and receives the messages of one plugin connection, in the format of its protocol version
+// with shared memory (see shm) the data of frames and images is moved into and out of the ring here
+pub struct Codec {
+    stream: WrappedStream,
+    name: String,
+    legacy: Option<LegacyCodec>,
+    shm: Option<ShmRing>,
+}
+
+impl Codec {
+    pub fn new(stream: WrappedStream, kind: PluginKind, name: &str, session: &Session, shm: Option<ShmRing>) -> Self {
+        let legacy = (session.protocol_version < TYPED_MESSAGES_VERSION).then_some(LegacyCodec { kind, protocol_version: session.protocol_version });
+        Codec { stream, name: name.to_string(), legacy, shm }
+    }
+
+    // ping and log are not part of the protocol before version 2
+    pub fn is_legacy(&self) -> bool {
+        self.legacy.is_some()
+    }
+
+    pub async fn send(&mut self, message: &Message) -> Result<(), ProtocolError> {
+        match self.legacy {
+            Some(legacy) => legacy.send(&mut self.stream, message).await,
+            None => {
+                let message = match (message, &mut self.shm) {
+                    // the image stays inline if it does not fit into a slot
+                    (Message::Image { image, data: ImageData::Inline }, Some(shm)) => match shm.write(&image.data) {
+                        Some(reference) => Cow::Owned(Message::Image { image: image.clone(), data: ImageData::Shm(reference) }),
+                        None => Cow::Borrowed(message),
+                    },
+                    _ => Cow::Borrowed(message),
+                };
+                self.stream.send_with_32bit_integer_length(message.encode()?).await.map(|_| ())
+            }
+        }
+    }
+
+    // the next message that is not a log line or a ping (which are handled here), the size of
+    // the frame is limited according to message_type
+    // an error sent by the plugin is returned as ProtocolError::Plugin
+    pub async fn recv(&mut self, message_type: MessageType) -> Result<Message, ProtocolError> {
+        loop {
+            let message = match self.legacy {
+                Some(legacy) => legacy.recv(&mut self.stream, message_type).await?,
+                None => {
+                    let mut doc = self.stream.recv_bson(message_type).await?;
+                    if let (Some(shm), Ok(reference)) = (&self.shm, doc.get_document("shm")) {
+                        let data = shm.read(reference)?;
+                        doc.insert("data", Bson::Binary(bson::Binary { subtype: BinarySubtype::Generic, bytes: data }));
+                    }
+                    Message::from_document(doc)?
+                }
+            };
+
+            match message {
+                Message::Log { level, message } => {
+                    let level = level.parse().unwrap_or(Level::Info);
+                    log!(target: &self.name, level, "{}", message);
+                }
+                Message::Ping => self.send(&Message::Pong).await?,
+                Message::Error { message } => return Err(ProtocolError::Plugin(message)),
+                message => return Ok(message),
+            }
+        }
+    }
+
+    // sends a ping and waits up to timeout for the pong
+    pub async fn ping(&mut self, timeout: Duration) -> Result<(), ProtocolError> {
+        self.send(&Message::Ping).await?;
+
+        // a pong is tiny, the limit of the hello is plenty
+        match wrapped_stream::timeout(timeout, self.recv(MessageType::Hello)).await? {
+            Message::Pong => Ok(()),
+            message => Err(unexpected(&message, "a pong")),
+        }
+    }
+}
+
+// the error for a message that is valid, but not the one expected
+pub fn unexpected(message: &Message, expected: &str) -> ProtocolError {
+    ProtocolError::UnexpectedMessage(format!("'{}' instead of {}", message.name(), expected))
+}
+
+// the protocol of version 0 and 1 (after the hello):
+// - input plugins: the core sends b"i" (RequestFrame) or b"s" (Shutdown), the plugin answers with
+//   a 32 bit integer, 0 (NoFrame without a retry_after) or 1 followed by the frame (Frame)
+// - data plugins: the core sends the bson documents of the dependency data and the image, or
+//   {"shutdown": true}, the plugin answers with a bson document (Result)
+// there is no shutdown in version 0, a plugin that does not expect it could take it for
+// anything, so nothing is sent and it gets a SIGTERM instead (see Plugin::stop)
+#[derive(Clone, Copy)]
+struct LegacyCodec {
+    kind: PluginKind,
+    protocol_version: i32,
+}
+
+impl LegacyCodec {
+    async fn send(self, stream: &mut WrappedStream, message: &Message) -> Result<(), ProtocolError> {
+        match (self.kind, message) {
+            (_, Message::Shutdown) if self.protocol_version == 0 => Ok(()),
+            (PluginKind::Input, Message::RequestFrame) => stream.write(b"i").await,
+            (PluginKind::Input, Message::Shutdown) => stream.write(b"s").await,
+            (PluginKind::Data, Message::DependencyData(doc)) => stream.send_bson(doc).await.map(|_| ()),
+            (PluginKind::Data, Message::Image { image, data }) => {
+                let encoded = bson::to_vec(&ImageDocument::new(image, data)).map_err(|e| ProtocolError::Io(io::Error::other(e)))?;
+                stream.send_with_32bit_integer_length(encoded).await.map(|_| ())
+            }
+            (PluginKind::Data, Message::Shutdown) => stream.send_bson(&doc! { "shutdown": true }).await.map(|_| ()),
+            (_, message) => Err(ProtocolError::UnexpectedMessage(format!("'{}' is not part of the protocol of {} plugins before version {}", message.name(), self.kind, TYPED_MESSAGES_VERSION))),
+        }
+    }
+
+    async fn recv(self, stream: &mut WrappedStream, message_type: MessageType) -> Result<Message, ProtocolError> {
+        match self.kind {
+            PluginKind::Input => match stream.recv_32bit_integer().await? {
+                0 => Ok(Message::NoFrame { retry_after: None }),
+                1 => Ok(Message::Frame { data: stream.recv_based_on_32bit_integer(message_type).await?, metadata: FrameMetadata::default() }),
+                mode => Err(ProtocolError::UnexpectedMessage(format!("unknown mode {}", mode))),
+            },
+            PluginKind::Data => Ok(Message::Result(stream.recv_bson(message_type).await?)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{Seek, SeekFrom, Write};
+    use std::time::UNIX_EPOCH;
+
+    use tokio::net::UnixStream;
+
+    use super::*;
+    use crate::config::ShmConfig;
+    use crate::plugin::{Accepted, Capabilities};
+    use crate::transport::Stream;
+
+    // the times are whole ms, as bson stores them
+    fn image(data: &[u8]) -> Image {
+        let timestamp = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        let metadata = FrameMetadata { width: Some(2), encoding: Some("raw".to_string()), tags: doc! { "exposure_us": 8000_i64 }, ..FrameMetadata::default() };
+        Image { data: Bytes::copy_from_slice(data), timestamp, arrival_time: timestamp + Duration::from_millis(40), input_source: "camera".to_string(), metadata }
+    }
+
+    fn session(protocol_version: i32) -> Session {
+        Session { protocol_version, plugin_name: None, plugin_version: None, capabilities: Capabilities::default(), accepted: Accepted::default(), hello: Document::new() }
+    }
+
+    // the codec of the core and the plugin's end of the connection
+    fn connection(kind: PluginKind, protocol_version: i32, shm: Option<ShmRing>) -> (Codec, WrappedStream<UnixStream>) {
+        let (core, plugin) = UnixStream::pair().unwrap();
+        (Codec::new(WrappedStream::new(Stream::Unix(core)), kind, "plugin", &session(protocol_version), shm), WrappedStream::new(plugin))
+    }
+
+    #[test]
+    fn every_message_survives_its_document_and_its_encoding() {
+        let messages = [
+            Message::RequestFrame,
+            Message::NoFrame { retry_after: None },
+            Message::NoFrame { retry_after: Some(Duration::from_millis(250)) },
+            Message::Frame { data: vec![1, 2, 3], metadata: FrameMetadata::default() },
+            Message::Frame { data: vec![], metadata: image(&[]).metadata },
+            Message::Credit { frames: 3 },
+            Message::DependencyData(doc! { "detector": { "faces": 2_i64 } }),
+            Message::Image { image: image(&[1, 2]), data: ImageData::Inline },
+            Message::Image { image: image(&[]), data: ImageData::Shm(doc! { "slot": 1_i64, "size": 2_i64 }) },
+            Message::Image { image: image(&[]), data: ImageData::Omitted },
+            Message::Result(doc! { "faces": 2_i64 }),
+            Message::Error { message: "no camera".to_string() },
+            Message::Ping,
+            Message::Pong,
+            Message::Shutdown,
+            Message::Log { level: "warn".to_string(), message: "dark".to_string() },
+            Message::Update { images: vec![image(&[1]), image(&[2])], data: vec![Bson::Document(doc! { "faces": 2_i64 })] },
+            Message::Status { idle: vec![] },
+            Message::Status { idle: vec!["camera".to_string()] },
+        ];
+
+        for message in messages {
+            assert_eq!(Message::from_document(message.to_document()).unwrap(), message);
+            // images are encoded without going through a document, the result is the same
+            let encoded: Document = bson::from_slice(&message.encode().unwrap()).unwrap();
+            assert_eq!(encoded, message.to_document(), "{}", message.name());
+        }
+    }
+
+    #[test]
+    fn plugins_may_send_32_bit_integers() {
+        let message = Message::from_document(doc! { "type": "no_frame", "retry_after_ms": 100_i32 }).unwrap();
+        assert_eq!(message, Message::NoFrame { retry_after: Some(Duration::from_millis(100)) });
+    }
+
+    #[test]
+    fn invalid_messages_are_unexpected() {
+        for doc in [
+            doc! { "name": "frame" },
+            doc! { "type": "selfie" },
+            doc! { "type": "frame" },
+            doc! { "type": "frame", "data": "not binary" },
+            doc! { "type": "no_frame", "retry_after_ms": -1_i64 },
+            doc! { "type": "credit", "frames": -1 },
+            doc! { "type": "result", "result": 2 },
+            doc! { "type": "status", "idle": [1] },
+            doc! { "type": "image", "image": { "input_source": "camera" } },
+        ] {
+            assert!(matches!(Message::from_document(doc.clone()), Err(ProtocolError::UnexpectedMessage(_))), "{}", doc);
+        }
+    }
+
+    #[tokio::test]
+    async fn logs_and_pings_are_handled_by_the_codec() {
+        let (mut core, mut plugin) = connection(PluginKind::Input, 2, None);
+        for message in [Message::Log { level: "info".to_string(), message: "warming up".to_string() }, Message::Ping, Message::NoFrame { retry_after: None }] {
+            plugin.send_bson(&message.to_document()).await.unwrap();
+        }
+
+        assert_eq!(core.recv(MessageType::Image).await.unwrap(), Message::NoFrame { retry_after: None });
+        assert_eq!(plugin.recv_bson(MessageType::Data).await.unwrap(), doc! { "type": "pong" });
+
+        plugin.send_bson(&doc! { "type": "error", "message": "no camera" }).await.unwrap();
+        assert!(matches!(core.recv(MessageType::Image).await, Err(ProtocolError::Plugin(message)) if message == "no camera"));
+    }
+
+    #[tokio::test]
+    async fn frames_and_images_go_through_shared_memory() {
+        let path = std::env::temp_dir().join(format!("palleon-protocol-test-{}.shm", std::process::id()));
+        let ring = ShmRing::create(path.clone(), ShmConfig { slots: 2, slot_bytes: 4 }).unwrap();
+        let (mut core, mut plugin) = connection(PluginKind::Data, 2, Some(ring));
+
+        // the image goes into the first slot, where the plugin finds it
+        core.send(&Message::Image { image: image(&[1, 2, 3]), data: ImageData::Inline }).await.unwrap();
+        let received = plugin.recv_bson(MessageType::Image).await.unwrap();
+        assert_eq!(received.get_document("image").unwrap().get_document("shm").unwrap(), &doc! { "slot": 0_i64, "size": 3_i64 });
+        assert!(!received.get_document("image").unwrap().contains_key("data"));
+        assert_eq!(fs::read(&path).unwrap()[..3], [1, 2, 3]);
+
+        // too big for a slot
+        core.send(&Message::Image { image: image(&[1, 2, 3, 4, 5]), data: ImageData::Inline }).await.unwrap();
+        let received = plugin.recv_bson(MessageType::Image).await.unwrap();
+        assert_eq!(received.get_document("image").unwrap().get_binary_generic("data").unwrap(), &vec![1, 2, 3, 4, 5]);
+
+        // the plugin puts its frame into the second slot
+        let mut file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(4)).unwrap();
+        file.write_all(&[9, 8]).unwrap();
+        plugin.send_bson(&doc! { "type": "frame", "shm": { "slot": 1, "size": 2 } }).await.unwrap();
+        assert_eq!(core.recv(MessageType::Image).await.unwrap(), Message::Frame { data: vec![9, 8], metadata: FrameMetadata::default() });
+
+        plugin.send_bson(&doc! { "type": "frame", "shm": { "slot": 2, "size": 2 } }).await.unwrap();
+        assert!(matches!(core.recv(MessageType::Image).await, Err(ProtocolError::UnexpectedMessage(_))));
+    }
+
+    #[tokio::test]
+    async fn legacy_input_plugins_speak_in_bytes_and_integers() {
+        let (mut core, mut plugin) = connection(PluginKind::Input, 1, None);
+        assert!(core.is_legacy());
+
+        core.send(&Message::RequestFrame).await.unwrap();
+        core.send(&Message::Shutdown).await.unwrap();
+        assert_eq!(plugin.recv_raw(2).await.unwrap(), b"is");
+
+        plugin.write(&0u32.to_le_bytes()).await.unwrap();
+        assert_eq!(core.recv(MessageType::Image).await.unwrap(), Message::NoFrame { retry_after: None });
+
+        plugin.write(&1u32.to_le_bytes()).await.unwrap();
+        plugin.send_with_32bit_integer_length(vec![1, 2, 3]).await.unwrap();
+        assert_eq!(core.recv(MessageType::Image).await.unwrap(), Message::Frame { data: vec![1, 2, 3], metadata: FrameMetadata::default() });
+
+        plugin.write(&7u32.to_le_bytes()).await.unwrap();
+        assert!(matches!(core.recv(MessageType::Image).await, Err(ProtocolError::UnexpectedMessage(_))));
+
+        assert!(matches!(core.send(&Message::Credit { frames: 1 }).await, Err(ProtocolError::UnexpectedMessage(_))));
+    }
+
+    #[tokio::test]
+    async fn plugins_of_version_0_are_not_sent_a_shutdown() {
+        for kind in [PluginKind::Input, PluginKind::Data] {
+            let (mut core, mut plugin) = connection(kind, 0, None);
+            core.send(&Message::Shutdown).await.unwrap();
+            drop(core);
+            assert!(matches!(plugin.recv_raw(1).await, Err(ProtocolError::Disconnected)), "{}", kind);
+        }
+    }
+
+    #[tokio::test]
+    async fn legacy_data_plugins_get_bare_documents() {
+        let (mut core, mut plugin) = connection(PluginKind::Data, 1, None);
+
+        core.send(&Message::DependencyData(doc! { "detector": { "faces": 2_i64 } })).await.unwrap();
+        assert_eq!(plugin.recv_bson(MessageType::Data).await.unwrap(), doc! { "detector": { "faces": 2_i64 } });
+
+        core.send(&Message::Image { image: image(&[1]), data: ImageData::Inline }).await.unwrap();
+        let received = plugin.recv_bson(MessageType::Image).await.unwrap();
+        assert_eq!(received.get_str("input_source"), Ok("camera"));
+        assert!(!received.contains_key("type"));
+
+        core.send(&Message::Shutdown).await.unwrap();
+        assert_eq!(plugin.recv_bson(MessageType::Data).await.unwrap(), doc! { "shutdown": true });
+
+        // whatever the plugin answers is its result
+        plugin.send_bson(&doc! { "faces": 2_i64 }).await.unwrap();
+        assert_eq!(core.recv(MessageType::Data).await.unwrap(), Message::Result(doc! { "faces": 2_i64 }));
+
+        assert!(matches!(core.send(&Message::Ping).await, Err(ProtocolError::UnexpectedMessage(_))));
+    }
+}
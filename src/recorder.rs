@@ -0,0 +1,203 @@
+use std::{fs, io};
+use std::io::{BufWriter, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::warn;
+
+use crate::config::PluginKind;
+
+// records everything that goes over a plugin connection after the handshake, so a session can
+// be looked at or played back against the core later (see replay)
+// a recording starts with MAGIC, followed by one entry per message:
+// - the direction, b'<' for what the core received and b'>' for what it sent
+// - the kind, b'f' for a frame (recorded without its length, as it went over the connection,
+//   i.e. compressed if the connection is) and b'r' for the raw bytes of the protocol before
+//   version 2 (b"i" and the 32 bit integers of input plugins)
+// - the time as microseconds since the unix epoch (u64, little endian)
+// - the length of the data (u32, little endian) and the data
+
+const MAGIC: &[u8] = b"palleon recording 1\n";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Received,
+    Sent,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    Frame,
+    Raw,
+}
+
+pub struct Entry {
+    pub direction: Direction,
+    pub kind: EntryKind,
+    pub time: SystemTime,
+    pub data: Vec<u8>,
+}
+
+pub struct Recorder {
+    path: PathBuf,
+    // None after writing failed once, the connection keeps working without the recording
+    file: Option<BufWriter<fs::File>>,
+}
+
+impl Recorder {
+    // a new recording <dir>/<kind>-<name>-<unix time in ms>.rec, only readable by the core's user
+    // (it contains everything the plugin sent)
+    pub fn create(dir: &Path, kind: PluginKind, name: &str) -> io::Result<Recorder> {
+        fs::create_dir_all(dir)?;
+
+        let millis = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+        let path = dir.join(format!("{}-{}-{}.rec", kind, name, millis));
+        let mut file = BufWriter::new(fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path)?);
+        file.write_all(MAGIC)?;
+
+        Ok(Recorder { path, file: Some(file) })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // every entry is flushed, so the recording is complete even if the core crashes
+    pub fn record(&mut self, direction: Direction, kind: EntryKind, data: &[u8]) {
+        let Some(file) = &mut self.file else {
+            return;
+        };
+
+        let direction = match direction {
+            Direction::Received => b'<',
+            Direction::Sent => b'>',
+        };
+        let kind = match kind {
+            EntryKind::Frame => b'f',
+            EntryKind::Raw => b'r',
+        };
+        let micros = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64;
+
+        let result = file.write_all(&[direction, kind])
+            .and_then(|_| file.write_all(&micros.to_le_bytes()))
+            .and_then(|_| file.write_all(&(data.len() as u32).to_le_bytes()))
+            .and_then(|_| file.write_all(data))
+            .and_then(|_| file.flush());
+
+        if let Err(e) = result {
+            warn!("writing the recording {:?} failed, stopping it: {}", self.path, e);
+            self.file = None;
+        }
+    }
+}
+
+// all entries of the recording at path
+pub fn read(path: &Path) -> io::Result<Vec<Entry>> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, format!("{:?} is not a valid recording: {}", path, message));
+
+    let mut file = io::BufReader::new(fs::File::open(path)?);
+    let mut magic = vec![0u8; MAGIC.len()];
+    file.read_exact(&mut magic).map_err(|_| invalid("it is too short"))?;
+    if magic != MAGIC {
+        return Err(invalid("it does not start with the header of a recording"));
+    }
+
+    let mut entries = vec![];
+    loop {
+        let mut header = [0u8; 14];
+        match file.read_exact(&mut header[..1]) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(entries),
+            Err(e) => return Err(e),
+        }
+        file.read_exact(&mut header[1..]).map_err(|_| invalid("the last entry is incomplete"))?;
+
+        let direction = match header[0] {
+            b'<' => Direction::Received,
+            b'>' => Direction::Sent,
+            _ => return Err(invalid("unknown direction")),
+        };
+        let kind = match header[1] {
+            b'f' => EntryKind::Frame,
+            b'r' => EntryKind::Raw,
+            _ => return Err(invalid("unknown kind of entry")),
+        };
+        let micros = u64::from_le_bytes(header[2..10].try_into().unwrap());
+        let len = u32::from_le_bytes(header[10..14].try_into().unwrap());
+
+        let mut data = vec![];
+        (&mut file).take(len as u64).read_to_end(&mut data)?;
+        if data.len() != len as usize {
+            return Err(invalid("the last entry is incomplete"));
+        }
+
+        entries.push(Entry { direction, kind, time: UNIX_EPOCH + Duration::from_micros(micros), data });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("palleon-recorder-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn entries_are_read_back_as_recorded() {
+        let dir = dir("read-back");
+        let before = SystemTime::now() - Duration::from_millis(1);
+        let mut recorder = Recorder::create(&dir, PluginKind::Input, "camera").unwrap();
+        recorder.record(Direction::Sent, EntryKind::Raw, b"i");
+        recorder.record(Direction::Received, EntryKind::Frame, &[1, 2, 3]);
+        recorder.record(Direction::Received, EntryKind::Frame, &[]);
+
+        let name = recorder.path().file_name().unwrap().to_string_lossy().to_string();
+        assert!(name.starts_with("input-camera-") && name.ends_with(".rec"), "{}", name);
+        assert_eq!(fs::metadata(recorder.path()).unwrap().permissions().mode() & 0o777, 0o600);
+
+        // every entry is on disk before the recorder is dropped
+        let entries = read(recorder.path()).unwrap();
+        let summary: Vec<_> = entries.iter().map(|entry| (entry.direction, entry.kind, entry.data.clone())).collect();
+        assert_eq!(summary, vec![
+            (Direction::Sent, EntryKind::Raw, b"i".to_vec()),
+            (Direction::Received, EntryKind::Frame, vec![1, 2, 3]),
+            (Direction::Received, EntryKind::Frame, vec![]),
+        ]);
+        assert!(entries.iter().all(|entry| entry.time >= before && entry.time <= SystemTime::now()));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn broken_recordings_are_refused() {
+        let dir = dir("broken");
+        let mut recorder = Recorder::create(&dir, PluginKind::Data, "detector").unwrap();
+        recorder.record(Direction::Received, EntryKind::Frame, &[1, 2, 3]);
+        let recording = fs::read(recorder.path()).unwrap();
+
+        let invalid = |data: &[u8]| {
+            let path = dir.join("broken.rec");
+            fs::write(&path, data).unwrap();
+            read(&path).map(|entries| entries.len()).map_err(|e| e.kind())
+        };
+
+        assert_eq!(invalid(&recording[..MAGIC.len()]), Ok(0));
+        assert_eq!(invalid(&recording[..MAGIC.len() - 1]), Err(io::ErrorKind::InvalidData));
+        assert_eq!(invalid(b"not a recording at all"), Err(io::ErrorKind::InvalidData));
+        // cut in the header and in the data of the entry
+        assert_eq!(invalid(&recording[..MAGIC.len() + 5]), Err(io::ErrorKind::InvalidData));
+        assert_eq!(invalid(&recording[..recording.len() - 1]), Err(io::ErrorKind::InvalidData));
+
+        let mut unknown_direction = recording.clone();
+        unknown_direction[MAGIC.len()] = b'?';
+        assert_eq!(invalid(&unknown_direction), Err(io::ErrorKind::InvalidData));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
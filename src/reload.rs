@@ -0,0 +1,203 @@
+use std::{fs, io};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::SystemTime;
+
+use log::{error, info, warn};
+use signal_hook::consts::SIGHUP;
+
+use crate::config::{self, Config, ConfigError, Overrides, PluginConfig, PluginKind};
+use crate::data_manager::DataManager;
+use crate::supervisor::{RunningPlugins, Supervisor};
+use crate::transport::Address;
+
+// hot reload of the config
+// the config file is checked for modifications regularly and a SIGHUP forces a reload,
+// the new config is compared with the running one and only the plugins that changed
+// are stopped and started, everything else (including the data manager) keeps running
+
+pub struct ConfigWatcher {
+    path: PathBuf,
+    overrides: Overrides,
+    modified: Option<SystemTime>,
+    sighup: Arc<AtomicBool>,
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+impl ConfigWatcher {
+    pub fn new(path: &Path, overrides: Overrides) -> io::Result<Self> {
+        let sighup = Arc::new(AtomicBool::new(false));
+        signal_hook::flag::register(SIGHUP, sighup.clone())?;
+
+        Ok(ConfigWatcher { path: path.to_path_buf(), overrides, modified: modified(path), sighup })
+    }
+
+    // returns the new config if the file was modified or a SIGHUP was received, with the ports
+    // of the running plugins kept (see Config::keep_ports)
+    // an invalid config is reported and ignored, i.e. the running one stays active
+    pub fn poll(&mut self, running: &Config) -> Option<Config> {
+        let modified = modified(&self.path);
+        let sighup = self.sighup.swap(false, Ordering::SeqCst);
+
+        if !sighup && modified == self.modified {
+            return None;
+        }
+        self.modified = modified;
+
+        info!("reloading the config {:?}", self.path);
+
+        let mut cfg = match config::load(&self.path, &self.overrides) {
+            Ok(cfg) => cfg,
+            Err(e) => {
+                error!("reloading the config failed, keeping the running one: {}", e);
+                return None;
+            }
+        };
+
+        // the kept ports push new plugins further up the range, which has to fit as well
+        cfg.keep_ports(running);
+        let errors = cfg.validate();
+        if !errors.is_empty() {
+            error!("reloading the config failed, keeping the running one: {}", ConfigError::Invalid(errors));
+            return None;
+        }
+
+        Some(cfg)
+    }
+}
+
+// the plugins that have to be stopped and started to get from one config to another
+// a changed plugin is in both lists
+
+#[derive(Debug, Default)]
+pub struct ConfigDiff {
+    pub stop: Vec<(PluginKind, String)>,
+    pub start: Vec<(PluginKind, String)>,
+}
+
+impl ConfigDiff {
+    pub fn is_empty(&self) -> bool {
+        self.stop.is_empty() && self.start.is_empty()
+    }
+}
+
+pub fn diff(old: &Config, new: &Config) -> ConfigDiff {
+    if old.bind_port_gui != new.bind_port_gui {
+        warn!("bind_port_gui changed, this only takes effect after restarting the core");
+    }
+    if old.gui_token != new.gui_token {
+        warn!("gui_token changed, this only takes effect after restarting the core");
+    }
+    if old.bind_addr != new.bind_addr {
+        warn!("bind_addr changed, the plugins using tcp are restarted but the gui listener only moves after restarting the core");
+    }
+
+    // a plugin is unchanged if its config is the same and the core listens for it (or connects to it)
+    // at the same address, i.e. bind_addr and port or the path of its unix socket
+    fn plugins(cfg: &Config) -> Vec<(PluginKind, &String, &PluginConfig, Address)> {
+        cfg.plugin_ports().into_iter().map(|(kind, name, plugin, port)| (kind, name, plugin, cfg.plugin_address(kind, name, plugin, port))).collect()
+    }
+
+    fn find<'a>(plugins: &'a [(PluginKind, &String, &'a PluginConfig, Address)], kind: PluginKind, name: &String) -> Option<(&'a PluginConfig, &'a Address)> {
+        plugins.iter().find(|p| p.0 == kind && p.1 == name).map(|p| (p.2, &p.3))
+    }
+
+    let old_plugins = plugins(old);
+    let new_plugins = plugins(new);
+    let mut diff = ConfigDiff::default();
+
+    for (kind, name, plugin, address) in &old_plugins {
+        if find(&new_plugins, *kind, name) != Some((plugin, address)) {
+            diff.stop.push((*kind, (*name).clone()));
+        }
+    }
+    for (kind, name, plugin, address) in &new_plugins {
+        if find(&old_plugins, *kind, name) != Some((plugin, address)) {
+            diff.start.push((*kind, (*name).clone()));
+        }
+    }
+
+    diff
+}
+
+// stop and start the plugins according to the diff, cfg is the new config
+// the main loop goes on while the changed and removed plugins are stopped, the supervisor starts
+// a changed one once it is gone and handles a plugin that can't be started by its restart policy
+pub fn apply(cfg: &Config, diff: &ConfigDiff, plugins: &mut RunningPlugins, supervisor: &mut Supervisor, data_mgr: &Arc<Mutex<DataManager>>) {
+    for (kind, name) in &diff.stop {
+        info!("stopping {} plugin {:?}", kind, name);
+    }
+
+    supervisor.reload(cfg, &diff.stop, &diff.start, plugins, data_mgr);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::supervisor::Supervisor;
+
+    use super::*;
+
+    fn config(toml: &str) -> Config {
+        toml::from_str(&format!("bind_addr = \"127.0.0.1\"\nbind_port_range_start = 9100\nbind_port_gui = 9000\n{}", toml)).unwrap()
+    }
+
+    fn names(plugins: &[(PluginKind, String)]) -> Vec<&str> {
+        plugins.iter().map(|(_, name)| name.as_str()).collect()
+    }
+
+    #[test]
+    fn only_changed_plugins_are_restarted() {
+        let old = config("[input.camera]\n[data.a]\n[data.b]\n");
+        let mut new = config("[input.camera]\n[data.a]\nrestart = { policy = \"never\" }\n[data.b]\n");
+        new.keep_ports(&old);
+
+        let changes = diff(&old, &new);
+        assert_eq!(names(&changes.stop), ["a"]);
+        assert_eq!(names(&changes.start), ["a"]);
+    }
+
+    #[test]
+    fn adding_or_removing_a_plugin_does_not_move_the_others() {
+        let old = config("[input.camera]\n[data.a]\n[data.b]\n");
+        // without kept ports, b and c would get 9101 and 9102 and everything after camera would move
+        let mut new = config("[input.camera]\n[input.other]\n[data.b]\n[data.c]\n");
+        new.keep_ports(&old);
+
+        let changes = diff(&old, &new);
+        assert_eq!(names(&changes.stop), ["a"]);
+        assert_eq!(names(&changes.start), ["other", "c"]);
+
+        let ports: Vec<_> = new.plugin_ports().into_iter().map(|(_, name, _, port)| (name.as_str(), port)).collect();
+        // a's port is free again and taken by the first new plugin
+        assert_eq!(ports, [("camera", 9100), ("other", 9101), ("b", 9102), ("c", 9103)]);
+
+        // and the next reload keeps them as well
+        let mut newer = config("[input.camera]\n[input.other]\n[data.b]\n[data.c]\n");
+        newer.keep_ports(&new);
+        assert!(diff(&new, &newer).is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_plugin_that_can_not_be_started_is_left_to_its_restart_policy() {
+        // the port the new plugins want is taken
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let old = config("[input.camera]\n[data.b]\n");
+        let data_mgr = Arc::new(Mutex::new(DataManager::new()));
+
+        for (policy, counts) in [("on-failure", (1, 0)), ("never", (0, 1))] {
+            let new = config(&format!("[input.camera]\n[data.a]\nmode = \"listen\"\ntoken = \"secret\"\nport = {}\nrestart = {{ policy = \"{}\" }}\n", port, policy));
+            let mut plugins = RunningPlugins::default();
+            let mut supervisor = Supervisor::new();
+
+            apply(&new, &diff(&old, &new), &mut plugins, &mut supervisor, &data_mgr);
+            assert!(plugins.data.is_empty());
+            // (waiting for a restart, stopped)
+            assert_eq!(supervisor.counts(), counts, "{}", policy);
+        }
+    }
+}
@@ -0,0 +1,183 @@
+use std::env;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::auth;
+use crate::config::FrameLimits;
+use crate::recorder::{self, Direction, Entry, EntryKind};
+use crate::transport::Address;
+use crate::wrapped_stream::{MessageType, ProtocolError, WrappedStream};
+
+// plays the plugin's side of a recording (see recorder) back against the core, like a plugin:
+// it connects to PALLEON_SOCKET or PALLEON_HOST:PALLEON_PORT and authenticates with PALLEON_TOKEN,
+// so it can be used as the command of a plugin in the spawn mode (or started by hand in the
+// listen mode)
+// everything the core received is sent as it was recorded, everything the core sent is waited
+// for and compared with the recording, a difference is reported but does not stop the replay
+// (images and timestamps differ from run to run)
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// the exit code
+pub fn run(path: &Path, realtime: bool) -> i32 {
+    let entries = match recorder::read(path) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("reading the recording failed: {}", e);
+            return 1;
+        }
+    };
+
+    let address = match address() {
+        Ok(address) => address,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+    let token = env::var("PALLEON_TOKEN").ok();
+
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().expect("starting the async runtime failed");
+    match runtime.block_on(replay(&entries, &address, token.as_deref(), realtime)) {
+        Ok(differences) => {
+            println!("replayed {} messages, {} sent by the core differed from the recording", entries.len(), differences);
+            0
+        }
+        Err(e) => {
+            eprintln!("replaying {:?} failed: {}", path, e);
+            1
+        }
+    }
+}
+
+fn address() -> Result<Address, String> {
+    if let Some(path) = env::var_os("PALLEON_SOCKET") {
+        return Ok(Address::Unix(PathBuf::from(path)));
+    }
+
+    match (env::var("PALLEON_HOST"), env::var("PALLEON_PORT")) {
+        (Ok(host), Ok(port)) => Ok(Address::Tcp(format!("{}:{}", host, port))),
+        _ => Err("neither PALLEON_SOCKET nor PALLEON_HOST and PALLEON_PORT are set".to_string()),
+    }
+}
+
+// the number of messages of the core that differed
+async fn replay(entries: &[Entry], address: &Address, token: Option<&str>, realtime: bool) -> Result<usize, String> {
+    let stream = address.connect(CONNECT_TIMEOUT).await.map_err(|e| format!("connecting to {} failed: {}", address, e))?;
+
+    // the frames of the core are as big as images at most
+    let mut stream = WrappedStream::with_limits(stream, FrameLimits::default());
+    if let Some(token) = token {
+        auth::present(&mut stream, token).await.map_err(|e| e.to_string())?;
+    }
+
+    let mut differences = 0;
+    let mut previous = entries.first().map(|entry| entry.time);
+
+    for (i, entry) in entries.iter().enumerate() {
+        let failed = |e: ProtocolError| format!("message {} of {}: {}", i + 1, entries.len(), e);
+
+        match entry.direction {
+            Direction::Received => {
+                if realtime {
+                    if let Some(delay) = previous.and_then(|previous| entry.time.duration_since(previous).ok()) {
+                        tokio::time::sleep(delay).await;
+                    }
+                }
+                match entry.kind {
+                    EntryKind::Frame => stream.send_with_32bit_integer_length(entry.data.clone()).await.map(|_| ()),
+                    EntryKind::Raw => stream.write(&entry.data).await,
+                }.map_err(failed)?;
+            }
+            Direction::Sent => {
+                let received = match entry.kind {
+                    EntryKind::Frame => stream.recv_based_on_32bit_integer(MessageType::Image).await,
+                    EntryKind::Raw => stream.recv_raw(entry.data.len()).await,
+                }.map_err(failed)?;
+
+                if received.len() != entry.data.len() {
+                    println!("message {} of {} differs from the recording ({} bytes instead of {})", i + 1, entries.len(), received.len(), entry.data.len());
+                    differences += 1;
+                } else if received != entry.data {
+                    println!("message {} of {} differs from the recording", i + 1, entries.len());
+                    differences += 1;
+                }
+            }
+        }
+
+        previous = Some(entry.time);
+    }
+
+    Ok(differences)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use bson::doc;
+    use tokio::net::UnixListener;
+
+    use crate::config::PluginKind;
+    use crate::recorder::Recorder;
+
+    use super::*;
+
+    fn bytes(doc: bson::Document) -> Vec<u8> {
+        let mut buffer = vec![];
+        doc.to_writer(&mut buffer).unwrap();
+        buffer
+    }
+
+    #[tokio::test]
+    async fn the_plugin_side_is_replayed_and_differences_of_the_core_are_counted() {
+        let dir = std::env::temp_dir().join(format!("palleon-replay-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        // what a core recorded of a data plugin
+        let mut recorder = Recorder::create(&dir, PluginKind::Data, "detector").unwrap();
+        recorder.record(Direction::Sent, EntryKind::Frame, &bytes(doc! { "type": "ping" }));
+        recorder.record(Direction::Received, EntryKind::Frame, &bytes(doc! { "type": "pong" }));
+        recorder.record(Direction::Sent, EntryKind::Frame, &bytes(doc! { "type": "shutdown" }));
+        let entries = recorder::read(recorder.path()).unwrap();
+
+        let path = dir.join("core.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        let core = tokio::spawn(async move {
+            let mut stream = WrappedStream::new(listener.accept().await.unwrap().0);
+            auth::accept(&mut stream, "secret").await.unwrap();
+
+            stream.send_bson(&doc! { "type": "ping" }).await.unwrap();
+            let answer = stream.recv_bson(MessageType::Data).await.unwrap();
+            // differs from the recording
+            stream.send_bson(&doc! { "type": "ping" }).await.unwrap();
+            answer
+        });
+
+        let differences = replay(&entries, &Address::Unix(path), Some("secret"), false).await;
+        assert_eq!(differences, Ok(1));
+        assert_eq!(core.await.unwrap(), doc! { "type": "pong" });
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_core_that_closes_the_connection_ends_the_replay() {
+        let dir = std::env::temp_dir().join(format!("palleon-replay-closed-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut recorder = Recorder::create(&dir, PluginKind::Input, "camera").unwrap();
+        recorder.record(Direction::Sent, EntryKind::Raw, b"i");
+        let entries = recorder::read(recorder.path()).unwrap();
+
+        let path = dir.join("core.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        let core = tokio::spawn(async move { drop(listener.accept().await.unwrap()) });
+
+        let error = replay(&entries, &Address::Unix(path), None, false).await.unwrap_err();
+        assert!(error.starts_with("message 1 of 1:"), "{}", error);
+        core.await.unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,151 @@
+use std::{fs, io};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use bson::{doc, Document};
+use memmap2::MmapMut;
+
+use crate::config::ShmConfig;
+use crate::wrapped_stream::ProtocolError;
+
+// frames of plugins on the same machine can be passed through shared memory instead of the
+// connection, which then only carries a reference to the slot the frame is in
+// - the core creates a file of slots * slot_bytes in shm_dir (0600, only the core's user can map it)
+//   for every connection, the plugin gets its path, slots and slot_bytes in the answer to its hello
+// - a reference is {"slot": <index>, "size": <bytes>} in place of the binary "data"
+// - the slots of the core (images to data plugins) are used round robin, the plugin has to be done
+//   with a slot when it answers, an input plugin can use any slot for its frame, the core copies
+//   the frame out before it requests the next one
+// - an input plugin in the push mode has frames in flight, the slot of a frame is free again once
+//   the credit for it came back (the core copies the frame out before granting it), so it uses
+//   the slots round robin as well, which is safe as there are at least as many slots as credits
+// a frame that does not fit into a slot is sent inline as before
+
+pub struct ShmRing {
+    path: PathBuf,
+    map: MmapMut,
+    config: ShmConfig,
+    next: u32,
+}
+
+impl ShmRing {
+    // an existing file at path (e.g. of a previous connection) is replaced
+    pub fn create(path: PathBuf, config: ShmConfig) -> io::Result<ShmRing> {
+        let _ = fs::remove_file(&path);
+        let file = fs::OpenOptions::new().read(true).write(true).create_new(true).mode(0o600).open(&path)?;
+        file.set_len(config.slots as u64 * config.slot_bytes as u64)?;
+
+        // the file is only ever mapped by the core and its plugin, which agree on who writes a slot when
+        let map = unsafe { MmapMut::map_mut(&file)? };
+
+        Ok(ShmRing { path, map, config, next: 0 })
+    }
+
+    // what the plugin needs to map the ring
+    pub fn to_document(&self) -> Document {
+        doc! {
+            "path": self.path.to_string_lossy().to_string(),
+            "slots": self.config.slots as i64,
+            "slot_bytes": self.config.slot_bytes as i64,
+        }
+    }
+
+    // copies data into the next slot, None if it does not fit into one
+    pub fn write(&mut self, data: &[u8]) -> Option<Document> {
+        if data.len() > self.config.slot_bytes as usize {
+            return None;
+        }
+
+        let slot = self.next;
+        self.next = (self.next + 1) % self.config.slots;
+
+        let start = slot as usize * self.config.slot_bytes as usize;
+        self.map[start..start + data.len()].copy_from_slice(data);
+
+        Some(doc! { "slot": slot as i64, "size": data.len() as i64 })
+    }
+
+    // copies the frame a plugin put into the slot of reference out of the ring
+    pub fn read(&self, reference: &Document) -> Result<Vec<u8>, ProtocolError> {
+        let invalid = |message: String| ProtocolError::UnexpectedMessage(format!("invalid shared memory reference: {}", message));
+
+        let slot = reference.get_i64("slot").or_else(|_| reference.get_i32("slot").map(i64::from)).map_err(|_| invalid("no 'slot'".to_string()))?;
+        let size = reference.get_i64("size").or_else(|_| reference.get_i32("size").map(i64::from)).map_err(|_| invalid("no 'size'".to_string()))?;
+
+        if slot < 0 || slot >= self.config.slots as i64 {
+            return Err(invalid(format!("there is no slot {}", slot)));
+        }
+        if size < 0 || size > self.config.slot_bytes as i64 {
+            return Err(invalid(format!("{} bytes do not fit into a slot of {} bytes", size, self.config.slot_bytes)));
+        }
+
+        let start = slot as usize * self.config.slot_bytes as usize;
+        Ok(self.map[start..start + size as usize].to_vec())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for ShmRing {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    static NEXT_RING: AtomicUsize = AtomicUsize::new(0);
+
+    // 3 slots of 8 bytes
+    fn ring() -> ShmRing {
+        let path = std::env::temp_dir().join(format!("palleon-shm-test-{}-{}.shm", std::process::id(), NEXT_RING.fetch_add(1, Ordering::SeqCst)));
+        ShmRing::create(path, ShmConfig { slots: 3, slot_bytes: 8 }).unwrap()
+    }
+
+    #[test]
+    fn frames_go_into_the_slots_round_robin() {
+        let mut ring = ring();
+        for (i, slot) in [0, 1, 2, 0].into_iter().enumerate() {
+            let data = vec![i as u8; 8 - i];
+            let reference = ring.write(&data).unwrap();
+            assert_eq!(reference, doc! { "slot": slot as i64, "size": (8 - i) as i64 });
+            assert_eq!(ring.read(&reference).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn a_frame_bigger_than_a_slot_stays_inline() {
+        let mut ring = ring();
+        assert_eq!(ring.write(&[0; 9]), None);
+        // and takes no slot
+        assert_eq!(ring.write(&[1]).unwrap().get_i64("slot"), Ok(0));
+    }
+
+    #[test]
+    fn references_outside_of_the_ring_are_refused() {
+        let ring = ring();
+        for reference in [doc! { "slot": 3, "size": 1 }, doc! { "slot": -1, "size": 1 }, doc! { "slot": 0, "size": 9 }, doc! { "slot": 0, "size": -1 }, doc! { "size": 1 }, doc! { "slot": 0 }] {
+            assert!(matches!(ring.read(&reference), Err(ProtocolError::UnexpectedMessage(_))), "{}", reference);
+        }
+        // 32 bit integers are fine
+        assert_eq!(ring.read(&doc! { "slot": 2_i32, "size": 8_i32 }).unwrap().len(), 8);
+    }
+
+    #[test]
+    fn the_ring_is_only_accessible_by_the_core_user_and_removed_with_it() {
+        let ring = ring();
+        let path = ring.path().to_path_buf();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(fs::metadata(&path).unwrap().len(), 24);
+
+        drop(ring);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,43 @@
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::flag;
+
+// SIGINT and SIGTERM ask the core to shut down gracefully
+// a second one terminates the core immediately, in case the graceful shutdown hangs,
+// with the usual exit code of a process that was killed by that signal (128 + signal)
+
+// a clone shares the state, so a shutdown can also be requested in code (see request)
+#[derive(Clone, Default)]
+pub struct ShutdownSignal {
+    requested: Arc<AtomicBool>,
+}
+
+impl ShutdownSignal {
+    // a signal that is only ever requested in code, e.g. by the integration tests
+    pub fn new() -> Self {
+        ShutdownSignal::default()
+    }
+
+    pub fn register() -> io::Result<Self> {
+        let requested = Arc::new(AtomicBool::new(false));
+
+        for signal in [SIGINT, SIGTERM] {
+            // the order matters: the conditional shutdown has to see the flag before it is set
+            flag::register_conditional_shutdown(signal, 128 + signal, requested.clone())?;
+            flag::register(signal, requested.clone())?;
+        }
+
+        Ok(ShutdownSignal { requested })
+    }
+
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+}
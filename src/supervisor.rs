@@ -0,0 +1,425 @@
+use std::collections::{HashMap, VecDeque};
+use std::{io, thread};
+use std::thread::JoinHandle;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use indexmap::IndexMap;
+use log::{error, info, warn};
+
+use crate::config::{Config, PluginConfig, PluginKind, RestartConfig, RestartPolicy};
+use crate::data_manager::DataManager;
+use crate::data_plugins::{self, DataPlugin};
+use crate::input_plugins::{self, InputPlugin};
+use crate::plugin::{PluginStoppedReason, StopOutcome};
+use crate::plugin_output::PluginOutput;
+
+// the supervisor watches all running plugins and decides, based on the restart config
+// of each plugin, what happens if one of them stops
+// a stopped plugin is removed from the running plugins (so the main loop does not wait
+// for it anymore) and reaped in the background, as a plugin that ignores its shutdown takes
+// up to twice the grace period, once it is gone its policy decides whether it is started
+// again after a backoff
+
+// all plugins that are currently running, in config order
+
+#[derive(Default)]
+pub struct RunningPlugins {
+    pub input: IndexMap<String, InputPlugin>,
+    pub data: IndexMap<String, DataPlugin>,
+}
+
+impl RunningPlugins {
+    // start every plugin of the config, fails on the first plugin that can't be started
+    pub fn start_all(cfg: &Config, data_mgr: &Arc<Mutex<DataManager>>) -> io::Result<Self> {
+        let mut running = RunningPlugins::default();
+
+        for (kind, name, plugin, port) in cfg.plugin_ports() {
+            running.start(cfg, data_mgr, kind, name, plugin, port)?;
+        }
+
+        Ok(running)
+    }
+
+    pub fn start(&mut self, cfg: &Config, data_mgr: &Arc<Mutex<DataManager>>, kind: PluginKind, name: &String, plugin: &PluginConfig, port: i32) -> io::Result<()> {
+        match kind {
+            PluginKind::Input => { self.input.insert(name.clone(), input_plugins::start_plugin(cfg, name, plugin, port)?); }
+            PluginKind::Data => { self.data.insert(name.clone(), data_plugins::start_plugin(cfg, data_mgr, name, plugin, port)?); }
+        }
+        Ok(())
+    }
+
+    // stop all plugins at once, returns the name and outcome of every plugin
+    pub fn stop_all(self, grace_period: Duration) -> Vec<(String, StopOutcome)> {
+        stop_in_parallel(self.input, self.data, grace_period)
+    }
+
+    // stop the plugin in a thread of its own if it is running, see Supervisor::supervise
+    fn stop_in_background(&mut self, kind: PluginKind, name: &String, grace_period: Duration) -> Option<JoinHandle<StopOutcome>> {
+        match kind {
+            PluginKind::Input => self.input.shift_remove(name).map(|p| thread::spawn(move || p.stop(grace_period))),
+            PluginKind::Data => self.data.shift_remove(name).map(|p| thread::spawn(move || p.stop(grace_period))),
+        }
+    }
+
+    // all running plugins that stopped on their own since the last call
+    fn stopped(&mut self) -> Vec<(PluginKind, String, PluginStoppedReason)> {
+        let input = self.input.iter_mut().map(|(name, p)| (PluginKind::Input, name, &mut p.plugin));
+        let data = self.data.iter_mut().map(|(name, p)| (PluginKind::Data, name, &mut p.plugin));
+
+        input.chain(data)
+            .filter_map(|(kind, name, plugin)| plugin.has_erroneously_stopped().map(|reason| (kind, name.clone(), reason)))
+            .collect()
+    }
+}
+
+// every plugin in its own thread, returns once all of them stopped
+fn stop_in_parallel(input: IndexMap<String, InputPlugin>, data: IndexMap<String, DataPlugin>, grace_period: Duration) -> Vec<(String, StopOutcome)> {
+    thread::scope(|scope| {
+        let input = input.into_iter().map(|(name, p)| scope.spawn(move || (name, p.stop(grace_period))));
+        let data = data.into_iter().map(|(name, p)| scope.spawn(move || (name, p.stop(grace_period))));
+
+        // collect first, so all plugins are stopped in parallel before joining
+        let stopping: Vec<_> = input.chain(data).collect();
+        stopping.into_iter().map(|handle| handle.join().unwrap()).collect()
+    })
+}
+
+enum State {
+    WaitingForRestart(Instant),
+    Stopped,
+}
+
+// everything the supervisor remembers about a plugin that stopped at least once
+#[derive(Default)]
+struct History {
+    restarts: u32,
+    consecutive_failures: u32,
+    recent_restarts: VecDeque<Instant>,
+    state: Option<State>,
+}
+
+// a stopped plugin that is being reaped, see supervise
+struct Reaping {
+    stopping: JoinHandle<StopOutcome>,
+    then: AfterReaping,
+}
+
+enum AfterReaping {
+    // it stopped on its own, its restart policy decides what happens
+    Restart { reason: PluginStoppedReason, ran_for: Duration, output: Option<PluginOutput> },
+    // it was changed by a config reload and is started with the new config
+    Start,
+    // it was removed by a config reload
+    Nothing,
+}
+
+#[derive(Default)]
+pub struct Supervisor {
+    plugins: HashMap<(PluginKind, String), History>,
+    reaping: HashMap<(PluginKind, String), Reaping>,
+    // the frames every data plugin missed because it was not connected
+    skipped_frames: HashMap<String, u64>,
+}
+
+impl Supervisor {
+    pub fn new() -> Self {
+        Supervisor::default()
+    }
+
+    // forget everything about a plugin, e.g. because it was changed or removed by a config reload
+    pub fn forget(&mut self, kind: PluginKind, name: &str) {
+        self.plugins.remove(&(kind, name.to_string()));
+    }
+
+    // (nr of plugins waiting for a restart, nr of plugins that stay stopped)
+    pub fn counts(&self) -> (usize, usize) {
+        let waiting = self.plugins.values().filter(|h| matches!(h.state, Some(State::WaitingForRestart(_)))).count();
+        let stopped = self.plugins.values().filter(|h| matches!(h.state, Some(State::Stopped))).count();
+        (waiting, stopped)
+    }
+
+    // a data plugin was not connected when a frame was distributed, the first frame it misses is
+    // reported, the count of all of them is part of the status (see skipped_frames)
+    pub fn skipped_frame(&mut self, name: &str) {
+        let count = self.skipped_frames.entry(name.to_string()).or_default();
+        if *count == 0 {
+            warn!("data plugin {:?} is not connected, it misses frames until it is", name);
+        }
+        *count += 1;
+    }
+
+    pub fn skipped_frames(&self) -> impl Iterator<Item=(&String, u64)> {
+        self.skipped_frames.iter().map(|(name, count)| (name, *count))
+    }
+
+    // the plugins that are still being reaped are waited for, e.g. when the core shuts down
+    pub fn finish_reaping(&mut self) -> Vec<(String, StopOutcome)> {
+        self.reaping.drain().map(|((_, name), reaping)| (name, reaping.stopping.join().unwrap())).collect()
+    }
+
+    // the plugins a config reload changed or removed, they are stopped in the background like
+    // the ones that stopped on their own, a changed one is started once it is gone (so it can
+    // bind its address again) and added ones right away, see reload::apply
+    // a plugin that can't be started is handled like one that failed, i.e. by its restart policy
+    pub fn reload(&mut self, cfg: &Config, stop: &[(PluginKind, String)], start: &[(PluginKind, String)], running: &mut RunningPlugins, data_mgr: &Arc<Mutex<DataManager>>) {
+        // a changed or removed plugin starts over without restart history
+        for (kind, name) in stop.iter().chain(start) {
+            self.forget(*kind, name);
+        }
+
+        for (kind, name) in stop {
+            if let Some(stopping) = running.stop_in_background(*kind, name, cfg.shutdown_grace_period()) {
+                self.reaping.insert((*kind, name.clone()), Reaping { stopping, then: AfterReaping::Nothing });
+            }
+        }
+
+        for plugin in cfg.plugin_ports() {
+            let key = (plugin.0, plugin.1.clone());
+            if !start.contains(&key) {
+                continue;
+            }
+            // also one that stopped on its own before and is still being reaped
+            match self.reaping.get_mut(&key) {
+                Some(reaping) => reaping.then = AfterReaping::Start,
+                None => self.start(cfg, running, data_mgr, plugin),
+            }
+        }
+    }
+
+    // plugin is one of Config::plugin_ports
+    fn start(&mut self, cfg: &Config, running: &mut RunningPlugins, data_mgr: &Arc<Mutex<DataManager>>, (kind, name, plugin, port): (PluginKind, &String, &PluginConfig, i32)) {
+        info!("starting {} plugin {:?}", kind, name);
+        if let Err(e) = running.start(cfg, data_mgr, kind, name, plugin, port) {
+            error!("starting {} plugin {:?} failed: {}", kind, name, e);
+            self.plugins.entry((kind, name.clone())).or_default().stopped(kind, name, &plugin.restart, true, Duration::ZERO, Instant::now());
+        }
+    }
+
+    // handle all plugins that stopped and restart the ones whose backoff is over
+    // a stopped plugin is only restarted once it was reaped, the main loop goes on meanwhile
+    pub fn supervise(&mut self, cfg: &Config, running: &mut RunningPlugins, data_mgr: &Arc<Mutex<DataManager>>) {
+        let now = Instant::now();
+
+        for (kind, name, reason) in running.stopped() {
+            let plugin = match kind {
+                PluginKind::Input => &running.input[&name].plugin,
+                PluginKind::Data => &running.data[&name].plugin,
+            };
+            let ran_for = plugin.started.elapsed();
+            let output = plugin.output.clone();
+
+            warn!("{} plugin {:?} stopped after {:?}: {}", kind, name, ran_for, reason);
+
+            // clean up what's left of the plugin, i.e. the process or the socket task
+            if let Some(stopping) = running.stop_in_background(kind, &name, cfg.shutdown_grace_period()) {
+                self.reaping.insert((kind, name), Reaping { stopping, then: AfterReaping::Restart { reason, ran_for, output } });
+            }
+        }
+
+        let reaped: Vec<_> = self.reaping.iter().filter(|(_, reaping)| reaping.stopping.is_finished()).map(|(key, _)| key.clone()).collect();
+        for (kind, name) in reaped {
+            let reaping = self.reaping.remove(&(kind, name.clone())).unwrap();
+            match reaping.stopping.join() {
+                Ok(StopOutcome::Exited) => info!("{} plugin {:?} exited", kind, name),
+                outcome => warn!("{} plugin {:?} did not exit on its own ({:?})", kind, name, outcome),
+            }
+
+            let found = cfg.plugin_ports().into_iter().find(|p| p.0 == kind && p.1 == &name);
+            match (reaping.then, found) {
+                (AfterReaping::Restart { reason, ran_for, output }, found) => {
+                    // the process is gone now, so its output has (most likely) been read completely
+                    let tail = output.map(|o| o.tail()).unwrap_or_default();
+                    if reason.is_failure() && !tail.is_empty() {
+                        error!("last output of {} plugin {:?}:\n{}", kind, name, tail.join("\n"));
+                    }
+
+                    let Some((_, _, plugin, _)) = found else { continue; };
+                    let history = self.plugins.entry((kind, name.clone())).or_default();
+                    history.stopped(kind, &name, &plugin.restart, reason.is_failure(), ran_for, now);
+                }
+                (AfterReaping::Start, Some(plugin)) => self.start(cfg, running, data_mgr, plugin),
+                (AfterReaping::Start | AfterReaping::Nothing, _) => {}
+            }
+        }
+
+        for (kind, name, plugin, port) in cfg.plugin_ports() {
+            let Some(history) = self.plugins.get_mut(&(kind, name.clone())) else { continue; };
+            let Some(State::WaitingForRestart(at)) = history.state else { continue; };
+            if at > now {
+                continue;
+            }
+
+            history.restarted(now);
+
+            info!("restarting {} plugin {:?} (restart {})", kind, name, history.restarts);
+
+            if let Err(e) = running.start(cfg, data_mgr, kind, name, plugin, port) {
+                warn!("restarting {} plugin {:?} failed: {}", kind, name, e);
+                history.schedule_restart(kind, name, &plugin.restart, now);
+            }
+        }
+    }
+}
+
+impl History {
+    // the plugin stopped after running for ran_for, its restart policy decides what happens
+    fn stopped(&mut self, kind: PluginKind, name: &str, restart: &RestartConfig, failure: bool, ran_for: Duration, now: Instant) {
+        let restarting = match restart.policy {
+            RestartPolicy::Never => false,
+            RestartPolicy::OnFailure => failure,
+            RestartPolicy::Always => true,
+        };
+
+        if !restarting {
+            info!("{} plugin {:?} is not restarted (restart policy {:?})", kind, name, restart.policy);
+            self.state = Some(State::Stopped);
+            return;
+        }
+
+        // a plugin that ran stable for a while starts over with the smallest backoff
+        if ran_for >= Duration::from_secs(restart.crash_loop_window_secs) {
+            self.consecutive_failures = 0;
+        }
+
+        self.schedule_restart(kind, name, restart, now);
+    }
+
+    fn restarted(&mut self, now: Instant) {
+        self.restarts += 1;
+        self.recent_restarts.push_back(now);
+        self.state = None;
+    }
+
+    // the plugin stays stopped once it was restarted max_restarts times, or crash_loop_restarts
+    // times within the last crash_loop_window_secs
+    fn schedule_restart(&mut self, kind: PluginKind, name: &str, restart: &RestartConfig, now: Instant) {
+        self.consecutive_failures += 1;
+
+        if restart.max_restarts.is_some_and(|max| self.restarts >= max) {
+            error!("{} plugin {:?} reached its maximum of {} restarts, it stays stopped", kind, name, self.restarts);
+            self.state = Some(State::Stopped);
+            return;
+        }
+
+        let window = Duration::from_secs(restart.crash_loop_window_secs);
+        while self.recent_restarts.front().is_some_and(|t| now.duration_since(*t) > window) {
+            self.recent_restarts.pop_front();
+        }
+        if self.recent_restarts.len() >= restart.crash_loop_restarts as usize {
+            error!("{} plugin {:?} is crash looping ({} restarts within {:?}), it stays stopped", kind, name, self.recent_restarts.len(), window);
+            self.state = Some(State::Stopped);
+            return;
+        }
+
+        // initial_backoff * 2^(failures - 1), limited to max_backoff
+        let exponent = (self.consecutive_failures - 1).min(31);
+        let backoff = restart.initial_backoff_ms.saturating_mul(1 << exponent).min(restart.max_backoff_ms);
+
+        info!("restarting {} plugin {:?} in {} ms", kind, name, backoff);
+        self.state = Some(State::WaitingForRestart(now + Duration::from_millis(backoff)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn restart() -> RestartConfig {
+        RestartConfig { initial_backoff_ms: 100, max_backoff_ms: 1000, crash_loop_restarts: 3, crash_loop_window_secs: 60, ..RestartConfig::default() }
+    }
+
+    // the delay of the scheduled restart, None if the plugin stays stopped
+    fn backoff(history: &History, now: Instant) -> Option<u128> {
+        match history.state {
+            Some(State::WaitingForRestart(at)) => Some(at.duration_since(now).as_millis()),
+            Some(State::Stopped) => None,
+            None => panic!("nothing was scheduled"),
+        }
+    }
+
+    // fails and gets restarted at the scheduled time, returns the delays until it stays stopped
+    fn crash_loop(history: &mut History, restart: &RestartConfig, mut now: Instant, failures: usize) -> Vec<Option<u128>> {
+        (0..failures).map(|_| {
+            history.schedule_restart(PluginKind::Data, "a", restart, now);
+            let backoff = backoff(history, now);
+            if let Some(backoff) = backoff {
+                now += Duration::from_millis(backoff as u64);
+                history.restarted(now);
+            }
+            backoff
+        }).collect()
+    }
+
+    #[test]
+    fn the_backoff_doubles_up_to_the_maximum() {
+        let restart = RestartConfig { crash_loop_restarts: 100, ..restart() };
+        let mut history = History::default();
+
+        let backoffs = crash_loop(&mut history, &restart, Instant::now(), 6);
+        assert_eq!(backoffs, [Some(100), Some(200), Some(400), Some(800), Some(1000), Some(1000)]);
+    }
+
+    #[test]
+    fn a_plugin_that_ran_for_a_while_starts_over_with_the_initial_backoff() {
+        let restart = restart();
+        let mut history = History::default();
+        let now = Instant::now();
+        crash_loop(&mut history, &restart, now, 2);
+
+        // one that crashes right away again keeps doubling
+        history.stopped(PluginKind::Data, "a", &restart, true, Duration::from_secs(1), now);
+        assert_eq!(backoff(&history, now), Some(400));
+        history.restarted(now);
+
+        let later = now + Duration::from_secs(120);
+        history.stopped(PluginKind::Data, "a", &restart, true, Duration::from_secs(restart.crash_loop_window_secs), later);
+        assert_eq!(backoff(&history, later), Some(100));
+    }
+
+    #[test]
+    fn the_policy_decides_whether_a_plugin_is_restarted() {
+        let now = Instant::now();
+        let stopped = |policy, failure| {
+            let mut history = History::default();
+            history.stopped(PluginKind::Data, "a", &RestartConfig { policy, ..restart() }, failure, Duration::from_secs(1), now);
+            backoff(&history, now)
+        };
+
+        assert_eq!(stopped(RestartPolicy::Never, true), None);
+        assert_eq!(stopped(RestartPolicy::OnFailure, false), None);
+        assert_eq!(stopped(RestartPolicy::OnFailure, true), Some(100));
+        assert_eq!(stopped(RestartPolicy::Always, false), Some(100));
+    }
+
+    #[test]
+    fn a_crash_looping_plugin_is_restarted_crash_loop_restarts_times_within_the_window() {
+        let restart = restart();
+        let mut history = History::default();
+
+        let backoffs = crash_loop(&mut history, &restart, Instant::now(), 4);
+        assert_eq!(backoffs, [Some(100), Some(200), Some(400), None]);
+        assert_eq!(history.restarts, 3);
+    }
+
+    #[test]
+    fn restarts_outside_of_the_window_do_not_count_as_crash_loop() {
+        let restart = restart();
+        let mut history = History::default();
+        let now = Instant::now();
+        crash_loop(&mut history, &restart, now, 3);
+
+        let later = now + Duration::from_secs(restart.crash_loop_window_secs + 10);
+        history.schedule_restart(PluginKind::Data, "a", &restart, later);
+        assert!(backoff(&history, later).is_some());
+    }
+
+    #[test]
+    fn a_plugin_stays_stopped_after_max_restarts() {
+        let restart = RestartConfig { max_restarts: Some(2), ..restart() };
+        let mut history = History::default();
+
+        let backoffs = crash_loop(&mut history, &restart, Instant::now(), 3);
+        assert_eq!(backoffs, [Some(100), Some(200), None]);
+    }
+}
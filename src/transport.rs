@@ -0,0 +1,229 @@
+use std::{fmt, fs, io, net};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt, PermissionsExt};
+use std::os::unix::net as unix;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use log::warn;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+
+// the connections between the core and the plugins either use tcp or unix domain sockets
+// a unix socket is only reachable on the local machine and only by users that are allowed to
+// access the socket file, which is created in the runtime directory of the core
+// the runtime directory is only accessible by the core's user (see create_runtime_dir), so
+// nobody else can reach the socket file in the moment between binding it and setting its
+// permissions to 0600
+
+// creates the runtime directory (and its parents) if it doesn't exist yet, accessible only by the core's user
+// an existing directory has to belong to the core's user (e.g. a palleon-<uid> in the temp
+// directory that was created by someone else is refused) and is made accessible only by it
+pub fn create_runtime_dir(dir: &Path) -> io::Result<()> {
+    fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+
+    let meta = fs::symlink_metadata(dir)?;
+    if !meta.is_dir() {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("the runtime directory {:?} is not a directory (but e.g. a symlink)", dir)));
+    }
+    let uid = unsafe { libc::getuid() };
+    if meta.uid() != uid {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("the runtime directory {:?} belongs to the user {} instead of {}", dir, meta.uid(), uid)));
+    }
+    if meta.mode() & 0o077 != 0 {
+        warn!("the runtime directory {:?} was accessible by other users (mode {:o}), changing it to 0700", dir, meta.mode() & 0o777);
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+    }
+    Ok(())
+}
+
+// what WrappedStream needs from a connection
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Transport for T {}
+
+// a connection of either kind, this is what the plugins use
+
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            Stream::Unix(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            Stream::Unix(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(s) => Pin::new(s).poll_flush(cx),
+            Stream::Unix(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            Stream::Unix(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+// where a plugin is listened for or connected to
+// tcp addresses are kept as "host:port" and resolved when they are used
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Tcp(addr) => f.pad(addr),
+            Address::Unix(path) => f.pad(&format!("unix:{}", path.display())),
+        }
+    }
+}
+
+impl Address {
+    // connecting times out, so a plugin that is not reachable does not block forever
+    pub async fn connect(&self, timeout: Duration) -> io::Result<Stream> {
+        let connecting = async {
+            match self {
+                Address::Tcp(addr) => TcpStream::connect(addr).await.map(Stream::Tcp),
+                Address::Unix(path) => UnixStream::connect(path).await.map(Stream::Unix),
+            }
+        };
+
+        tokio::time::timeout(timeout, connecting).await
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, format!("connecting to {} timed out", self))))
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    // a socket file left behind by a previous run is replaced, any other file is not
+    // binding happens right away, so errors like an address in use are reported to the caller,
+    // but it has to be called within the runtime
+    pub fn bind(addr: &Address) -> io::Result<Listener> {
+        match addr {
+            Address::Tcp(addr) => {
+                let listener = net::TcpListener::bind(addr)?;
+                listener.set_nonblocking(true)?;
+                TcpListener::from_std(listener).map(Listener::Tcp)
+            }
+            Address::Unix(path) => {
+                if let Some(dir) = path.parent() {
+                    create_runtime_dir(dir)?;
+                }
+
+                match fs::symlink_metadata(path) {
+                    Ok(meta) if meta.file_type().is_socket() => fs::remove_file(path)?,
+                    Ok(_) => return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{:?} exists and is not a socket", path))),
+                    Err(_) => {}
+                }
+
+                let listener = unix::UnixListener::bind(path)?;
+                fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+                listener.set_nonblocking(true)?;
+                Ok(Listener::Unix(UnixListener::from_std(listener)?, path.clone()))
+            }
+        }
+    }
+
+    // the address the listener is actually bound to, i.e. with the port the os chose
+    pub fn local_addr(&self) -> io::Result<Address> {
+        match self {
+            Listener::Tcp(listener) => Ok(Address::Tcp(listener.local_addr()?.to_string())),
+            Listener::Unix(_, path) => Ok(Address::Unix(path.clone())),
+        }
+    }
+
+    pub async fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().await.map(|(stream, _)| Stream::Tcp(stream)),
+            Listener::Unix(listener, _) => listener.accept().await.map(|(stream, _)| Stream::Unix(stream)),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("palleon-transport-test-{}-{}", std::process::id(), NEXT_DIR.fetch_add(1, Ordering::SeqCst)))
+    }
+
+    fn mode(path: &Path) -> u32 {
+        fs::symlink_metadata(path).unwrap().mode() & 0o777
+    }
+
+    #[tokio::test]
+    async fn unix_sockets_are_only_accessible_by_the_core_user() {
+        let dir = temp_path();
+        let path = dir.join("input-camera.sock");
+
+        let listener = Listener::bind(&Address::Unix(path.clone())).unwrap();
+        assert_eq!(mode(&dir), 0o700);
+        assert_eq!(mode(&path), 0o600);
+
+        drop(listener);
+        assert!(!path.exists());
+        fs::remove_dir(dir).unwrap();
+    }
+
+    #[test]
+    fn an_existing_runtime_dir_accessible_by_others_is_restricted() {
+        let dir = temp_path();
+        fs::DirBuilder::new().mode(0o777).create(&dir).unwrap();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o777)).unwrap();
+
+        create_runtime_dir(&dir).unwrap();
+        assert_eq!(mode(&dir), 0o700);
+        fs::remove_dir(dir).unwrap();
+    }
+
+    #[test]
+    fn a_runtime_dir_that_is_a_symlink_is_refused() {
+        let target = temp_path();
+        let link = temp_path();
+        fs::DirBuilder::new().mode(0o700).create(&target).unwrap();
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+
+        assert_eq!(create_runtime_dir(&link).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        fs::remove_file(link).unwrap();
+        fs::remove_dir(target).unwrap();
+    }
+}
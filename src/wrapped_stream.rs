@@ -1,53 +1,337 @@
-use std::io::{BufReader, Read, Write};
-use std::net::TcpStream;
+use std::{fmt, io};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::Duration;
 
 use bson::{Document};
+use bson::serde_helpers::Utf8LossyDeserialization;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
 
+use crate::compression::{Compression, Compressor};
+use crate::config::FrameLimits;
+use crate::recorder::{Direction, EntryKind, Recorder};
+use crate::transport::{Stream, Transport};
 
-pub struct WrappedStream {
-    reader: BufReader<TcpStream>,
-    stream: TcpStream,
-    length_buffer: [u8; 4],
+// the framing of the plugin protocol on top of any transport
+// every send and recv returns a ProtocolError instead of panicking, so a plugin that closes
+// its connection or sends garbage only ends the handling of that one connection
+
+// every frame is received as one type of message, which determines its size limit
+// frames bigger than the limit are refused before anything is allocated for them
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MessageType {
+    // the token, see auth (the limit is fixed)
+    Handshake,
+    Hello,
+    Image,
+    Data,
+}
+
+// the token is small, anything bigger is refused
+const MAX_HANDSHAKE_BYTES: u32 = 4096;
+
+impl MessageType {
+    fn limit(self, limits: &FrameLimits) -> u32 {
+        match self {
+            MessageType::Handshake => MAX_HANDSHAKE_BYTES,
+            MessageType::Hello => limits.hello_bytes,
+            MessageType::Image => limits.image_bytes,
+            MessageType::Data => limits.data_bytes,
+        }
+    }
+}
+
+impl fmt::Display for MessageType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageType::Handshake => f.pad("handshake"),
+            MessageType::Hello => f.pad("hello"),
+            MessageType::Image => f.pad("image"),
+            MessageType::Data => f.pad("data"),
+        }
+    }
+}
+
+// the number of rejected (oversized) frames per plugin (or "gui") and type of message, since the core started
+static REJECTED_FRAMES: Mutex<BTreeMap<(String, MessageType), u64>> = Mutex::new(BTreeMap::new());
+
+// counts the frame if the error is about an oversized one
+pub fn count_rejected_frame(name: &str, error: &ProtocolError) {
+    if let ProtocolError::OversizedFrame { message, .. } = error {
+        *REJECTED_FRAMES.lock().unwrap().entry((name.to_string(), *message)).or_default() += 1;
+    }
+}
+
+pub fn rejected_frames() -> Vec<(String, MessageType, u64)> {
+    REJECTED_FRAMES.lock().unwrap().iter().map(|((name, message), count)| (name.clone(), *message, *count)).collect()
+}
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    // the plugin closed the connection (or it broke)
+    Disconnected,
+    OversizedFrame { message: MessageType, size: u32, limit: u32 },
+    InvalidBson(bson::de::Error),
+    // the message is well-formed, but not what the protocol expects at this point
+    UnexpectedMessage(String),
+    // the plugin sent an error message, see protocol::Message::Error
+    Plugin(String),
+    // a compressed frame could not be decompressed
+    Compression(String),
+    Io(io::Error),
+}
+
+impl ProtocolError {
+    // a read timed out, see timeout
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, ProtocolError::Io(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut))
+    }
 }
 
-impl WrappedStream {
-    pub fn new(stream: TcpStream) -> Self {
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Disconnected => write!(f, "the connection was closed"),
+            ProtocolError::OversizedFrame { message, size, limit } => write!(f, "the {} frame of {} bytes exceeds the limit of {} bytes", message, size, limit),
+            ProtocolError::InvalidBson(e) => write!(f, "invalid bson: {}", e),
+            ProtocolError::UnexpectedMessage(message) => write!(f, "unexpected message: {}", message),
+            ProtocolError::Plugin(message) => write!(f, "the plugin reported an error: {}", message),
+            ProtocolError::Compression(e) => write!(f, "invalid compressed frame: {}", e),
+            ProtocolError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<io::Error> for ProtocolError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted | io::ErrorKind::BrokenPipe => ProtocolError::Disconnected,
+            _ => ProtocolError::Io(e),
+        }
+    }
+}
+
+impl From<bson::de::Error> for ProtocolError {
+    fn from(e: bson::de::Error) -> Self {
+        ProtocolError::InvalidBson(e)
+    }
+}
+
+// the reads are buffered, writes go straight to the connection (see tokio's BufReader)
+// with a compression (see set_compression) the payload of every frame is compressed, the
+// length in front of it is the compressed one
+// with a recorder (see record) everything sent and received is recorded as it goes over the connection
+pub struct WrappedStream<T: Transport = Stream> {
+    stream: BufReader<T>,
+    limits: FrameLimits,
+    compressor: Option<Compressor>,
+    recorder: Option<Recorder>,
+}
+
+impl<T: Transport> WrappedStream<T> {
+    pub fn new(stream: T) -> Self {
+        WrappedStream::with_limits(stream, FrameLimits::default())
+    }
+
+    pub fn with_limits(stream: T, limits: FrameLimits) -> Self {
         WrappedStream {
-            reader: BufReader::new(stream.try_clone().unwrap()),
-            stream,
-            length_buffer: [0 as u8; 4],
+            stream: BufReader::new(stream),
+            limits,
+            compressor: None,
+            recorder: None,
+        }
+    }
+
+    pub fn record(&mut self, recorder: Recorder) {
+        self.recorder = Some(recorder);
+    }
+
+    fn record_entry(&mut self, direction: Direction, kind: EntryKind, data: &[u8]) {
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record(direction, kind, data);
         }
     }
 
-    pub fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
-        self.stream.write(data)
+    // compresses the frames from now on, the sizes are counted in the compression stats of name
+    pub fn set_compression(&mut self, compression: Option<Compression>, name: &str) {
+        self.compressor = compression.map(|c| Compressor::new(c, name));
     }
 
-    pub fn send_with_32bit_integer_length(&mut self, buffer: Vec<u8>) -> std::io::Result<usize> {
-        self.stream.write(u32::to_le_bytes(buffer.len() as u32).as_ref())?;
-        self.stream.write(buffer.as_slice())
+    pub async fn write(&mut self, data: &[u8]) -> Result<(), ProtocolError> {
+        self.stream.write_all(data).await?;
+        self.record_entry(Direction::Sent, EntryKind::Raw, data);
+        Ok(())
     }
 
-    pub fn send_bson(&mut self, doc: &Document) -> std::io::Result<usize> {
+    pub async fn send_with_32bit_integer_length(&mut self, buffer: Vec<u8>) -> Result<usize, ProtocolError> {
+        let buffer = match &self.compressor {
+            Some(compressor) => compressor.compress(&buffer),
+            None => buffer,
+        };
+
+        self.stream.write_all(frame_length(buffer.len())?.to_le_bytes().as_ref()).await?;
+        self.stream.write_all(buffer.as_slice()).await?;
+        self.record_entry(Direction::Sent, EntryKind::Frame, &buffer);
+        Ok(buffer.len())
+    }
+
+    pub async fn send_bson(&mut self, doc: &Document) -> Result<usize, ProtocolError> {
         let mut buffer = Vec::new();
-        doc.to_writer(&mut buffer).unwrap();
-        self.send_with_32bit_integer_length(buffer)
+        doc.to_writer(&mut buffer).map_err(|e| ProtocolError::Io(io::Error::other(e)))?;
+        self.send_with_32bit_integer_length(buffer).await
     }
 
-    pub fn recv_32bit_integer(&mut self) -> u32 {
-        self.reader.read_exact(&mut self.length_buffer).unwrap();
-        u32::from_le_bytes(self.length_buffer)
+    pub async fn recv_32bit_integer(&mut self) -> Result<u32, ProtocolError> {
+        let integer = self.stream.read_u32_le().await?;
+        self.record_entry(Direction::Received, EntryKind::Raw, &integer.to_le_bytes());
+        Ok(integer)
     }
 
-    pub fn recv_based_on_32bit_integer(&mut self) -> Vec<u8> {
-        let data_size = self.recv_32bit_integer();
+    // exactly len bytes, without any framing (for replaying the protocol before version 2)
+    pub async fn recv_raw(&mut self, len: usize) -> Result<Vec<u8>, ProtocolError> {
+        let mut buf = vec![0u8; len];
+        self.stream.read_exact(&mut buf).await?;
+        self.record_entry(Direction::Received, EntryKind::Raw, &buf);
+        Ok(buf)
+    }
+
+    // a compressed frame is checked against the limit twice, with its compressed size and with
+    // the decompressed size it declares
+    pub async fn recv_based_on_32bit_integer(&mut self, message: MessageType) -> Result<Vec<u8>, ProtocolError> {
+        let data_size = self.stream.read_u32_le().await?;
+        let limit = message.limit(&self.limits);
+        if data_size > limit {
+            return Err(ProtocolError::OversizedFrame { message, size: data_size, limit });
+        }
+
         let mut buf = vec![0u8; data_size as usize];
-        self.reader.read_exact(&mut buf).unwrap();
-        buf
+        self.stream.read_exact(&mut buf).await?;
+        self.record_entry(Direction::Received, EntryKind::Frame, &buf);
+
+        match &self.compressor {
+            Some(compressor) => compressor.decompress(&buf, message, limit),
+            None => Ok(buf),
+        }
+    }
+
+    pub async fn recv_bson(&mut self, message: MessageType) -> Result<Document, ProtocolError> {
+        let buf = self.recv_based_on_32bit_integer(message).await?;
+        Ok(bson::from_slice::<Utf8LossyDeserialization<Document>>(buf.as_slice())?.0)
+    }
+}
+
+// the length in front of a frame has 32 bits, a bigger frame is refused instead of being cut off
+fn frame_length(len: usize) -> Result<u32, ProtocolError> {
+    u32::try_from(len).map_err(|_| ProtocolError::Io(io::Error::new(io::ErrorKind::InvalidInput, format!("a frame of {} bytes does not fit into the 32 bit length", len))))
+}
+
+// gives up on future after duration, with an error for which ProtocolError::is_timeout is true
+pub async fn timeout<T>(duration: Duration, future: impl Future<Output = Result<T, ProtocolError>>) -> Result<T, ProtocolError> {
+    tokio::time::timeout(duration, future).await
+        .unwrap_or_else(|_| Err(ProtocolError::Io(io::Error::new(io::ErrorKind::TimedOut, format!("no answer within {:?}", duration)))))
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::doc;
+    use tokio::net::UnixStream;
+
+    use super::*;
+
+    fn limits() -> FrameLimits {
+        FrameLimits { hello_bytes: 64, image_bytes: 1024, data_bytes: 256 }
+    }
+
+    fn pair() -> (WrappedStream<UnixStream>, WrappedStream<UnixStream>) {
+        let (a, b) = UnixStream::pair().unwrap();
+        (WrappedStream::with_limits(a, limits()), WrappedStream::with_limits(b, limits()))
+    }
+
+    #[tokio::test]
+    async fn documents_go_through_the_framing() {
+        let (mut core, mut plugin) = pair();
+        plugin.send_bson(&doc! { "type": "ping" }).await.unwrap();
+        assert_eq!(core.recv_bson(MessageType::Data).await.unwrap(), doc! { "type": "ping" });
+    }
+
+    #[tokio::test]
+    async fn an_oversized_frame_is_refused_by_its_length_alone() {
+        let (mut core, mut plugin) = pair();
+        // only the length is sent, the frame itself never comes
+        plugin.write(&257u32.to_le_bytes()).await.unwrap();
+        match core.recv_based_on_32bit_integer(MessageType::Data).await {
+            Err(error @ ProtocolError::OversizedFrame { .. }) => {
+                assert!(matches!(error, ProtocolError::OversizedFrame { message: MessageType::Data, size: 257, limit: 256 }));
+                count_rejected_frame("oversized", &error);
+                assert!(rejected_frames().contains(&("oversized".to_string(), MessageType::Data, 1)));
+            }
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn every_type_of_message_has_its_own_limit() {
+        let (mut core, mut plugin) = pair();
+        plugin.send_with_32bit_integer_length(vec![0; 65]).await.unwrap();
+        assert!(matches!(core.recv_based_on_32bit_integer(MessageType::Hello).await, Err(ProtocolError::OversizedFrame { limit: 64, .. })));
+
+        let (mut core, mut plugin) = pair();
+        plugin.send_with_32bit_integer_length(vec![0; 1024]).await.unwrap();
+        assert_eq!(core.recv_based_on_32bit_integer(MessageType::Image).await.unwrap().len(), 1024);
+    }
+
+    #[tokio::test]
+    async fn compressed_frames_are_checked_with_their_decompressed_size() {
+        for compression in [Compression::Zstd, Compression::Lz4] {
+            let (mut core, mut plugin) = pair();
+            core.set_compression(Some(compression), "wrapped-stream-test");
+            plugin.set_compression(Some(compression), "wrapped-stream-test");
+
+            // compresses to far less than the limit
+            let sent = plugin.send_with_32bit_integer_length(vec![7; 1024]).await.unwrap();
+            assert!(sent < 256, "{}", compression);
+            assert_eq!(core.recv_based_on_32bit_integer(MessageType::Image).await.unwrap(), vec![7; 1024]);
+
+            plugin.send_with_32bit_integer_length(vec![7; 1025]).await.unwrap();
+            assert!(matches!(core.recv_based_on_32bit_integer(MessageType::Image).await, Err(ProtocolError::OversizedFrame { size: 1025, .. })), "{}", compression);
+        }
+    }
+
+    #[test]
+    fn frames_of_4_gib_and_more_are_refused() {
+        assert_eq!(frame_length(u32::MAX as usize).unwrap(), u32::MAX);
+        assert!(matches!(frame_length(u32::MAX as usize + 1), Err(ProtocolError::Io(e)) if e.kind() == io::ErrorKind::InvalidInput));
+    }
+
+    #[tokio::test]
+    async fn a_closed_connection_is_a_disconnect() {
+        let (mut core, plugin) = pair();
+        drop(plugin);
+        assert!(matches!(core.recv_bson(MessageType::Data).await, Err(ProtocolError::Disconnected)));
+
+        // also in the middle of a frame
+        let (mut core, mut plugin) = pair();
+        plugin.write(&[16, 0, 0, 0, 1, 2]).await.unwrap();
+        drop(plugin);
+        assert!(matches!(core.recv_bson(MessageType::Data).await, Err(ProtocolError::Disconnected)));
+    }
+
+    #[tokio::test]
+    async fn garbage_is_invalid_bson() {
+        let (mut core, mut plugin) = pair();
+        plugin.send_with_32bit_integer_length(vec![1, 2, 3, 4, 5]).await.unwrap();
+        assert!(matches!(core.recv_bson(MessageType::Data).await, Err(ProtocolError::InvalidBson(_))));
     }
 
-    pub fn recv_bson(&mut self) -> Document {
-        let buf = self.recv_based_on_32bit_integer();
-        Document::from_reader_utf8_lossy(buf.as_slice()).expect("invalid bson received from data client")
+    #[tokio::test]
+    async fn a_silent_peer_times_out() {
+        let (mut core, _plugin) = pair();
+        let error = timeout(Duration::from_millis(10), core.recv_bson(MessageType::Data)).await.unwrap_err();
+        assert!(error.is_timeout());
+        assert!(!ProtocolError::Disconnected.is_timeout());
     }
 }
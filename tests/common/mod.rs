@@ -0,0 +1,443 @@
+// the harness of the integration tests: the core runs in-process with a config built in code,
+// fake plugins and a fake gui talk to it over the real sockets
+// the fake input plugins are built on the plugin sdk (palleon-plugin) and run in threads of their
+// own, the fakes that have to misbehave (and the gui) speak the handshake, the hello and the
+// messages of protocol version 2 (see protocol) themselves on the same tokio runtime as the core
+// every fake reports what it got on a channel, the tests wait on those with recv
+
+// not every test file uses every part of the harness
+#![allow(dead_code)]
+
+use std::{env, fs, process, thread};
+use std::collections::HashMap;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use bson::{doc, Bson, Document};
+use flume::{unbounded, Receiver, Sender};
+use indexmap::IndexMap;
+use tokio::runtime::Runtime;
+
+use palleon_plugin::{Endpoint, InputPlugin, PluginResult, Runner};
+
+use palleon_core::auth;
+use palleon_core::config::{Config, PluginConfig, PluginKind, PluginMode, PluginTransport, RestartConfig};
+use palleon_core::image::{FrameMetadata, Image};
+use palleon_core::protocol::Message;
+use palleon_core::shutdown::ShutdownSignal;
+use palleon_core::transport::Address;
+use palleon_core::wrapped_stream::{MessageType, ProtocolError, WrappedStream};
+
+// the token of every plugin and the gui
+pub const TOKEN: &str = "palleon-test";
+// how long a test waits for something the core is expected to do
+pub const WAIT: Duration = Duration::from_secs(10);
+
+static NEXT_RUNTIME_DIR: AtomicUsize = AtomicUsize::new(0);
+
+// a config without plugins, listening on a free port for the gui, with a runtime_dir of its own
+pub fn config() -> Config {
+    let runtime_dir = env::temp_dir().join(format!("palleon-test-{}-{}", process::id(), NEXT_RUNTIME_DIR.fetch_add(1, Ordering::SeqCst)));
+
+    Config {
+        bind_addr: "127.0.0.1".to_string(),
+        port_allocation: Default::default(),
+        bind_port_range_start: None,
+        bind_port_gui: free_port(),
+        gui_token: Some(TOKEN.to_string()),
+        gui_compression: vec![],
+        runtime_dir: Some(runtime_dir),
+        shutdown_grace_period_ms: 1000,
+        data_plugins: IndexMap::new(),
+        input_plugins: IndexMap::new(),
+        kept_ports: HashMap::new(),
+    }
+}
+
+// a plugin the fakes connect to: listen mode on a unix socket in the runtime_dir, restarted quickly
+pub fn plugin() -> PluginConfig {
+    PluginConfig {
+        mode: PluginMode::Listen,
+        transport: PluginTransport::Unix,
+        token: Some(TOKEN.to_string()),
+        restart: RestartConfig { initial_backoff_ms: 50, ..RestartConfig::default() },
+        ..PluginConfig::default()
+    }
+}
+
+// the os picks the port, which is free again right after
+fn free_port() -> i32 {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("binding a free port failed");
+    listener.local_addr().unwrap().port() as i32
+}
+
+// waits up to WAIT for the next report of a fake
+pub fn recv<T>(rx: &Receiver<T>) -> T {
+    rx.recv_timeout(WAIT).expect("the core did not get in touch in time")
+}
+
+// what an input plugin answers to the requests of the core, one step per request
+pub enum InputStep {
+    Frame(Vec<u8>),
+    FrameWith(Vec<u8>, FrameMetadata),
+    NoFrame,
+    // no frame, but there will be one after that long (retry_after_ms)
+    NoFrameFor(Duration),
+    // fail instead of answering, which ends the connection, and connect again (once the core
+    // listens again)
+    Disconnect,
+}
+
+// what a data plugin does with an image, one step per image
+pub enum DataStep {
+    Result(Document),
+    // answer after a while
+    Delay(Duration, Document),
+    // close the connection without answering and never come back
+    Crash,
+    // never answer, but keep the connection open
+    Hang,
+    // send an error message instead of a result, which ends the connection as well
+    Error(String),
+}
+
+// the images and the data of an update the fake gui got, see TestCore::gui
+pub type GuiUpdate = (Vec<Image>, Vec<Bson>);
+
+// what a fake data plugin got for one image
+#[derive(Debug)]
+pub struct Received {
+    pub dependency_data: Document,
+    pub image: Image,
+}
+
+// a running core, stopped (and its runtime_dir removed) when dropped at the latest
+pub struct TestCore {
+    // where the fakes connect to, taken from the config before it is handed to the core
+    plugin_addresses: HashMap<(PluginKind, String), Address>,
+    gui_address: Address,
+    runtime_dir: PathBuf,
+    shutdown: ShutdownSignal,
+    runtime: Option<Runtime>,
+    core: Option<thread::JoinHandle<i32>>,
+}
+
+impl TestCore {
+    pub fn start(cfg: Config) -> TestCore {
+        let _ = env_logger::builder().is_test(true).try_init();
+
+        let runtime = Runtime::new().expect("starting the async runtime failed");
+        let shutdown = ShutdownSignal::new();
+
+        let plugin_addresses = cfg.plugins()
+            .map(|(kind, name, plugin)| ((kind, name.clone()), cfg.plugin_address(kind, name, plugin, 0)))
+            .collect();
+        let gui_address = Address::Tcp(format!("{}:{}", cfg.bind_addr, cfg.bind_port_gui));
+        let runtime_dir = cfg.runtime_dir();
+
+        let core = {
+            let (shutdown, handle) = (shutdown.clone(), runtime.handle().clone());
+            thread::spawn(move || {
+                let _runtime = handle.enter();
+                palleon_core::run(cfg, None, shutdown)
+            })
+        };
+
+        TestCore { plugin_addresses, gui_address, runtime_dir, shutdown, runtime: Some(runtime), core: Some(core) }
+    }
+
+    // starts a fake input plugin, which answers the requests of the core with steps and with
+    // NoFrame once they are used up
+    pub fn input(&self, name: &str, steps: Vec<InputStep>) {
+        let runner = self.runner(PluginKind::Input, name);
+        thread::spawn(move || fake_input(runner, steps));
+    }
+
+    // a runner of the plugin sdk for the plugin name of the config
+    pub fn runner(&self, kind: PluginKind, name: &str) -> Runner {
+        let endpoint = match self.address(kind, name) {
+            Address::Tcp(addr) => Endpoint::Tcp(addr),
+            Address::Unix(path) => Endpoint::Unix(path),
+        };
+        Runner::new(name, "test").connect_to(endpoint).token(TOKEN)
+    }
+
+    // starts a fake input plugin in the push mode, which sends the frames as fast as its credits
+    // allow (through shared memory if the core accepts it) and returns what the core accepted in
+    // the hello
+    pub fn push_input(&self, name: &str, frames: Vec<Vec<u8>>) -> Receiver<Document> {
+        let address = self.address(PluginKind::Input, name);
+        let (tx, rx) = unbounded();
+        self.runtime().spawn(fake_push_input(address, name.to_string(), frames, tx));
+        rx
+    }
+
+    // starts a fake data plugin with the dependencies {name: nr of values}, which handles the
+    // images with steps and answers with {"count": <nr of images so far>} once they are used up
+    // the core skips data plugins that are not connected, so it returns once the hello is done
+    pub fn data(&self, name: &str, dependencies: &[(&str, i32)], steps: Vec<DataStep>) -> Receiver<Received> {
+        let address = self.address(PluginKind::Data, name);
+        let dependencies: Document = dependencies.iter().map(|(name, values)| (name.to_string(), Bson::Int32(*values))).collect();
+        let (tx, rx) = unbounded();
+        let (stream, _) = self.runtime().block_on(connect_plugin(&address, name, &Document::new(), &doc! { "image": true, "dependencies": dependencies }));
+        self.runtime().spawn(fake_data(stream, steps, tx));
+        rx
+    }
+
+    // connects a fake gui and returns the updates it gets as (images, data)
+    // data is [[<data plugin>, <input source>, <timestamp>, <value>], ..]
+    pub fn gui(&self) -> Receiver<GuiUpdate> {
+        self.gui_with_status().0
+    }
+
+    // like gui, with the statuses the gui gets as well, i.e. the idle input plugins
+    pub fn gui_with_status(&self) -> (Receiver<GuiUpdate>, Receiver<Vec<String>>) {
+        let (tx, rx) = unbounded();
+        let (status_tx, status_rx) = unbounded();
+
+        // the gui only gets what happens after it connected, so the handshake is waited for
+        let mut stream = self.runtime().block_on(connect(&self.gui_address));
+        self.runtime().spawn(async move {
+            loop {
+                match recv_message(&mut stream).await {
+                    Ok(Message::Update { images, data }) => {
+                        if tx.send((images, data)).is_err() {
+                            return;
+                        }
+                    }
+                    // nobody may be interested in those
+                    Ok(Message::Status { idle }) => { let _ = status_tx.send(idle); }
+                    _ => return,
+                }
+            }
+        });
+
+        (rx, status_rx)
+    }
+
+    // requests a shutdown and returns the exit code of the core
+    pub fn stop(mut self) -> i32 {
+        self.shut_down()
+    }
+
+    fn shut_down(&mut self) -> i32 {
+        let Some(core) = self.core.take() else { return palleon_core::EXIT_OK; };
+
+        self.shutdown.request();
+        let exit_code = core.join().unwrap_or(palleon_core::EXIT_ERROR);
+
+        // ends the gui connector and the fakes
+        drop(self.runtime.take());
+        let _ = fs::remove_dir_all(&self.runtime_dir);
+
+        exit_code
+    }
+
+    fn runtime(&self) -> &Runtime {
+        self.runtime.as_ref().expect("the core is stopped")
+    }
+
+    fn address(&self, kind: PluginKind, name: &str) -> Address {
+        self.plugin_addresses.get(&(kind, name.to_string()))
+            .unwrap_or_else(|| panic!("there is no {} plugin {:?} in the config", kind, name))
+            .clone()
+    }
+}
+
+impl Drop for TestCore {
+    fn drop(&mut self) {
+        self.shut_down();
+    }
+}
+
+// connects and authenticates, retrying until the core listens (the plugins are started after the
+// fakes, and a restarted plugin listens only after its backoff)
+async fn connect(address: &Address) -> WrappedStream {
+    loop {
+        if let Ok(stream) = address.connect(WAIT).await {
+            let mut stream = WrappedStream::new(stream);
+            if auth::present(&mut stream, TOKEN).await.is_ok() {
+                return stream;
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+// connects like a plugin of protocol version 2, fields are added to the hello
+// returns the stream and what the core accepted
+async fn connect_plugin(address: &Address, name: &str, capabilities: &Document, fields: &Document) -> (WrappedStream, Document) {
+    loop {
+        let mut stream = connect(address).await;
+
+        let mut hello = doc! { "protocol_version": 2, "name": name, "version": "test", "capabilities": capabilities.clone() };
+        hello.extend(fields.clone());
+        if stream.send_bson(&hello).await.is_err() {
+            continue;
+        }
+        if let Ok(answer) = stream.recv_bson(MessageType::Hello).await {
+            return (stream, answer.get_document("accepted").cloned().unwrap_or_default());
+        }
+    }
+}
+
+async fn send_message(stream: &mut WrappedStream, message: &Message) -> Result<(), ProtocolError> {
+    stream.send_with_32bit_integer_length(message.encode()?).await.map(|_| ())
+}
+
+async fn recv_message(stream: &mut WrappedStream) -> Result<Message, ProtocolError> {
+    Message::from_document(stream.recv_bson(MessageType::Image).await?)
+}
+
+// the fake input plugin on the sdk, see fake_input
+struct ScriptedInput {
+    steps: std::vec::IntoIter<InputStep>,
+    // of the frame or the no frame just returned
+    metadata: Document,
+    retry_after: Option<Duration>,
+    // whether the core asked for a frame on this connection
+    asked: bool,
+    disconnecting: bool,
+}
+
+impl InputPlugin for ScriptedInput {
+    fn next_frame(&mut self) -> PluginResult<Option<Vec<u8>>> {
+        self.asked = true;
+        match self.steps.next() {
+            Some(InputStep::Frame(data)) => Ok(Some(data)),
+            Some(InputStep::FrameWith(data, metadata)) => {
+                self.metadata = metadata.to_document();
+                Ok(Some(data))
+            }
+            Some(InputStep::NoFrame) | None => Ok(None),
+            Some(InputStep::NoFrameFor(retry_after)) => {
+                self.retry_after = Some(retry_after);
+                Ok(None)
+            }
+            Some(InputStep::Disconnect) => {
+                self.disconnecting = true;
+                Err("disconnecting as scripted".into())
+            }
+        }
+    }
+
+    fn metadata(&mut self) -> Document {
+        std::mem::take(&mut self.metadata)
+    }
+
+    fn retry_after(&mut self) -> Option<Duration> {
+        self.retry_after.take()
+    }
+}
+
+// runs the sdk's input runner with the steps, connecting until the core listens (for up to WAIT)
+// ends on a shutdown, an error and a closed connection
+fn fake_input(runner: Runner, steps: Vec<InputStep>) {
+    let mut plugin = ScriptedInput { steps: steps.into_iter(), metadata: Document::new(), retry_after: None, asked: false, disconnecting: false };
+    let mut connecting_since = Instant::now();
+
+    loop {
+        if runner.run_input(&mut plugin).is_ok() {
+            return;
+        }
+
+        if plugin.disconnecting {
+            plugin.disconnecting = false;
+            connecting_since = Instant::now();
+        } else if plugin.asked || connecting_since.elapsed() > WAIT {
+            return;
+        }
+        plugin.asked = false;
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+// waits for a credit whenever it has none left, ends like fake_input
+// with shared memory the slots are used round robin, a slot is free again once the credit for
+// its frame came back
+async fn fake_push_input(address: Address, name: String, frames: Vec<Vec<u8>>, tx: Sender<Document>) {
+    let (mut stream, accepted) = connect_plugin(&address, &name, &doc! { "push": true, "shm": true }, &Document::new()).await;
+    let mut credits = accepted.get_i32("credits").unwrap_or_default();
+    let shm = accepted.get_document("shm").ok().map(|shm| {
+        let file = fs::OpenOptions::new().write(true).open(shm.get_str("path").unwrap()).unwrap();
+        (file, shm.get_i64("slots").unwrap() as u64, shm.get_i64("slot_bytes").unwrap() as u64)
+    });
+    let _ = tx.send(accepted);
+
+    for (i, data) in frames.into_iter().enumerate() {
+        while credits == 0 {
+            match recv_message(&mut stream).await {
+                Ok(Message::Credit { frames }) => credits += frames as i32,
+                _ => return,
+            }
+        }
+        let sent = match &shm {
+            Some((file, slots, slot_bytes)) => {
+                let slot = i as u64 % slots;
+                file.write_all_at(&data, slot * slot_bytes).unwrap();
+                stream.send_bson(&doc! { "type": "frame", "shm": { "slot": slot as i64, "size": data.len() as i64 } }).await.map(|_| ())
+            }
+            None => send_message(&mut stream, &Message::Frame { data, metadata: FrameMetadata::default() }).await,
+        };
+        if sent.is_err() {
+            return;
+        }
+        credits -= 1;
+    }
+
+    // the remaining credits until the shutdown
+    while let Ok(Message::Credit { .. }) = recv_message(&mut stream).await {}
+}
+
+// ends like fake_input, or after a crash or an error
+async fn fake_data(mut stream: WrappedStream, steps: Vec<DataStep>, tx: Sender<Received>) {
+    let mut steps = steps.into_iter();
+    let mut count = 0;
+
+    // the dependency data comes right before its image
+    let mut dependency_data = Document::new();
+
+    loop {
+        let image = match recv_message(&mut stream).await {
+            Ok(Message::DependencyData(data)) => {
+                dependency_data = data;
+                continue;
+            }
+            Ok(Message::Image { image, .. }) => image,
+            Ok(Message::Ping) => {
+                if send_message(&mut stream, &Message::Pong).await.is_err() {
+                    return;
+                }
+                continue;
+            }
+            _ => return,
+        };
+
+        count += 1;
+        let _ = tx.send(Received { dependency_data: std::mem::take(&mut dependency_data), image });
+
+        let answer = match steps.next() {
+            Some(DataStep::Result(result)) => Message::Result(result),
+            Some(DataStep::Delay(delay, result)) => {
+                tokio::time::sleep(delay).await;
+                Message::Result(result)
+            }
+            Some(DataStep::Crash) => return,
+            Some(DataStep::Hang) => {
+                // until the core closes the connection
+                while recv_message(&mut stream).await.is_ok() {}
+                return;
+            }
+            Some(DataStep::Error(message)) => {
+                let _ = send_message(&mut stream, &Message::Error { message }).await;
+                return;
+            }
+            None => Message::Result(doc! { "count": count }),
+        };
+        if send_message(&mut stream, &answer).await.is_err() {
+            return;
+        }
+    }
+}
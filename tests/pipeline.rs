@@ -0,0 +1,303 @@
+// the way of a frame through the core: from the input plugins to every data plugin (together with
+// the values of its dependencies) and to the gui, also when plugins misbehave
+
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use bson::{doc, Bson};
+use palleon_core::config::{IdleBackoff, IdleConfig, PluginConfig, ShmConfig, TimestampConfig, TimestampKey};
+use palleon_core::image::FrameMetadata;
+
+use common::{config, plugin, recv, DataStep, InputStep, TestCore};
+
+mod common;
+
+#[test]
+fn every_data_plugin_gets_every_frame() {
+    let mut cfg = config();
+    cfg.input_plugins.insert("camera".to_string(), plugin());
+    cfg.data_plugins.insert("a".to_string(), plugin());
+    cfg.data_plugins.insert("b".to_string(), plugin());
+
+    let core = TestCore::start(cfg);
+    let a = core.data("a", &[], vec![]);
+    let b = core.data("b", &[], vec![]);
+    core.input("camera", vec![InputStep::Frame(vec![1]), InputStep::Frame(vec![2]), InputStep::Frame(vec![3])]);
+
+    for received in [a, b] {
+        for frame in 1..=3u8 {
+            let image = recv(&received).image;
+            assert_eq!(image.data.as_ref(), [frame]);
+            assert_eq!(image.input_source, "camera");
+        }
+    }
+
+    assert_eq!(core.stop(), palleon_core::EXIT_OK);
+}
+
+#[test]
+fn an_input_plugin_in_the_push_mode_sends_as_many_frames_as_it_has_credits() {
+    let mut cfg = config();
+    cfg.input_plugins.insert("camera".to_string(), PluginConfig { push_credits: 2, ..plugin() });
+    cfg.data_plugins.insert("a".to_string(), plugin());
+
+    let core = TestCore::start(cfg);
+    let a = core.data("a", &[], vec![]);
+    let accepted = core.push_input("camera", (1..=6).map(|i| vec![i]).collect());
+
+    assert_eq!(recv(&accepted).get_i32("credits"), Ok(2));
+    // a frame without a credit would end the connection, so all of them arriving means the
+    // credits came back in time
+    for frame in 1..=6u8 {
+        assert_eq!(recv(&a).image.data.as_ref(), [frame]);
+    }
+
+    assert_eq!(core.stop(), palleon_core::EXIT_OK);
+}
+
+#[test]
+fn a_pushing_input_plugin_reuses_a_slot_of_the_shared_memory_once_its_credit_is_back() {
+    let mut cfg = config();
+    cfg.input_plugins.insert("camera".to_string(), PluginConfig { push_credits: 2, shm: Some(ShmConfig { slots: 2, slot_bytes: 16 }), ..plugin() });
+    cfg.data_plugins.insert("a".to_string(), plugin());
+
+    let core = TestCore::start(cfg);
+    // a slow data plugin, so the camera always has its frames in flight
+    let a = core.data("a", &[], (1..=8).map(|_| DataStep::Delay(Duration::from_millis(20), doc! {})).collect());
+    let accepted = core.push_input("camera", (1..=8).map(|i| vec![i; 16]).collect());
+
+    let accepted = recv(&accepted);
+    assert_eq!(accepted.get_i32("credits"), Ok(2));
+    assert_eq!(accepted.get_document("shm").unwrap().get_i64("slots"), Ok(2));
+    // every slot is written 4 times, no frame was overwritten before the core copied it out
+    for frame in 1..=8u8 {
+        assert_eq!(recv(&a).image.data.as_ref(), [frame; 16]);
+    }
+
+    assert_eq!(core.stop(), palleon_core::EXIT_OK);
+}
+
+#[test]
+fn frames_of_every_input_plugin_arrive() {
+    let mut cfg = config();
+    cfg.input_plugins.insert("left".to_string(), plugin());
+    cfg.input_plugins.insert("right".to_string(), plugin());
+    cfg.data_plugins.insert("a".to_string(), plugin());
+
+    let core = TestCore::start(cfg);
+    let a = core.data("a", &[], vec![]);
+    core.input("left", vec![InputStep::Frame(vec![1])]);
+    core.input("right", vec![InputStep::Frame(vec![2])]);
+
+    let mut sources: Vec<_> = (0..2).map(|_| recv(&a).image.input_source).collect();
+    sources.sort();
+    assert_eq!(sources, ["left", "right"]);
+}
+
+#[test]
+fn dependencies_get_the_last_values_per_input_source() {
+    let mut cfg = config();
+    cfg.input_plugins.insert("camera".to_string(), plugin());
+    cfg.data_plugins.insert("a".to_string(), plugin());
+    cfg.data_plugins.insert("b".to_string(), plugin());
+
+    let core = TestCore::start(cfg);
+    let _a = core.data("a", &[], (1..=3).map(|i| DataStep::Result(doc! { "value": i })).collect());
+    let b = core.data("b", &[("a", 2)], vec![]);
+    core.input("camera", (1..=3).map(|i| InputStep::Frame(vec![i])).collect());
+
+    // the values of a dependency are collected when the image is sent, so they are the ones of
+    // the frames before, the newest first
+    let values = |received: common::Received| -> Vec<i32> {
+        received.dependency_data.get_array("a").map(|values| values.iter()
+            .map(|value| {
+                let value = value.as_array().expect("a value is [<timestamp>, <value>]");
+                assert!(matches!(value[0], Bson::DateTime(_)));
+                value[1].as_document().unwrap().get_i32("value").unwrap()
+            })
+            .collect()
+        ).unwrap_or_default()
+    };
+    assert_eq!(values(recv(&b)), Vec::<i32>::new());
+    assert_eq!(values(recv(&b)), [1]);
+    assert_eq!(values(recv(&b)), [2, 1]);
+}
+
+#[test]
+fn the_gui_gets_the_images_and_the_results() {
+    let mut cfg = config();
+    cfg.input_plugins.insert("camera".to_string(), plugin());
+    cfg.data_plugins.insert("a".to_string(), plugin());
+
+    let metadata = FrameMetadata {
+        width: Some(4),
+        height: Some(4),
+        pixel_format: Some("gray8".to_string()),
+        encoding: Some("raw".to_string()),
+        // bson date times have milliseconds
+        capture_time: Some(UNIX_EPOCH + Duration::from_millis(1_700_000_000_123)),
+        sequence: Some(42),
+        tags: doc! { "exposure_us": 8000 },
+    };
+
+    let core = TestCore::start(cfg);
+    let gui = core.gui();
+    let a = core.data("a", &[], vec![DataStep::Result(doc! { "value": 1 })]);
+    core.input("camera", vec![InputStep::FrameWith(vec![7; 16], metadata.clone())]);
+
+    assert_eq!(recv(&a).image.metadata, metadata);
+
+    // images and results may arrive in one update or in several
+    let (mut images, mut data) = (vec![], vec![]);
+    while images.is_empty() || data.is_empty() {
+        let update = recv(&gui);
+        images.extend(update.0);
+        data.extend(update.1);
+    }
+
+    assert_eq!(images[0].data.as_ref(), [7; 16]);
+    assert_eq!(images[0].input_source, "camera");
+    assert_eq!(images[0].metadata, metadata);
+    let datum = data[0].as_array().expect("a datum is [<data plugin>, <input source>, <timestamp>, <value>]");
+    assert_eq!(datum[0].as_str(), Some("a"));
+    assert_eq!(datum[1].as_str(), Some("camera"));
+    assert_eq!(datum[3].as_document(), Some(&doc! { "value": 1 }));
+}
+
+#[test]
+fn images_are_stamped_with_the_capture_time_unless_the_config_says_otherwise() {
+    let mut cfg = config();
+    cfg.input_plugins.insert("capture".to_string(), plugin());
+    let timestamps = TimestampConfig { key: TimestampKey::Arrival, ..TimestampConfig::default() };
+    cfg.input_plugins.insert("arrival".to_string(), PluginConfig { timestamps, ..plugin() });
+    cfg.data_plugins.insert("a".to_string(), plugin());
+
+    let captured = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+    let metadata = FrameMetadata { capture_time: Some(captured), ..FrameMetadata::default() };
+
+    let core = TestCore::start(cfg);
+    let a = core.data("a", &[], vec![]);
+    core.input("capture", vec![InputStep::FrameWith(vec![1], metadata.clone())]);
+    core.input("arrival", vec![InputStep::FrameWith(vec![2], metadata)]);
+
+    for _ in 0..2 {
+        let image = recv(&a).image;
+        assert!(image.arrival_time > captured);
+        match image.input_source.as_str() {
+            "capture" => assert_eq!(image.timestamp, captured),
+            _ => assert_eq!(image.timestamp, image.arrival_time),
+        }
+    }
+}
+
+#[test]
+fn an_input_plugin_that_has_no_frame_or_disconnects_is_asked_again() {
+    let mut cfg = config();
+    cfg.input_plugins.insert("camera".to_string(), plugin());
+    cfg.data_plugins.insert("a".to_string(), plugin());
+
+    let core = TestCore::start(cfg);
+    let a = core.data("a", &[], vec![]);
+    core.input("camera", vec![
+        InputStep::NoFrame,
+        InputStep::Frame(vec![1]),
+        // the plugin is restarted by the supervisor, which listens again for it after the backoff
+        InputStep::Disconnect,
+        InputStep::Frame(vec![2]),
+    ]);
+
+    assert_eq!(recv(&a).image.data.as_ref(), [1]);
+    assert_eq!(recv(&a).image.data.as_ref(), [2]);
+}
+
+#[test]
+fn an_input_plugin_without_a_frame_is_asked_again_after_the_idle_backoff() {
+    let idle = |backoff, initial_backoff_ms| IdleConfig { backoff, initial_backoff_ms, max_backoff_ms: 5000, ..IdleConfig::default() };
+    let mut cfg = config();
+    cfg.input_plugins.insert("fixed".to_string(), PluginConfig { idle: idle(IdleBackoff::Fixed, 10), ..plugin() });
+    cfg.input_plugins.insert("exponential".to_string(), PluginConfig { idle: idle(IdleBackoff::Exponential, 10), ..plugin() });
+    // the retry_after of the plugin takes precedence over the backoff
+    cfg.input_plugins.insert("suggesting".to_string(), PluginConfig { idle: idle(IdleBackoff::Fixed, 5000), ..plugin() });
+    cfg.data_plugins.insert("a".to_string(), plugin());
+
+    let core = TestCore::start(cfg);
+    let a = core.data("a", &[], vec![]);
+    let started = Instant::now();
+    core.input("fixed", vec![InputStep::NoFrame, InputStep::NoFrame, InputStep::NoFrame, InputStep::Frame(vec![1])]);
+    // 10 + 20 + 40 ms
+    core.input("exponential", vec![InputStep::NoFrame, InputStep::NoFrame, InputStep::NoFrame, InputStep::Frame(vec![2])]);
+    core.input("suggesting", vec![InputStep::NoFrameFor(Duration::from_millis(10)), InputStep::Frame(vec![3])]);
+
+    let mut frames: Vec<_> = (0..3).map(|_| recv(&a).image.data[0]).collect();
+    frames.sort();
+    assert_eq!(frames, [1, 2, 3]);
+    // the default backoff alone would have taken 3 seconds
+    assert!(started.elapsed() < Duration::from_secs(2), "the frames took {:?}", started.elapsed());
+}
+
+#[test]
+fn the_gui_learns_which_input_plugins_are_idle() {
+    let mut cfg = config();
+    let idle = IdleConfig { initial_backoff_ms: 50, warn_after_secs: 1, ..IdleConfig::default() };
+    cfg.input_plugins.insert("idle".to_string(), PluginConfig { idle, ..plugin() });
+    cfg.input_plugins.insert("busy".to_string(), plugin());
+    cfg.data_plugins.insert("a".to_string(), plugin());
+
+    let core = TestCore::start(cfg);
+    let (_updates, statuses) = core.gui_with_status();
+    let _a = core.data("a", &[], vec![]);
+    core.input("idle", vec![]);
+    core.input("busy", (0..200).map(|i| InputStep::Frame(vec![i])).collect());
+
+    // the status is sent every second, the plugin is idle after one
+    let started = Instant::now();
+    while recv(&statuses) != ["idle"] {
+        assert!(started.elapsed() < common::WAIT, "the plugin never became idle");
+    }
+}
+
+#[test]
+fn data_plugins_that_fail_hang_or_are_slow_do_not_stop_the_others() {
+    let mut cfg = config();
+    cfg.input_plugins.insert("camera".to_string(), plugin());
+    for name in ["crashing", "failing", "hanging", "slow", "fine"] {
+        cfg.data_plugins.insert(name.to_string(), plugin());
+    }
+    cfg.data_plugins["hanging"].result_timeout_ms = 300;
+
+    let core = TestCore::start(cfg);
+    // they are restarted (on-failure), but never connect again
+    let crashing = core.data("crashing", &[], vec![DataStep::Crash]);
+    let failing = core.data("failing", &[], vec![DataStep::Error("broken".to_string())]);
+    let hanging = core.data("hanging", &[], vec![DataStep::Hang]);
+    let slow = core.data("slow", &[], (0..5).map(|_| DataStep::Delay(Duration::from_millis(100), doc! {})).collect());
+    let fine = core.data("fine", &[], vec![]);
+    core.input("camera", (1..=5).map(|i| InputStep::Frame(vec![i])).collect());
+
+    for received in [crashing, failing, hanging] {
+        assert_eq!(recv(&received).image.data.as_ref(), [1]);
+    }
+    for frame in 1..=5u8 {
+        assert_eq!(recv(&slow).image.data.as_ref(), [frame]);
+        assert_eq!(recv(&fine).image.data.as_ref(), [frame]);
+    }
+
+    assert_eq!(core.stop(), palleon_core::EXIT_OK);
+}
+
+#[test]
+fn a_data_plugin_that_never_connects_does_not_hold_up_the_frames() {
+    let mut cfg = config();
+    cfg.input_plugins.insert("camera".to_string(), plugin());
+    cfg.data_plugins.insert("absent".to_string(), plugin());
+    cfg.data_plugins.insert("fine".to_string(), plugin());
+
+    let core = TestCore::start(cfg);
+    let fine = core.data("fine", &[], vec![]);
+    core.input("camera", (1..=3).map(|i| InputStep::Frame(vec![i])).collect());
+
+    for frame in 1..=3u8 {
+        assert_eq!(recv(&fine).image.data.as_ref(), [frame]);
+    }
+
+    assert_eq!(core.stop(), palleon_core::EXIT_OK);
+}
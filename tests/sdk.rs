@@ -0,0 +1,84 @@
+// plugins built on the plugin sdk (palleon-plugin) against the core
+
+mod common;
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bson::{doc, Bson, Document};
+use flume::{unbounded, Sender};
+
+use palleon_core::config::PluginKind;
+use palleon_plugin::{DataPlugin, Image, InputPlugin, PluginResult};
+
+use common::{config, plugin, recv, TestCore, WAIT};
+
+// sends the frames 1, 2, 3, .. every 10 ms, as long as the core asks
+struct Counter(u8);
+
+impl InputPlugin for Counter {
+    fn next_frame(&mut self) -> PluginResult<Option<Vec<u8>>> {
+        thread::sleep(Duration::from_millis(10));
+        self.0 = self.0.wrapping_add(1);
+        Ok(Some(vec![self.0; 4]))
+    }
+
+    fn metadata(&mut self) -> Document {
+        doc! { "width": 2, "height": 2, "sequence": self.0 as i64 }
+    }
+}
+
+// reports every image and answers with the sum of its bytes
+struct Sum(Sender<Image>);
+
+impl DataPlugin for Sum {
+    fn process(&mut self, image: &Image, _: &Document) -> PluginResult<Document> {
+        let _ = self.0.send(image.clone());
+        let sum: i32 = image.data.as_ref().ok_or("no data")?.iter().map(|b| *b as i32).sum();
+        Ok(doc! { "sum": sum })
+    }
+}
+
+// runs a plugin of the sdk until the core shuts it down, connecting again until the core listens
+fn run(connect: impl Fn() -> Result<(), palleon_plugin::Error> + Send + 'static) {
+    thread::spawn(move || {
+        let started = Instant::now();
+        while connect().is_err() && started.elapsed() < WAIT {
+            thread::sleep(Duration::from_millis(20));
+        }
+    });
+}
+
+#[test]
+fn the_frames_of_an_sdk_input_plugin_reach_an_sdk_data_plugin_and_its_results_the_gui() {
+    let mut cfg = config();
+    cfg.input_plugins.insert("counter".to_string(), plugin());
+    cfg.data_plugins.insert("sum".to_string(), plugin());
+
+    let core = TestCore::start(cfg);
+    let gui = core.gui();
+    let (images_tx, images) = unbounded();
+    let input = core.runner(PluginKind::Input, "counter");
+    run(move || input.run_input(&mut Counter(0)));
+    let data = core.runner(PluginKind::Data, "sum");
+    run(move || data.run_data(&mut Sum(images_tx.clone())));
+
+    // the frames before the data plugin connected are skipped
+    let image = recv(&images);
+    let frame = image.data.as_ref().unwrap()[0];
+    assert_eq!(image.data.as_deref(), Some(&[frame; 4][..]));
+    assert_eq!(image.input_source, "counter");
+    // the core passes the numbers on as 64 bit integers
+    assert_eq!(image.metadata, doc! { "width": 2_i64, "height": 2_i64, "sequence": frame as i64 });
+
+    let sum = loop {
+        let (_, data) = recv(&gui);
+        if let Some(Bson::Array(datum)) = data.into_iter().next() {
+            assert_eq!(datum[0], Bson::String("sum".to_string()));
+            break datum[3].as_document().and_then(|result| result.get_i32("sum").ok()).unwrap();
+        }
+    };
+    assert_eq!(sum % 4, 0);
+
+    assert_eq!(core.stop(), palleon_core::EXIT_OK);
+}